@@ -1,30 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Cutoff at 0 V on the 1V/oct input (middle C).
+pub const VOCT_REFERENCE_HZ: f32 = 261.63;
+
+// Band state is soft-clipped around this level, which is what bounds the
+// filter once it self-oscillates
+const SATURATION: f32 = 2.0;
+
+// Damping at full resonance. Slightly negative so the filter rings up into
+// the saturator and keeps oscillating on its own
+const MIN_DAMPING: f32 = -0.02;
+
+/// Which of the filter's simultaneous outputs `SergeFilter::process` returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterMode {
+    #[default]
+    Lowpass,
+    Bandpass,
+    Highpass,
+    Notch,
+}
+
+/// All four responses for one input sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FilterOutputs {
+    pub lowpass: f32,
+    pub bandpass: f32,
+    pub highpass: f32,
+    pub notch: f32,
+}
+
+impl FilterOutputs {
+    pub fn get(&self, mode: FilterMode) -> f32 {
+        match mode {
+            FilterMode::Lowpass => self.lowpass,
+            FilterMode::Bandpass => self.bandpass,
+            FilterMode::Highpass => self.highpass,
+            FilterMode::Notch => self.notch,
+        }
+    }
+}
+
+/// A filter's tuning worked out from its cutoff and damping, so filters
+/// that move together, like one per channel, can share it.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterCoefficients {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+impl FilterCoefficients {
+    fn new(g: f32, k: f32) -> Self {
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        Self {
+            k,
+            a1,
+            a2,
+            a3: g * a2,
+        }
+    }
+}
+
+/// Serge VCFQ-inspired state-variable filter. Uses the zero-delay-feedback
+/// (trapezoidal) topology, so the cutoff can be swept every sample without
+/// the filter blowing up.
+#[derive(Debug, Clone, Copy)]
 pub struct SergeFilter {
-    // Serge-inspired resonant filter
-    #[allow(dead_code)]
+    sample_rate: f32,
     cutoff: f32,
-    #[allow(dead_code)]
     resonance: f32,
+    mode: FilterMode,
+    // Integrator gain from the prewarped cutoff
+    g: f32,
+    // Damping, 2 at no resonance down to MIN_DAMPING at full
+    k: f32,
+    coefficients: FilterCoefficients,
+    // Integrator states
+    ic1eq: f32,
+    ic2eq: f32,
 }
 
 impl Default for SergeFilter {
     fn default() -> Self {
-        Self {
-            cutoff: 1000.0,
-            resonance: 0.5,
-        }
+        Self::new()
     }
 }
 
 impl SergeFilter {
-    pub const fn new() -> Self {
-        Self {
+    pub fn new() -> Self {
+        Self::with_sample_rate(48000.0)
+    }
+
+    pub fn with_sample_rate(sample_rate: f32) -> Self {
+        let mut filter = Self {
+            sample_rate,
             cutoff: 1000.0,
             resonance: 0.5,
-        }
+            mode: FilterMode::Lowpass,
+            g: 0.0,
+            k: 0.0,
+            coefficients: FilterCoefficients::new(0.0, 0.0),
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set_cutoff(filter.cutoff);
+        filter.set_resonance(filter.resonance);
+        filter
+    }
+
+    /// Cutoff in Hz, kept between 10 Hz and just under Nyquist.
+    pub fn set_cutoff(&mut self, hz: f32) {
+        self.cutoff = hz.clamp(10.0, self.sample_rate * 0.49);
+        self.g = (PI * self.cutoff / self.sample_rate).tan();
+        self.coefficients = FilterCoefficients::new(self.g, self.k);
+    }
+
+    /// Cutoff as a 1V/oct control voltage, 0 V being `VOCT_REFERENCE_HZ`.
+    pub fn set_cutoff_voct(&mut self, volts: f32) {
+        self.set_cutoff(VOCT_REFERENCE_HZ * volts.exp2());
+    }
+
+    pub fn cutoff(&self) -> f32 {
+        self.cutoff
+    }
+
+    /// Resonance from 0 (none) to 1, where the filter self-oscillates.
+    pub fn set_resonance(&mut self, resonance: f32) {
+        self.resonance = resonance.clamp(0.0, 1.0);
+        self.k = 2.0 + (MIN_DAMPING - 2.0) * self.resonance;
+        self.coefficients = FilterCoefficients::new(self.g, self.k);
     }
 
+    pub fn resonance(&self) -> f32 {
+        self.resonance
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> FilterMode {
+        self.mode
+    }
+
+    /// The current tuning, for `process_with` on other filters.
+    pub fn coefficients(&self) -> FilterCoefficients {
+        self.coefficients
+    }
+
+    /// Clears the integrators, silencing any ringing or oscillation.
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    /// Filters one sample and returns the output for the current mode.
     pub fn process(&mut self, input: f32) -> f32 {
-        // TODO: Implement Serge-style filter
-        input
+        self.process_all(input).get(self.mode)
+    }
+
+    /// Filters one sample and returns every response at once.
+    pub fn process_all(&mut self, input: f32) -> FilterOutputs {
+        self.process_with(self.coefficients, input)
+    }
+
+    /// Like `process_all`, but tuned by `coefficients` in place of this
+    /// filter's own cutoff and resonance.
+    pub fn process_with(&mut self, coefficients: FilterCoefficients, input: f32) -> FilterOutputs {
+        let FilterCoefficients { k, a1, a2, a3 } = coefficients;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+
+        self.ic1eq = saturate(2.0 * v1 - self.ic1eq);
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let highpass = input - k * v1 - v2;
+        FilterOutputs {
+            lowpass: v2,
+            bandpass: v1,
+            highpass,
+            notch: highpass + v2,
+        }
+    }
+}
+
+fn saturate(x: f32) -> f32 {
+    SATURATION * (x / SATURATION).tanh()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48000.0;
+
+    fn resonant(cutoff: f32) -> SergeFilter {
+        let mut filter = SergeFilter::with_sample_rate(RATE);
+        filter.set_cutoff(cutoff);
+        filter.set_resonance(1.0);
+        filter
+    }
+
+    #[test]
+    fn full_resonance_stays_bounded_under_loud_input() {
+        for cutoff in [50.0, 1000.0, 20000.0] {
+            let mut filter = resonant(cutoff);
+            // A square wave at the cutoff, far over full scale
+            let period = (RATE / cutoff).max(2.0) as usize;
+            for i in 0..RATE as usize {
+                let input = if i % period < period / 2 { 20.0 } else { -20.0 };
+                let out = filter.process_all(input);
+                for value in [out.lowpass, out.bandpass, out.highpass, out.notch] {
+                    assert!(
+                        value.is_finite() && value.abs() < 100.0,
+                        "{cutoff} Hz: {value}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn full_resonance_oscillates_at_the_cutoff() {
+        let cutoff = 1000.0;
+        let ring = |resonance| {
+            let mut filter = resonant(cutoff);
+            filter.set_resonance(resonance);
+            filter.process(1e-3);
+            let output: Vec<f32> = (0..RATE as usize * 2)
+                .map(|_| filter.process(0.0))
+                .collect();
+            output[output.len() - 4800..].to_vec()
+        };
+        let peak = |frames: &[f32]| frames.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+
+        // Negative damping rings a tiny kick up to where the saturator holds it
+        let tail = ring(1.0);
+        assert!(peak(&tail) > 0.1, "settled at {}", peak(&tail));
+        assert!(peak(&tail) < SATURATION);
+        let crossings = tail
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        let hz = crossings as f32 / (tail.len() as f32 / RATE);
+        assert!((hz - cutoff).abs() < cutoff * 0.05, "rang at {hz} Hz");
+
+        // Short of full resonance it dies away
+        assert!(peak(&ring(0.9)) < 1e-6);
+    }
+
+    #[test]
+    fn shared_coefficients_filter_like_their_own() {
+        let mut tuned = resonant(440.0);
+        tuned.set_resonance(0.7);
+        let mut shared = SergeFilter::with_sample_rate(RATE);
+        for i in 0..1000 {
+            let input = (i as f32 * 0.1).sin();
+            assert_eq!(
+                shared.process_with(tuned.coefficients(), input),
+                tuned.process_all(input)
+            );
+        }
     }
 }
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Kernel phases per sample interval in the sinc tables
+const PHASES: usize = 256;
+
+// Octaves of band-limiting the polyphase mode covers (up to 4x pitch up)
+const POLYPHASE_OCTAVES: usize = 2;
+
+// Cutoff steps per octave in the polyphase mode
+const STEPS_PER_OCTAVE: usize = 8;
+
+/// How a voice reads between stored sample frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InterpolationMode {
+    /// Two-point linear; cheapest, dull and aliasing when pitched up
+    #[default]
+    Linear,
+    /// Four-point cubic Hermite (Catmull-Rom)
+    Cubic,
+    /// Windowed sinc at the source's Nyquist. Clean at or below the
+    /// original pitch, but does nothing about aliasing when pitching up.
+    Sinc { taps: usize },
+    /// Polyphase windowed sinc that lowers its cutoff as the playback rate
+    /// rises, so pitching up stays alias-free. Cutoffs come in eighth-octave
+    /// steps, rounded down from the rate. Costs more taps per octave up.
+    Polyphase { taps: usize },
+}
+
+/// One windowed-sinc filter stored phase-major: `PHASES + 1` rows of `taps`
+/// coefficients, so each output reads a contiguous row.
+struct SincBank {
+    taps: usize,
+    coefficients: Vec<f32>,
+}
+
+impl SincBank {
+    /// `cutoff` is relative to the source Nyquist; the kernel widens by
+    /// `1 / cutoff` so its quality stays the same at lower cutoffs.
+    fn new(taps: usize, cutoff: f64) -> Self {
+        let taps = ((taps as f64 / cutoff).round() as usize).max(2) & !1;
+        let half = (taps / 2) as f64;
+        let mut coefficients = Vec::with_capacity((PHASES + 1) * taps);
+
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            let row_start = coefficients.len();
+
+            for tap in 0..taps {
+                // Distance from the read position to the frame under this tap
+                let x = fraction - (tap as f64 - (half - 1.0));
+                coefficients.push((cutoff * sinc(cutoff * x) * blackman_harris(x / half)) as f32);
+            }
+
+            // Normalise each phase to unity gain so DC doesn't ripple
+            let row = &mut coefficients[row_start..];
+            let sum: f32 = row.iter().sum();
+            if sum.abs() > f32::EPSILON {
+                row.iter_mut().for_each(|c| *c /= sum);
+            }
+        }
+
+        Self { taps, coefficients }
+    }
+
+    fn read(&self, data: &[f32], channels: usize, ch: usize, position: f64) -> f32 {
+        let index = position.floor();
+        let phase = (position - index) * PHASES as f64;
+        let row = phase.floor() as usize;
+        let blend = (phase - row as f64) as f32;
+
+        let first = index as isize - (self.taps / 2) as isize + 1;
+        let a = &self.coefficients[row * self.taps..(row + 1) * self.taps];
+        let b = &self.coefficients[(row + 1) * self.taps..(row + 2) * self.taps];
+
+        let mut sum = 0.0;
+        for (tap, (ca, cb)) in a.iter().zip(b).enumerate() {
+            let coefficient = ca + (cb - ca) * blend;
+            sum += frame(data, channels, ch, first + tap as isize) * coefficient;
+        }
+        sum
+    }
+}
+
+/// Reads interleaved sample data at fractional frame positions. Kernel
+/// tables are built on construction and shared between clones, so build one
+/// off the audio thread and hand clones to voices.
+#[derive(Clone, Default)]
+pub struct Interpolator {
+    mode: InterpolationMode,
+    // One bank per cutoff step; a single bank for plain sinc
+    banks: Option<Arc<[SincBank]>>,
+}
+
+impl Interpolator {
+    pub fn new(mode: InterpolationMode) -> Self {
+        let banks: Option<Arc<[SincBank]>> = match mode {
+            InterpolationMode::Linear | InterpolationMode::Cubic => None,
+            InterpolationMode::Sinc { taps } => Some(Arc::new([SincBank::new(taps, 1.0)])),
+            InterpolationMode::Polyphase { taps } => Some(
+                (0..=POLYPHASE_OCTAVES * STEPS_PER_OCTAVE)
+                    .map(|step| {
+                        SincBank::new(taps, 0.5f64.powf(step as f64 / STEPS_PER_OCTAVE as f64))
+                    })
+                    .collect(),
+            ),
+        };
+        Self { mode, banks }
+    }
+
+    pub fn mode(&self) -> InterpolationMode {
+        self.mode
+    }
+
+    /// Whether anything besides this value still holds the kernel tables.
+    pub fn is_shared(&self) -> bool {
+        self.banks
+            .as_ref()
+            .is_some_and(|banks| Arc::strong_count(banks) > 1)
+    }
+
+    /// Reads channel `ch` of interleaved `data` at fractional frame
+    /// `position`. `ratio` is the playback increment, which the polyphase
+    /// mode uses to pick its cutoff. Frames outside the data read as silence.
+    pub fn read(&self, data: &[f32], channels: usize, ch: usize, position: f64, ratio: f64) -> f32 {
+        let index = position.floor() as isize;
+        let fract = (position - position.floor()) as f32;
+
+        match (&self.mode, &self.banks) {
+            (InterpolationMode::Sinc { .. }, Some(banks)) => {
+                banks[0].read(data, channels, ch, position)
+            }
+            (InterpolationMode::Polyphase { .. }, Some(banks)) => {
+                // Drop an octave of bandwidth for each octave pitched up,
+                // taking the step at or under the output's Nyquist so
+                // nothing above it gets through
+                let steps = ratio.max(1.0).log2() * STEPS_PER_OCTAVE as f64;
+                // Float error in log2 shouldn't push exact steps down one
+                let step = ((steps - 1e-9).ceil().max(0.0) as usize).min(banks.len() - 1);
+                banks[step].read(data, channels, ch, position)
+            }
+            (InterpolationMode::Cubic, _) => {
+                let xm1 = frame(data, channels, ch, index - 1);
+                let x0 = frame(data, channels, ch, index);
+                let x1 = frame(data, channels, ch, index + 1);
+                let x2 = frame(data, channels, ch, index + 2);
+
+                let c1 = 0.5 * (x1 - xm1);
+                let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+                let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+                ((c3 * fract + c2) * fract + c1) * fract + x0
+            }
+            _ => {
+                let x0 = frame(data, channels, ch, index);
+                let x1 = frame(data, channels, ch, index + 1);
+                x0 + (x1 - x0) * fract
+            }
+        }
+    }
+}
+
+fn frame(data: &[f32], channels: usize, ch: usize, index: isize) -> f32 {
+    if index < 0 {
+        return 0.0;
+    }
+    data.get(index as usize * channels + ch)
+        .copied()
+        .unwrap_or(0.0)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Four-term Blackman-Harris window over -1..1.
+fn blackman_harris(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let t = PI * (x + 1.0);
+    0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RMS of a sine at `cycles` per source frame, read at `ratio`
+    fn read_sine_rms(mode: InterpolationMode, cycles: f64, ratio: f64) -> f32 {
+        let data: Vec<f32> = (0..8192)
+            .map(|i| (2.0 * PI * cycles * i as f64).sin() as f32)
+            .collect();
+        let interpolator = Interpolator::new(mode);
+        let reads = 2000;
+        let sum: f32 = (0..reads)
+            .map(|i| {
+                let value = interpolator.read(&data, 1, 0, 1000.0 + i as f64 * ratio, ratio);
+                value * value
+            })
+            .sum();
+        (sum / reads as f32).sqrt()
+    }
+
+    #[test]
+    fn polyphase_keeps_top_octave_for_small_pitch_up() {
+        let mode = InterpolationMode::Polyphase { taps: 32 };
+        // Six tenths of Nyquist, a semitone up, is still under the output's
+        for cycles in [0.25, 0.3, 0.35] {
+            let rms = read_sine_rms(mode, cycles, 1.06);
+            assert!(rms > 0.6, "{cycles} cycles/frame came out at {rms}");
+        }
+    }
+
+    #[test]
+    fn polyphase_cutoff_follows_ratio_smoothly() {
+        let mode = InterpolationMode::Polyphase { taps: 32 };
+        let just_under = read_sine_rms(mode, 0.3, 0.999);
+        let just_over = read_sine_rms(mode, 0.3, 1.001);
+        assert!((just_under - just_over).abs() < 0.01);
+    }
+
+    #[test]
+    fn polyphase_removes_what_would_alias() {
+        let mode = InterpolationMode::Polyphase { taps: 32 };
+        // 0.3 cycles/frame at 2.5x lands above the output's Nyquist
+        let rms = read_sine_rms(mode, 0.3, 2.5);
+        assert!(rms < 0.05, "aliasing content came out at {rms}");
+        // Well inside the band it passes
+        assert!(read_sine_rms(mode, 0.05, 2.5) > 0.65);
+    }
+
+    #[test]
+    fn polyphase_filters_part_octaves_at_the_output_nyquist() {
+        let mode = InterpolationMode::Polyphase { taps: 32 };
+        // At 1.5x the output's Nyquist is 1/3 cycle per source frame; a tone
+        // a little above it must not fold back into the band
+        for cycles in [0.38, 0.42, 0.46] {
+            let rms = read_sine_rms(mode, cycles, 1.5);
+            assert!(rms < 0.01, "{cycles} cycles/frame aliased at {rms}");
+        }
+        assert!(read_sine_rms(mode, 0.2, 1.5) > 0.65);
+    }
+}
@@ -3,7 +3,13 @@
 #![allow(clippy::missing_const_for_fn)]
 
 pub mod filters;
+pub mod interpolation;
+pub mod pitch;
 pub mod resampler;
+pub mod smoothing;
 
 pub use filters::*;
+pub use interpolation::*;
+pub use pitch::*;
 pub use resampler::*;
+pub use smoothing::*;
@@ -0,0 +1,192 @@
+use realfft::RealFftPlanner;
+
+// Range of fundamentals the detector looks for (A0 to just above C8)
+const MIN_FREQUENCY: f32 = 27.5;
+const MAX_FREQUENCY: f32 = 4200.0;
+
+// Normalised difference below which a lag counts as periodic (YIN's threshold)
+const YIN_THRESHOLD: f32 = 0.15;
+
+// Best normalised difference still accepted as pitched at all
+const MAX_APERIODICITY: f32 = 0.5;
+
+// Integration window length in seconds
+const WINDOW_SECS: f32 = 0.04;
+
+// Analysis windows spread over the sustain, for a median estimate
+const WINDOWS: usize = 5;
+
+/// A detected fundamental.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchEstimate {
+    pub frequency: f32,
+    /// 0 to 1, how periodic the signal was at that frequency
+    pub confidence: f32,
+}
+
+impl PitchEstimate {
+    /// Nearest MIDI note (A4 = 69 = 440 Hz) and how many cents the
+    /// frequency sits above it, from -50 to 50.
+    pub fn to_note(&self) -> (u8, f32) {
+        let semitones = 69.0 + 12.0 * (self.frequency / 440.0).log2();
+        let note = semitones.round().clamp(0.0, 127.0);
+        (note as u8, (semitones - note) * 100.0)
+    }
+}
+
+/// Estimates the fundamental of a recorded note with YIN, using FFT
+/// correlation. `samples` is mono. Skips the attack and takes the median
+/// over several windows of the sustain. Returns `None` for unpitched or
+/// too-short material. Allocates, so keep it off the audio thread.
+pub fn detect_pitch(samples: &[f32], sample_rate: f32) -> Option<PitchEstimate> {
+    let window = (WINDOW_SECS * sample_rate) as usize;
+    let min_lag = ((sample_rate / MAX_FREQUENCY) as usize).max(2);
+    let max_lag = (sample_rate / MIN_FREQUENCY) as usize;
+
+    // Start just after the loudest point, once the attack transient has passed
+    let peak = samples
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+        .map_or(0, |(index, _)| index);
+    let start = (peak + window / 2).min(samples.len());
+    let sustain = &samples[start..];
+
+    // Shorten the longest lag for short samples rather than giving up
+    let max_lag = max_lag.min(sustain.len().saturating_sub(window));
+    if max_lag <= min_lag * 2 {
+        return None;
+    }
+
+    let span = window + max_lag;
+    let hop = (sustain.len() - span) / WINDOWS;
+    let mut yin = Yin::new(window, max_lag);
+    let mut estimates: Vec<PitchEstimate> = (0..WINDOWS)
+        .filter_map(|i| {
+            let lag = yin.best_lag(&sustain[i * hop..i * hop + span], min_lag)?;
+            Some(PitchEstimate {
+                frequency: sample_rate / lag.0,
+                confidence: 1.0 - lag.1,
+            })
+        })
+        .collect();
+
+    if estimates.is_empty() {
+        return None;
+    }
+    estimates.sort_by(|a, b| a.frequency.total_cmp(&b.frequency));
+    Some(estimates[estimates.len() / 2])
+}
+
+/// Scratch buffers for the YIN difference function of one window size.
+struct Yin {
+    window: usize,
+    max_lag: usize,
+    planner: RealFftPlanner<f32>,
+    fft_len: usize,
+    difference: Vec<f32>,
+}
+
+impl Yin {
+    fn new(window: usize, max_lag: usize) -> Self {
+        Self {
+            window,
+            max_lag,
+            planner: RealFftPlanner::new(),
+            fft_len: (window * 2 + max_lag).next_power_of_two(),
+            difference: vec![0.0; max_lag + 1],
+        }
+    }
+
+    /// Refined lag of the fundamental and its normalised difference, for
+    /// `frame` of `window + max_lag` samples.
+    fn best_lag(&mut self, frame: &[f32], min_lag: usize) -> Option<(f32, f32)> {
+        self.difference_function(frame)?;
+        let d = &mut self.difference;
+
+        // Cumulative mean normalised difference
+        let mut running = 0.0;
+        d[0] = 1.0;
+        for (lag, value) in d.iter_mut().enumerate().skip(1) {
+            running += *value;
+            *value = if running > 0.0 {
+                *value * lag as f32 / running
+            } else {
+                1.0
+            };
+        }
+
+        // First dip under the threshold, followed down to its minimum
+        let lag = (min_lag..d.len() - 1)
+            .find(|&lag| d[lag] < YIN_THRESHOLD)
+            .map(|mut lag| {
+                while lag + 1 < d.len() && d[lag + 1] < d[lag] {
+                    lag += 1;
+                }
+                lag
+            })
+            .or_else(|| {
+                (min_lag..d.len() - 1)
+                    .min_by(|&a, &b| d[a].total_cmp(&d[b]))
+                    .filter(|&lag| d[lag] < MAX_APERIODICITY)
+            })?;
+
+        // Parabolic interpolation around the minimum
+        let refined = if lag > 0 && lag + 1 < d.len() {
+            let (a, b, c) = (d[lag - 1], d[lag], d[lag + 1]);
+            let denominator = a - 2.0 * b + c;
+            if denominator.abs() > f32::EPSILON {
+                lag as f32 + 0.5 * (a - c) / denominator
+            } else {
+                lag as f32
+            }
+        } else {
+            lag as f32
+        };
+        Some((refined, d[lag]))
+    }
+
+    /// Fills `difference` with the squared difference between the window
+    /// and itself shifted by each lag, via one FFT cross-correlation.
+    fn difference_function(&mut self, frame: &[f32]) -> Option<()> {
+        let (window, max_lag, n) = (self.window, self.max_lag, self.fft_len);
+        let forward = self.planner.plan_fft_forward(n);
+        let inverse = self.planner.plan_fft_inverse(n);
+
+        let mut head = forward.make_input_vec();
+        head[..window].copy_from_slice(&frame[..window]);
+        let mut whole = forward.make_input_vec();
+        whole[..frame.len()].copy_from_slice(frame);
+
+        let mut head_spectrum = forward.make_output_vec();
+        let mut whole_spectrum = forward.make_output_vec();
+        forward.process(&mut head, &mut head_spectrum).ok()?;
+        forward.process(&mut whole, &mut whole_spectrum).ok()?;
+
+        for (h, w) in head_spectrum.iter().zip(whole_spectrum.iter_mut()) {
+            *w *= h.conj();
+        }
+        // DC and Nyquist are real for real input; clear rounding noise
+        whole_spectrum[0].im = 0.0;
+        if let Some(last) = whole_spectrum.last_mut() {
+            last.im = 0.0;
+        }
+        let mut correlation = inverse.make_output_vec();
+        inverse
+            .process(&mut whole_spectrum, &mut correlation)
+            .ok()?;
+
+        // Window energies at each lag from a running sum of squares
+        let scale = 1.0 / n as f32;
+        let mut energy_at_lag: f32 = frame[..window].iter().map(|x| x * x).sum();
+        let energy_at_zero = energy_at_lag;
+        for lag in 0..=max_lag {
+            self.difference[lag] =
+                (energy_at_zero + energy_at_lag - 2.0 * correlation[lag] * scale).max(0.0);
+            if lag + window < frame.len() {
+                energy_at_lag += frame[lag + window].powi(2) - frame[lag].powi(2);
+            }
+        }
+        Some(())
+    }
+}
@@ -1,14 +1,128 @@
+use crate::{InterpolationMode, Interpolator};
+
+/// Plays a mono buffer back at a variable rate through an [`Interpolator`].
+/// Allocation-free once built.
 pub struct PitchShifter {
-    // Placeholder for high-quality pitch shifting
+    interpolator: Interpolator,
+    position: f64,
 }
 
 impl PitchShifter {
-    pub const fn new(_sample_rate: f32) -> Self {
-        Self {}
+    pub fn new(mode: InterpolationMode) -> Self {
+        Self::with_interpolator(Interpolator::new(mode))
+    }
+
+    /// Shares the kernel tables of an existing interpolator.
+    pub fn with_interpolator(interpolator: Interpolator) -> Self {
+        Self {
+            interpolator,
+            position: 0.0,
+        }
+    }
+
+    /// Fills `output` reading `input` at `pitch_ratio` input frames per
+    /// output frame, carrying on from where the last call stopped. Returns
+    /// how many samples were written before the input ran out.
+    pub fn process(&mut self, input: &[f32], pitch_ratio: f64, output: &mut [f32]) -> usize {
+        let end = input.len() as f64;
+        let mut written = 0;
+
+        for out in output.iter_mut() {
+            if self.position >= end {
+                break;
+            }
+            *out = self
+                .interpolator
+                .read(input, 1, 0, self.position, pitch_ratio);
+            self.position += pitch_ratio;
+            written += 1;
+        }
+        written
+    }
+
+    pub fn position(&self) -> f64 {
+        self.position
+    }
+
+    pub fn reset(&mut self) {
+        self.position = 0.0;
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResampleError {
+    #[error("Cannot build resampler: {0}")]
+    Construction(#[from] rubato::ResamplerConstructionError),
+    #[error("Resampling failed: {0}")]
+    Process(#[from] rubato::ResampleError),
+}
+
+/// Converts a whole interleaved buffer from one sample rate to another with
+/// a band-limited sinc resampler. Allocates, so keep it off the audio thread.
+pub fn resample_buffer(
+    input: &[f32],
+    channels: usize,
+    from_rate: f32,
+    to_rate: f32,
+) -> Result<Vec<f32>, ResampleError> {
+    use rubato::{
+        Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+    };
+
+    let channels = channels.max(1);
+    if from_rate == to_rate || input.is_empty() {
+        return Ok(input.to_vec());
+    }
+
+    let frames = input.len() / channels;
+    let ratio = f64::from(to_rate) / f64::from(from_rate);
+    let expected = (frames as f64 * ratio).round() as usize;
+
+    let planar: Vec<Vec<f32>> = (0..channels)
+        .map(|ch| input.iter().skip(ch).step_by(channels).copied().collect())
+        .collect();
+
+    let parameters = SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        oversampling_factor: 256,
+        interpolation: SincInterpolationType::Cubic,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    let mut resampler = SincFixedIn::<f32>::new(ratio, 1.0, parameters, 1024, channels)?;
+    let delay = resampler.output_delay();
+
+    let mut output: Vec<Vec<f32>> = vec![Vec::with_capacity(expected + delay); channels];
+    let append = |output: &mut Vec<Vec<f32>>, chunk: Vec<Vec<f32>>| {
+        for (out, chunk) in output.iter_mut().zip(chunk) {
+            out.extend(chunk);
+        }
+    };
+
+    let mut pos = 0;
+    while frames - pos >= resampler.input_frames_next() {
+        let end = pos + resampler.input_frames_next();
+        let chunk: Vec<&[f32]> = planar.iter().map(|ch| &ch[pos..end]).collect();
+        append(&mut output, resampler.process(&chunk, None)?);
+        pos = end;
+    }
+
+    let tail: Vec<&[f32]> = planar.iter().map(|ch| &ch[pos..]).collect();
+    append(&mut output, resampler.process_partial(Some(&tail), None)?);
+
+    // Flush the filter until the delayed end of the signal is out
+    while output[0].len() < delay + expected {
+        append(
+            &mut output,
+            resampler.process_partial::<&[f32]>(None, None)?,
+        );
     }
 
-    pub fn process(&mut self, input: &[f32], _pitch_ratio: f32) -> Vec<f32> {
-        // TODO: Implement high-quality resampling
-        input.to_vec()
+    let mut interleaved = Vec::with_capacity(expected * channels);
+    for frame in delay..delay + expected {
+        for ch in &output {
+            interleaved.push(ch[frame]);
+        }
     }
+    Ok(interleaved)
 }
@@ -0,0 +1,67 @@
+/// Ramp time used for parameter changes unless a caller picks its own.
+pub const DEFAULT_RAMP_MS: f32 = 20.0;
+
+/// A parameter that glides linearly to new targets over a fixed ramp time,
+/// advanced once per sample so automation doesn't zipper.
+#[derive(Debug, Clone, Copy)]
+pub struct SmoothedParam {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: u32,
+    ramp_samples: u32,
+}
+
+impl SmoothedParam {
+    pub fn new(value: f32, sample_rate: f32, ramp_ms: f32) -> Self {
+        Self {
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+            ramp_samples: ((ramp_ms / 1000.0) * sample_rate).max(1.0) as u32,
+        }
+    }
+
+    /// Starts a ramp from the current value to `target`.
+    pub fn set_target(&mut self, target: f32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        self.remaining = self.ramp_samples;
+        self.step = (target - self.current) / self.ramp_samples as f32;
+    }
+
+    /// Jumps straight to `value`, cancelling any ramp in progress.
+    pub fn set_immediate(&mut self, value: f32) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+
+    /// Advances one sample and returns the new value.
+    pub fn next_value(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 {
+                self.target
+            } else {
+                self.current + self.step
+            };
+        }
+        self.current
+    }
+
+    pub fn value(&self) -> f32 {
+        self.current
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn is_smoothing(&self) -> bool {
+        self.remaining > 0
+    }
+}
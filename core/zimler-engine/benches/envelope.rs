@@ -0,0 +1,125 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::f32::consts::TAU;
+use zimler_engine::{Curve, Envelope, EnvelopeShape, LoopMode, Sample, SampleLoop, Voice};
+
+const SAMPLE_RATE: f32 = 48000.0;
+const BLOCK_FRAMES: usize = 256;
+const VOICE_COUNTS: [usize; 3] = [16, 64, 128];
+
+// Short enough that every stage comes round while benchmarking
+const SHAPE: EnvelopeShape = EnvelopeShape::ADSR {
+    attack_ms: 5.0,
+    decay_ms: 40.0,
+    sustain: 0.6,
+    release_ms: 60.0,
+    attack_curve: Curve::Shaped(-0.5),
+    decay_curve: Curve::Rc,
+    release_curve: Curve::Shaped(0.5),
+};
+
+/// Envelopes part way through their notes, spread across the stages.
+fn envelopes(count: usize) -> Vec<Envelope> {
+    (0..count)
+        .map(|i| {
+            let mut envelope = Envelope::new(SAMPLE_RATE);
+            envelope.set_shape(SHAPE);
+            envelope.trigger();
+            for _ in 0..i * 97 {
+                envelope.process_sample();
+            }
+            envelope
+        })
+        .collect()
+}
+
+/// Releases envelopes that have sustained and restarts finished ones, so
+/// they keep moving.
+fn keep_moving(envelope: &mut Envelope, block: usize) {
+    if envelope.is_finished() {
+        envelope.trigger();
+    } else if block % 8 == 7 {
+        envelope.release();
+    }
+}
+
+fn bench_envelope(c: &mut Criterion) {
+    let mut group = c.benchmark_group("envelope");
+    for count in VOICE_COUNTS {
+        group.throughput(Throughput::Elements(count as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("per_sample", count),
+            &count,
+            |b, &count| {
+                let mut envelopes = envelopes(count);
+                let mut levels = [0.0; BLOCK_FRAMES];
+                let mut block = 0;
+                b.iter(|| {
+                    for envelope in &mut envelopes {
+                        for level in &mut levels {
+                            *level = envelope.get_current_value();
+                            envelope.process_sample();
+                        }
+                        black_box(&levels);
+                        keep_moving(envelope, block);
+                    }
+                    block += 1;
+                });
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("block", count), &count, |b, &count| {
+            let mut envelopes = envelopes(count);
+            let mut levels = [0.0; BLOCK_FRAMES];
+            let mut block = 0;
+            b.iter(|| {
+                for envelope in &mut envelopes {
+                    envelope.process_block(&mut levels);
+                    black_box(&levels);
+                    keep_moving(envelope, block);
+                }
+                block += 1;
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Whole voices playing a looped sine, envelope included.
+fn bench_voices(c: &mut Criterion) {
+    let data = (0..4800)
+        .map(|i| (i as f32 / 48.0 * TAU).sin())
+        .collect::<Vec<_>>();
+    let mut sample = Sample::new(data, SAMPLE_RATE, 1);
+    sample.set_loop(Some(SampleLoop::new(0, 4800, LoopMode::Forward)));
+
+    let mut group = c.benchmark_group("voices");
+    for count in VOICE_COUNTS {
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            let mut voices = (0..count)
+                .map(|i| {
+                    let mut voice = Voice::with_format(SAMPLE_RATE, 2);
+                    voice.set_envelope(SHAPE);
+                    voice.trigger(36 + (i % 60) as u8, 0.8, sample.clone());
+                    voice
+                })
+                .collect::<Vec<_>>();
+            let mut output = vec![0.0; BLOCK_FRAMES * 2];
+            b.iter(|| {
+                output.fill(0.0);
+                for (i, voice) in voices.iter_mut().enumerate() {
+                    if !voice.is_active() {
+                        voice.trigger(36 + (i % 60) as u8, 0.8, sample.clone());
+                    }
+                    voice.process_block(&mut output);
+                }
+                black_box(&output);
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_envelope, bench_voices);
+criterion_main!(benches);
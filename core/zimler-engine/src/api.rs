@@ -1,23 +1,150 @@
-use crate::{EngineState, EnvelopeShape, SampleBank};
-use parking_lot::RwLock;
+use crate::{
+    Bundle, BundleCompression, EngineSettings, EngineState, EngineUpdate, EnvelopeShape,
+    Modulation, Preset, PresetSample, RateConversion, Retired, RootDetection, Sample, SampleBank,
+    SampleLoop, SampleMapping, SfzInstrument, SfzWarning, WaveformPeaks, Zone,
+};
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use zimler_dsp::{FilterMode, InterpolationMode, Interpolator};
 
 #[derive(Clone)]
 pub struct EngineHandle {
-    pub sample_bank: Arc<RwLock<SampleBank>>,
+    /// Control-side copy of the bank. The audio thread keeps its own copy,
+    /// updated over `update_sender`, so holding this lock never stalls audio.
+    /// Changes go through the handle so they reach both copies.
+    pub(crate) sample_bank: Arc<RwLock<SampleBank>>,
     pub engine_state: Arc<RwLock<EngineState>>,
-    pub command_sender: crossbeam::channel::Sender<EngineCommand>,
+    pub command_sender: crossbeam::channel::Sender<TimedCommand>,
+    // Future commands the engine had no room to schedule
+    pub(crate) refused: crossbeam::channel::Receiver<TimedCommand>,
+    pub frame_clock: Arc<AtomicU64>,
+    pub(crate) update_sender: crossbeam::channel::Sender<EngineUpdate>,
+    // Replaced samples and kernels parked here until no voice refers to them,
+    // so their memory is freed on this side rather than on the audio thread
+    pub(crate) retired: Arc<Mutex<Retired>>,
+    // Settings sent through any handle, so presets can capture them
+    pub(crate) settings: Arc<Mutex<EngineSettings>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EngineCommand {
-    LoadSample { slot: usize, path: String },
-    TriggerNote { note: u8, velocity: f32 },
-    ReleaseNote { note: u8 },
-    SetEnvelope { envelope: EnvelopeShape },
-    SetMixMode { mode: MixMode },
-    SetParameter { param: Parameter, value: f32 },
+    LoadSample {
+        slot: usize,
+        path: String,
+    },
+    TriggerNote {
+        note: u8,
+        velocity: f32,
+    },
+    ReleaseNote {
+        note: u8,
+    },
+    SetEnvelope {
+        envelope: EnvelopeShape,
+    },
+    SetMixMode {
+        mode: MixMode,
+    },
+    SetParameter {
+        param: Parameter,
+        value: f32,
+    },
+    SetVoiceStealing {
+        mode: VoiceStealing,
+    },
+    /// Poly or mono playing; switching releases whatever is sounding
+    SetVoiceMode {
+        mode: VoiceMode,
+    },
+    /// What a voice that is still sounding does with a new note
+    SetRetrigger {
+        mode: RetriggerMode,
+    },
+    SetInterpolation {
+        mode: InterpolationMode,
+    },
+    /// Filter response for every voice; `None` bypasses the filter
+    SetFilterMode {
+        mode: Option<FilterMode>,
+    },
+    /// Loop for the sample in `slot`, from the next note on; `None` plays it once
+    SetLoop {
+        slot: usize,
+        sample_loop: Option<SampleLoop>,
+    },
+    SetMapping {
+        mapping: SampleMapping,
+    },
+    AddZone {
+        zone: Zone,
+    },
+    /// Replaces the zone at `index`
+    SetZone {
+        index: usize,
+        zone: Zone,
+    },
+    RemoveZone {
+        index: usize,
+    },
+    /// Replaces samples, mapping and settings with those of a preset file
+    LoadPreset {
+        path: String,
+    },
+    /// Like `LoadPreset`, for a bundle with its audio embedded
+    LoadBundle {
+        path: String,
+    },
+    /// Bend from -1 to 1, scaled by `Parameter::PitchBendRange`
+    PitchBend {
+        amount: f32,
+    },
+    /// Mod wheel from 0 to 1, scaled by `Parameter::VibratoDepth`
+    ModWheel {
+        amount: f32,
+    },
+    /// Damper pedal: while down, released notes keep sounding
+    SetSustain {
+        on: bool,
+    },
+    /// Sostenuto pedal: holds only the notes down when it was pressed
+    SetSostenuto {
+        on: bool,
+    },
+    /// Releases every held key; notes on a pedal sound until it lifts
+    AllNotesOff,
+    /// Envelope or LFO for pitch, filter or amplitude; `None` turns it off
+    SetModulation {
+        modulation: Option<Modulation>,
+    },
+}
+
+impl EngineCommand {
+    /// Whether the handle carries the command out itself as it is sent,
+    /// rather than the audio thread on the command's frame.
+    pub fn is_control_side(&self) -> bool {
+        matches!(
+            self,
+            Self::LoadSample { .. }
+                | Self::SetInterpolation { .. }
+                | Self::SetLoop { .. }
+                | Self::SetMapping { .. }
+                | Self::AddZone { .. }
+                | Self::SetZone { .. }
+                | Self::RemoveZone { .. }
+                | Self::LoadPreset { .. }
+                | Self::LoadBundle { .. }
+        )
+    }
+}
+
+/// An `EngineCommand` stamped with the absolute frame it should take effect on.
+/// Frames that have already passed take effect at the start of the next block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedCommand {
+    pub frame: u64,
+    pub command: EngineCommand,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -28,15 +155,85 @@ pub enum Parameter {
     EnvelopeDecay,
     EnvelopeSustain,
     EnvelopeRelease,
+    /// Curvature of the attack, decay or release, -1 (log) to 1 (exp)
+    EnvelopeAttackCurve,
+    EnvelopeDecayCurve,
+    EnvelopeReleaseCurve,
     SampleStartOffset,
     SampleEndOffset,
     PitchBendRange,
+    FilterCutoff,
+    FilterResonance,
+    /// Width of stereo samples: 0 mono, 1 as recorded, 2 widest
+    StereoWidth,
+    /// Vibrato in semitones with the mod wheel fully up
+    VibratoDepth,
+    /// Beats per minute, for cycling envelopes synced to tempo
+    Tempo,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Which busy voice a new note takes over when every voice is in use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoiceStealing {
+    /// The voice triggered longest ago
+    #[default]
+    Oldest,
+    /// The voice with the lowest envelope level
+    Quietest,
+    /// A voice already playing the same note, else the oldest
+    SameNote,
+    /// Keep low notes sounding: steal the highest note
+    LowestNotePriority,
+    /// Keep high notes sounding: steal the lowest note
+    HighestNotePriority,
+}
+
+/// Whether notes get voices of their own or share one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum VoiceMode {
+    #[default]
+    Poly,
+    /// One note at a time, picked from the keys held by `priority`; letting
+    /// go of it returns to the next held note. Pitch glides between notes
+    /// over `glide_ms`.
+    Mono {
+        priority: NotePriority,
+        glide_ms: f32,
+    },
+}
+
+/// Which held key a mono voice plays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotePriority {
+    /// The key pressed most recently
+    #[default]
+    Last,
+    Lowest,
+    Highest,
+}
+
+/// What a voice that is still sounding does when it is given a new note:
+/// in mono mode on every note, in poly mode when a voice is stolen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetriggerMode {
+    /// Fade the old note out, then start from silence and the sample start
+    #[default]
+    Reset,
+    /// Attack from the envelope's current level, restarting the sample
+    FromCurrent,
+    /// Carry on the envelope and sample position at the new pitch while the
+    /// old note is held over; otherwise as `FromCurrent`. Notes playing a
+    /// different sample always restart.
+    Legato,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
 pub enum MixMode {
+    #[default]
     Poly,
-    Blur { crossfade_ms: f32 },
+    Blur {
+        crossfade_ms: f32,
+    },
     Stack,
     Rotate,
 }
@@ -51,7 +248,19 @@ pub enum QueryType {
     GetState,
     GetSampleList,
     GetCurrentPreset,
-    GetWaveform { voice_index: usize },
+    /// Min/max peaks in `points` columns
+    GetWaveform {
+        source: WaveformSource,
+        points: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum WaveformSource {
+    /// The whole sample in a bank slot
+    Slot(usize),
+    /// The region a voice is playing through, with its playhead
+    Voice(usize),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,7 +275,7 @@ pub enum ResponseData {
     State(EngineState),
     SampleList(Vec<SampleInfo>),
     Preset(String),
-    Waveform(Vec<f32>),
+    Waveform(WaveformPeaks),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,38 +287,424 @@ pub struct SampleInfo {
     pub channels: usize,
 }
 
+/// A voice's note and playback region, published by the audio thread.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct VoiceInfo {
+    /// `None` while the voice is idle
+    pub note: Option<u8>,
+    pub slot: Option<usize>,
+    /// Playhead in frames
+    pub position: f64,
+    pub start_frame: usize,
+    /// Exclusive
+    pub end_frame: usize,
+}
+
 impl EngineHandle {
+    /// Sends a command to take effect at the start of the next block.
     pub fn send_command(&self, command: EngineCommand) -> Result<(), String> {
+        self.send_command_at(command, 0)
+    }
+
+    /// Sends a command to take effect at an absolute engine frame, splitting
+    /// the block it falls in so notes start and stop on that exact frame.
+    /// If too many are waiting for later blocks the engine hands it back
+    /// through `refused_commands`.
+    pub fn send_command_at(&self, command: EngineCommand, frame: u64) -> Result<(), String> {
+        self.settings.lock().record(&command);
         match &command {
-            EngineCommand::LoadSample { slot, path } => {
-                let mut bank = self.sample_bank.write();
-                bank.load_sample(*slot, path).map_err(|e| e.to_string())?;
+            EngineCommand::LoadSample { slot, path } => self.load_sample(*slot, path)?,
+            EngineCommand::SetInterpolation { mode } => self.set_interpolation(*mode)?,
+            EngineCommand::SetLoop { slot, sample_loop } => self.set_loop(*slot, *sample_loop)?,
+            EngineCommand::SetMapping { mapping } => self.install_mapping(mapping.clone())?,
+            EngineCommand::AddZone { zone } => self.add_zone(zone.clone())?,
+            EngineCommand::SetZone { index, zone } => self.set_zone(*index, zone.clone())?,
+            EngineCommand::RemoveZone { index } => {
+                self.remove_zone(*index)?;
             }
+            EngineCommand::LoadPreset { path } => self.load_preset(path)?,
+            EngineCommand::LoadBundle { path } => self.load_bundle(path)?,
             _ => {
                 // Send other commands to the audio thread
                 self.command_sender
-                    .send(command)
+                    .send(TimedCommand { frame, command })
                     .map_err(|e| e.to_string())?;
             }
         }
         Ok(())
     }
 
+    /// Decodes an audio file into `slot`, converting its rate as the bank
+    /// is set to.
+    pub fn load_sample(&self, slot: usize, path: &str) -> Result<(), String> {
+        // Decode before touching the bank so the lock is held only briefly
+        let root_detection = self.sample_bank.read().root_detection();
+        let sample = SampleBank::load_file(path, root_detection).map_err(|e| e.to_string())?;
+        let (rate_conversion, engine_rate) = self.rate_conversion();
+        let sample = rate_conversion
+            .prepare(sample, engine_rate)
+            .map_err(|e| e.to_string())?;
+        self.install_sample(slot, Some(sample))
+    }
+
+    /// Installs (or clears) a sample in both copies of the bank.
+    pub fn install_sample(&self, slot: usize, sample: Option<Sample>) -> Result<(), String> {
+        let previous = self
+            .sample_bank
+            .write()
+            .insert_sample(slot, sample.clone())
+            .map_err(|e| e.to_string())?;
+
+        // A copy with edited metadata shares its audio, which then stays alive anyway
+        let previous = previous.filter(|previous| {
+            !sample
+                .as_ref()
+                .is_some_and(|sample| Arc::ptr_eq(&sample.data, &previous.data))
+        });
+
+        let mut retired = self.retired.lock();
+        retired.prune();
+        retired.samples.extend(previous);
+        drop(retired);
+
+        self.update_sender
+            .send(EngineUpdate::Sample { slot, sample })
+            .map_err(|e| e.to_string())
+    }
+
+    /// Sets or clears the loop of the sample in `slot`. Notes already
+    /// playing keep the loop they started with.
+    pub fn set_loop(&self, slot: usize, sample_loop: Option<SampleLoop>) -> Result<(), String> {
+        let mut sample = self
+            .sample_bank
+            .read()
+            .get_sample(slot)
+            .cloned()
+            .ok_or_else(|| format!("No sample in slot {slot}"))?;
+        sample.set_loop(sample_loop);
+        self.install_sample(slot, Some(sample))
+    }
+
+    /// Loads an SFZ instrument into slots 0 onwards and switches to its
+    /// region mapping. Decoding happens here, off the audio thread.
+    pub fn load_sfz<P: AsRef<std::path::Path>>(&self, path: P) -> Result<Vec<SfzWarning>, String> {
+        let instrument = SfzInstrument::from_file(&path).map_err(|e| e.to_string())?;
+        let (rate_conversion, engine_rate) = self.rate_conversion();
+        let build = instrument.build(rate_conversion, engine_rate);
+
+        // Samples go first so the new mapping never points at an empty slot
+        for (slot, sample) in build.samples.into_iter().enumerate() {
+            self.install_sample(slot, Some(sample))?;
+        }
+        self.install_mapping(build.mapping)?;
+        let name = path
+            .as_ref()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+        self.set_preset_name(name);
+        Ok(build.warnings)
+    }
+
+    /// Replaces the mapping in both copies of the bank.
+    pub fn install_mapping(&self, mapping: SampleMapping) -> Result<(), String> {
+        self.edit_mapping(|current| {
+            *current = mapping;
+            Ok(())
+        })
+    }
+
+    /// A copy of the mapping notes are currently played with.
+    pub fn mapping(&self) -> SampleMapping {
+        SampleMapping::clone(self.sample_bank.read().mapping())
+    }
+
+    /// Edits a copy of the current mapping and swaps it in as a whole, so
+    /// the audio thread sees either the old mapping or the new one. Nothing
+    /// changes if `edit` fails. Concurrent edits apply one after another;
+    /// the bank is locked meanwhile, so `edit` must not use this handle.
+    pub fn edit_mapping<R>(
+        &self,
+        edit: impl FnOnce(&mut SampleMapping) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let mut bank = self.sample_bank.write();
+        let mut mapping = SampleMapping::clone(bank.mapping());
+        let result = edit(&mut mapping)?;
+
+        let mapping = Arc::new(mapping);
+        let previous = bank.set_mapping(Arc::clone(&mapping));
+
+        let mut retired = self.retired.lock();
+        retired.prune();
+        retired.mappings.push(previous);
+        drop(retired);
+
+        // Sent under the bank lock so edits reach the audio thread in order
+        self.update_sender
+            .send(EngineUpdate::Mapping(mapping))
+            .map_err(|e| e.to_string())?;
+        Ok(result)
+    }
+
+    pub fn add_zone(&self, zone: Zone) -> Result<(), String> {
+        self.edit_mapping(|mapping| {
+            mapping.zones.push(zone);
+            Ok(())
+        })
+    }
+
+    pub fn set_zone(&self, index: usize, zone: Zone) -> Result<(), String> {
+        self.edit_mapping(|mapping| {
+            let entry = mapping
+                .zones
+                .get_mut(index)
+                .ok_or_else(|| format!("No zone {index}"))?;
+            *entry = zone;
+            Ok(())
+        })
+    }
+
+    /// Removes and returns the zone at `index`.
+    pub fn remove_zone(&self, index: usize) -> Result<Zone, String> {
+        self.edit_mapping(|mapping| {
+            if index >= mapping.zones.len() {
+                return Err(format!("No zone {index}"));
+            }
+            Ok(mapping.zones.remove(index))
+        })
+    }
+
+    /// How samples loaded from now on are fitted to the engine's rate.
+    pub fn set_rate_conversion(&self, rate_conversion: RateConversion) {
+        self.sample_bank
+            .write()
+            .set_rate_conversion(rate_conversion);
+    }
+
+    /// How samples loaded from now on get a root note their files don't give.
+    pub fn set_root_detection(&self, root_detection: RootDetection) {
+        self.sample_bank.write().set_root_detection(root_detection);
+    }
+
+    /// Switches every voice to a new interpolation kernel. The kernel tables
+    /// are built here, so call this off the audio thread.
+    pub fn set_interpolation(&self, mode: InterpolationMode) -> Result<(), String> {
+        let interpolator = Interpolator::new(mode);
+
+        let mut retired = self.retired.lock();
+        retired.prune();
+        let previous = std::mem::replace(&mut retired.interpolator, interpolator.clone());
+        retired.interpolators.push(previous);
+        drop(retired);
+
+        self.update_sender
+            .send(EngineUpdate::Interpolation(interpolator))
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn interpolation(&self) -> InterpolationMode {
+        self.retired.lock().interpolator.mode()
+    }
+
+    /// Commands for later blocks the engine turned away because too many
+    /// were already waiting, oldest first. Send them again once the earlier
+    /// ones have played.
+    pub fn refused_commands(&self) -> Vec<TimedCommand> {
+        self.refused.try_iter().collect()
+    }
+
+    /// The frame the next processed block starts on.
+    pub fn current_frame(&self) -> u64 {
+        self.frame_clock.load(Ordering::Relaxed)
+    }
+
+    /// The current sound as a preset. Samples that weren't loaded from a
+    /// file can't be referenced and are left out.
+    pub fn preset(&self) -> Preset {
+        let bank = self.sample_bank.read();
+        let mut settings = *self.settings.lock();
+        settings.interpolation = self.interpolation();
+        Preset {
+            name: self
+                .engine_state
+                .read()
+                .current_preset
+                .clone()
+                .unwrap_or_default(),
+            samples: bank
+                .samples()
+                .filter_map(|(slot, sample)| PresetSample::from_sample(slot, sample))
+                .collect(),
+            mapping: SampleMapping::clone(bank.mapping()),
+            settings,
+            ..Preset::default()
+        }
+    }
+
+    /// Saves the current sound, as RON for `.ron` paths and MessagePack
+    /// otherwise. An unnamed sound takes the file's name.
+    pub fn save_preset<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut preset = self.preset();
+        if preset.name.is_empty() {
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned());
+            preset.name = name.unwrap_or_default();
+            self.set_preset_name(Some(preset.name.clone()));
+        }
+        preset.save(path).map_err(|e| e.to_string())
+    }
+
+    pub fn load_preset<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        let preset = Preset::load(path).map_err(|e| e.to_string())?;
+        self.apply_preset(&preset)
+    }
+
+    /// Switches to a preset. Its samples are decoded first, so nothing
+    /// changes if one fails to load.
+    pub fn apply_preset(&self, preset: &Preset) -> Result<(), String> {
+        let (rate_conversion, engine_rate) = self.rate_conversion();
+        let samples = preset
+            .load_samples(rate_conversion, engine_rate)
+            .map_err(|e| e.to_string())?;
+        self.switch_to(preset, samples)
+    }
+
+    /// Saves the current sound with all of its audio embedded, so it loads
+    /// on other machines.
+    pub fn save_bundle<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        compression: BundleCompression,
+    ) -> Result<(), String> {
+        let path = path.as_ref();
+        let preset = self.preset();
+        let name = if preset.name.is_empty() {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        } else {
+            preset.name
+        };
+        let bundle = Bundle::from_bank(&self.sample_bank.read(), name, preset.settings);
+        bundle.save(path, compression).map_err(|e| e.to_string())
+    }
+
+    pub fn load_bundle<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        let bundle = Bundle::load(path).map_err(|e| e.to_string())?;
+        let (rate_conversion, engine_rate) = self.rate_conversion();
+        let samples = bundle
+            .samples(rate_conversion, engine_rate)
+            .map_err(|e| e.to_string())?;
+        self.switch_to(&bundle.preset, samples)
+    }
+
+    fn rate_conversion(&self) -> (RateConversion, f32) {
+        let bank = self.sample_bank.read();
+        (bank.rate_conversion(), bank.sample_rate())
+    }
+
+    /// Installs a preset whose samples are already loaded.
+    fn switch_to(&self, preset: &Preset, samples: Vec<(usize, Sample)>) -> Result<(), String> {
+        // Empty the slots the preset doesn't use
+        let unused: Vec<usize> = self
+            .sample_bank
+            .read()
+            .samples()
+            .map(|(slot, _)| slot)
+            .filter(|slot| !samples.iter().any(|(used, _)| used == slot))
+            .collect();
+        for slot in unused {
+            self.install_sample(slot, None)?;
+        }
+        for (slot, sample) in samples {
+            self.install_sample(slot, Some(sample))?;
+        }
+        self.install_mapping(preset.mapping.clone())?;
+        for command in preset.settings.commands() {
+            self.send_command(command)?;
+        }
+        self.set_preset_name(Some(preset.name.clone()));
+        Ok(())
+    }
+
+    /// Name reported as the current preset.
+    pub fn set_preset_name(&self, name: Option<String>) {
+        self.engine_state.write().current_preset = name;
+    }
+
     pub fn query(&self, query: EngineQuery) -> EngineResponse {
-        match query.query_type {
-            QueryType::GetState => {
-                let state = self.engine_state.read().clone();
-                EngineResponse {
-                    success: true,
-                    data: Some(ResponseData::State(state)),
-                    error: None,
-                }
+        let result = match query.query_type {
+            QueryType::GetState => Ok(ResponseData::State(self.engine_state.read().clone())),
+            QueryType::GetSampleList => Ok(ResponseData::SampleList(self.sample_list())),
+            QueryType::GetCurrentPreset => self
+                .engine_state
+                .read()
+                .current_preset
+                .clone()
+                .map(ResponseData::Preset)
+                .ok_or_else(|| "No preset loaded".to_string()),
+            QueryType::GetWaveform { source, points } => {
+                self.waveform(source, points).map(ResponseData::Waveform)
             }
-            _ => EngineResponse {
+        };
+
+        match result {
+            Ok(data) => EngineResponse {
+                success: true,
+                data: Some(data),
+                error: None,
+            },
+            Err(error) => EngineResponse {
                 success: false,
                 data: None,
-                error: Some("Not implemented".to_string()),
+                error: Some(error),
             },
         }
     }
+
+    /// Every occupied slot, in slot order.
+    pub fn sample_list(&self) -> Vec<SampleInfo> {
+        self.sample_bank
+            .read()
+            .samples()
+            .map(|(slot, sample)| SampleInfo {
+                slot,
+                name: sample.name.to_string(),
+                duration_ms: sample.duration_ms(),
+                sample_rate: sample.sample_rate,
+                channels: sample.channels,
+            })
+            .collect()
+    }
+
+    /// Peak summary for drawing a slot's sample or a voice's playback region.
+    pub fn waveform(&self, source: WaveformSource, points: usize) -> Result<WaveformPeaks, String> {
+        let bank = self.sample_bank.read();
+        match source {
+            WaveformSource::Slot(slot) => bank
+                .get_sample(slot)
+                .map(|sample| sample.peaks(0, sample.frames(), points))
+                .ok_or_else(|| format!("No sample in slot {slot}")),
+            WaveformSource::Voice(index) => {
+                let info = self
+                    .engine_state
+                    .read()
+                    .voices
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| format!("No voice {index}"))?;
+                let slot = info
+                    .slot
+                    .filter(|_| info.note.is_some())
+                    .ok_or_else(|| format!("Voice {index} is idle"))?;
+                let sample = bank
+                    .get_sample(slot)
+                    .ok_or_else(|| format!("No sample in slot {slot}"))?;
+                let mut peaks = sample.peaks(info.start_frame, info.end_frame, points);
+                peaks.position = Some(info.position);
+                Ok(peaks)
+            }
+        }
+    }
 }
@@ -0,0 +1,420 @@
+use crate::{
+    encode_flac, DecodeError, DecoderRegistry, EncodeError, EngineSettings, Preset, PresetError,
+    PresetFormat, PresetSample, RateConversion, Sample, SampleBank, SampleMapping,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use zimler_dsp::ResampleError;
+
+/// Container version this build writes.
+pub const BUNDLE_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"ZMLB";
+
+// Chunk ids
+const PRESET_CHUNK: &[u8; 4] = b"PRST";
+const AUDIO_CHUNK: &[u8; 4] = b"AUDI";
+
+// Embedded audio encodings
+const RAW_F32: u8 = 0;
+const FLAC: u8 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Not an instrument bundle")]
+    NotABundle,
+    #[error("Bundle version {0} is newer than this build supports ({BUNDLE_VERSION})")]
+    TooNew(u32),
+    #[error("Invalid bundle: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Preset(#[from] PresetError),
+    #[error("Embedded audio {hash}: {error}")]
+    Decode {
+        hash: ContentHash,
+        error: DecodeError,
+    },
+    #[error("Embedded audio {hash}: {error}")]
+    Encode {
+        hash: ContentHash,
+        error: EncodeError,
+    },
+    #[error("No embedded audio for slot {slot}")]
+    MissingAudio { slot: usize },
+    #[error("Sample for slot {slot}: {error}")]
+    Resample { slot: usize, error: ResampleError },
+}
+
+/// How embedded audio is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BundleCompression {
+    /// 32-bit floats, exactly as in the bank
+    #[default]
+    None,
+    /// 24-bit FLAC, typically a third to half smaller. Audio FLAC can't hold
+    /// (more than 8 channels, or peaks above 0 dBFS) is stored raw.
+    Flac,
+}
+
+/// BLAKE3 hash of a sample's audio, channel count and rate. Slots playing
+/// the same audio share one embedded copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    pub fn of(sample: &Sample) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&(sample.channels as u32).to_le_bytes());
+        hasher.update(&sample.sample_rate.to_le_bytes());
+        let mut buffer = Vec::with_capacity(4096 * 4);
+        for chunk in sample.data.chunks(4096) {
+            buffer.clear();
+            buffer.extend(chunk.iter().flat_map(|x| x.to_le_bytes()));
+            hasher.update(&buffer);
+        }
+        Self(*hasher.finalize().as_bytes())
+    }
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl From<ContentHash> for String {
+    fn from(hash: ContentHash) -> Self {
+        hash.to_string()
+    }
+}
+
+impl TryFrom<String> for ContentHash {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid content hash: {hex}");
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// A single-file instrument: a preset plus the audio it plays, so it
+/// loads on any machine.
+///
+/// On disk it is the magic `ZMLB` and a little-endian u32 version, then
+/// chunks of a 4-byte id, a u64 length and the body: one `PRST` chunk
+/// holding the preset as MessagePack, and an `AUDI` chunk per distinct
+/// audio (hash, encoding, channels, rate, data).
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub preset: Preset,
+    /// Audio the preset's samples refer to by `content_hash`
+    pub audio: HashMap<ContentHash, Sample>,
+}
+
+impl Bundle {
+    /// Embeds every sample in `bank`, along with its mapping.
+    pub fn from_bank(bank: &SampleBank, name: String, settings: EngineSettings) -> Self {
+        let mut audio = HashMap::new();
+        let samples = bank
+            .samples()
+            .map(|(slot, sample)| {
+                let hash = ContentHash::of(sample);
+                audio.entry(hash).or_insert_with(|| sample.clone());
+                PresetSample {
+                    slot,
+                    // Kept for reference; the embedded audio is what plays
+                    path: sample
+                        .source
+                        .as_deref()
+                        .map_or_else(|| PathBuf::from(&*sample.name), Path::to_path_buf),
+                    root_note: sample.root_note,
+                    tune_cents: sample.tune_cents,
+                    sample_loop: sample.sample_loop,
+                    sample_rate: sample.sample_rate,
+                    content_hash: Some(hash),
+                }
+            })
+            .collect();
+
+        Self {
+            preset: Preset {
+                name,
+                samples,
+                mapping: SampleMapping::clone(bank.mapping()),
+                settings,
+                ..Preset::default()
+            },
+            audio,
+        }
+    }
+
+    pub fn to_bytes(&self, compression: BundleCompression) -> Result<Vec<u8>, BundleError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&BUNDLE_VERSION.to_le_bytes());
+
+        let preset = self.preset.to_bytes(PresetFormat::MessagePack)?;
+        write_chunk(&mut out, PRESET_CHUNK, &preset);
+
+        // Sorted so the same bundle always writes the same bytes
+        let mut audio: Vec<_> = self.audio.iter().collect();
+        audio.sort_by_key(|(hash, _)| hash.0);
+        for (hash, sample) in audio {
+            let flac = match compression {
+                BundleCompression::Flac => match encode_flac(sample) {
+                    Ok(flac) => Some(flac),
+                    Err(EncodeError::Channels(_) | EncodeError::OverFullScale(_)) => None,
+                    Err(error) => return Err(BundleError::Encode { hash: *hash, error }),
+                },
+                BundleCompression::None => None,
+            };
+            let mut body = Vec::new();
+            body.extend_from_slice(&hash.0);
+            body.push(if flac.is_some() { FLAC } else { RAW_F32 });
+            body.extend_from_slice(&(sample.channels as u16).to_le_bytes());
+            body.extend_from_slice(&sample.sample_rate.to_le_bytes());
+            match flac {
+                Some(flac) => body.extend_from_slice(&flac),
+                None => body.extend(sample.data.iter().flat_map(|x| x.to_le_bytes())),
+            }
+            write_chunk(&mut out, AUDIO_CHUNK, &body);
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BundleError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(BundleError::NotABundle);
+        }
+        let version = read_u32(bytes, 4).ok_or(BundleError::NotABundle)?;
+        if version > BUNDLE_VERSION {
+            return Err(BundleError::TooNew(version));
+        }
+
+        let mut preset = None;
+        let mut audio = HashMap::new();
+        let mut pos = 8;
+        while pos < bytes.len() {
+            let header = bytes
+                .get(pos..pos + 12)
+                .ok_or_else(|| BundleError::Invalid("truncated chunk header".to_string()))?;
+            let size = u64::from_le_bytes(header[4..12].try_into().unwrap_or_default());
+            let body_start = pos + 12;
+            let body = usize::try_from(size)
+                .ok()
+                .and_then(|size| bytes.get(body_start..body_start.checked_add(size)?))
+                .ok_or_else(|| BundleError::Invalid("truncated chunk".to_string()))?;
+
+            match &header[..4] {
+                id if id == PRESET_CHUNK => preset = Some(Preset::from_bytes(body)?),
+                id if id == AUDIO_CHUNK => {
+                    let (hash, sample) = read_audio(body)?;
+                    audio.insert(hash, sample);
+                }
+                // Unknown chunks are from newer builds; skip them
+                _ => {}
+            }
+            pos = body_start + body.len();
+        }
+
+        let preset = preset.ok_or_else(|| BundleError::Invalid("no preset".to_string()))?;
+        Ok(Self { preset, audio })
+    }
+
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        compression: BundleCompression,
+    ) -> Result<(), BundleError> {
+        std::fs::write(path, self.to_bytes(compression)?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BundleError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// The preset's samples with their edits applied, ready to install.
+    /// Slots sharing audio share its memory too.
+    pub fn samples(
+        &self,
+        rate_conversion: RateConversion,
+        engine_rate: f32,
+    ) -> Result<Vec<(usize, Sample)>, BundleError> {
+        self.preset
+            .samples
+            .iter()
+            .map(|entry| {
+                let slot = entry.slot;
+                let mut sample = entry
+                    .content_hash
+                    .and_then(|hash| self.audio.get(&hash))
+                    .cloned()
+                    .ok_or(BundleError::MissingAudio { slot })?;
+                if let Some(stem) = entry.path.file_stem() {
+                    sample.name = stem.to_string_lossy().into();
+                }
+                let sample = entry
+                    .prepare(sample, rate_conversion, engine_rate)
+                    .map_err(|error| BundleError::Resample { slot, error })?;
+                Ok((slot, sample))
+            })
+            .collect()
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    out.extend_from_slice(body);
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    let b = bytes.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_audio(body: &[u8]) -> Result<(ContentHash, Sample), BundleError> {
+    let invalid = || BundleError::Invalid("truncated audio chunk".to_string());
+    let header = body.get(..39).ok_or_else(invalid)?;
+    let hash = ContentHash(header[..32].try_into().map_err(|_| invalid())?);
+    let encoding = header[32];
+    let channels = usize::from(u16::from_le_bytes([header[33], header[34]]));
+    let sample_rate = f32::from_le_bytes([header[35], header[36], header[37], header[38]]);
+    let payload = &body[39..];
+
+    let mut sample = match encoding {
+        RAW_F32 => Sample::new(
+            payload
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            sample_rate,
+            channels,
+        ),
+        FLAC => DecoderRegistry::default()
+            .decode_bytes(payload, Some("flac"))
+            .map_err(|error| BundleError::Decode { hash, error })?,
+        other => {
+            return Err(BundleError::Invalid(format!(
+                "unknown audio encoding {other}"
+            )))
+        }
+    };
+    // FLAC rounds the rate to whole hertz
+    sample.sample_rate = sample_rate;
+    Ok((hash, sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LoopMode, SampleLoop};
+    use std::sync::Arc;
+
+    /// A bank with a quiet stereo sample in two slots and a hot mono one.
+    fn bank() -> SampleBank {
+        let step = 1.0 / (1 << 23) as f32;
+        let quiet: Vec<f32> = (0..2000)
+            .map(|i| ((i as f32 * 0.02).sin() * 0.5 / step).round() * step)
+            .collect();
+        let mut quiet = Sample::new(quiet, 48000.0, 2);
+        quiet.root_note = Some(64);
+        quiet.set_loop(Some(SampleLoop::new(100, 900, LoopMode::Forward)));
+        // Peaks above 0 dBFS, as float recordings can
+        let hot = Sample::new(vec![0.0, 1.5, -2.0, 0.25, 1.0], 44100.0, 1);
+
+        let mut bank = SampleBank::with_sample_rate(48000.0);
+        bank.insert_sample(0, Some(quiet.clone())).unwrap();
+        bank.insert_sample(1, Some(quiet)).unwrap();
+        bank.insert_sample(2, Some(hot)).unwrap();
+        bank.set_mapping(Arc::new(SampleMapping::round_robin(&[0, 1, 2])));
+        bank
+    }
+
+    fn round_trip(compression: BundleCompression) {
+        let bundle = Bundle::from_bank(&bank(), "Test".to_string(), EngineSettings::default());
+        // Slots 0 and 1 share their audio
+        assert_eq!(bundle.audio.len(), 2);
+
+        let bytes = bundle.to_bytes(compression).unwrap();
+        let loaded = Bundle::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.preset, bundle.preset);
+        assert_eq!(loaded.audio.len(), bundle.audio.len());
+        for (hash, sample) in &bundle.audio {
+            let back = &loaded.audio[hash];
+            assert_eq!(back.channels, sample.channels);
+            assert_eq!(back.sample_rate, sample.sample_rate);
+            assert_eq!(back.data, sample.data);
+            assert_eq!(ContentHash::of(back), *hash);
+        }
+        // Writing is deterministic
+        assert_eq!(bundle.to_bytes(compression).unwrap(), bytes);
+    }
+
+    #[test]
+    fn round_trips_raw() {
+        round_trip(BundleCompression::None);
+    }
+
+    #[test]
+    fn round_trips_flac_storing_hot_audio_raw() {
+        round_trip(BundleCompression::Flac);
+    }
+
+    #[test]
+    fn flac_is_smaller() {
+        let bundle = Bundle::from_bank(&bank(), "Test".to_string(), EngineSettings::default());
+        let raw = bundle.to_bytes(BundleCompression::None).unwrap();
+        let flac = bundle.to_bytes(BundleCompression::Flac).unwrap();
+        assert!(flac.len() < raw.len());
+    }
+
+    #[test]
+    fn samples_come_back_ready_to_install() {
+        let bundle = Bundle::from_bank(&bank(), "Test".to_string(), EngineSettings::default());
+        let loaded =
+            Bundle::from_bytes(&bundle.to_bytes(BundleCompression::Flac).unwrap()).unwrap();
+        let samples = loaded.samples(RateConversion::Playback, 48000.0).unwrap();
+        assert_eq!(samples.len(), 3);
+        let (_, first) = samples.iter().find(|(slot, _)| *slot == 0).unwrap();
+        assert_eq!(first.root_note, Some(64));
+        assert_eq!(
+            first.sample_loop,
+            Some(SampleLoop::new(100, 900, LoopMode::Forward))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(matches!(
+            Bundle::from_bytes(b"RIFF...."),
+            Err(BundleError::NotABundle)
+        ));
+        let mut bytes = Bundle::from_bank(&bank(), String::new(), EngineSettings::default())
+            .to_bytes(BundleCompression::None)
+            .unwrap();
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(
+            Bundle::from_bytes(&bytes),
+            Err(BundleError::Invalid(_))
+        ));
+        bytes[4..8].copy_from_slice(&(BUNDLE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Bundle::from_bytes(&bytes),
+            Err(BundleError::TooNew(_))
+        ));
+    }
+}
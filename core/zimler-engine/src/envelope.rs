@@ -1,22 +1,220 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use wide::f32x4;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Most points a `Variable` envelope can have.
+pub const MAX_BREAKPOINTS: usize = 32;
+
+// Breakpoints are stored inline, so `Variable` dwarfs the other shapes
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum EnvelopeShape {
     ADSR {
         attack_ms: f32,
         decay_ms: f32,
         sustain: f32,
         release_ms: f32,
+        #[serde(default)]
+        attack_curve: Curve,
+        #[serde(default)]
+        decay_curve: Curve,
+        #[serde(default)]
+        release_curve: Curve,
     },
     AR {
         attack_ms: f32,
         release_ms: f32,
+        #[serde(default)]
+        attack_curve: Curve,
+        #[serde(default)]
+        release_curve: Curve,
     },
     Trapezoid {
         rise_ms: f32,
         hold_ms: f32,
         fall_ms: f32,
+        #[serde(default)]
+        rise_curve: Curve,
+        #[serde(default)]
+        fall_curve: Curve,
+    },
+    /// Rise, hold and fall over and over, like a cycling slope generator;
+    /// with no hold it loops attack and decay. Released, it falls to zero,
+    /// or if free running finishes the cycle it is in.
+    Cycling {
+        rise_ms: f32,
+        hold_ms: f32,
+        fall_ms: f32,
+        #[serde(default)]
+        rise_curve: Curve,
+        #[serde(default)]
+        fall_curve: Curve,
+        #[serde(default)]
+        rate: CycleRate,
+        /// As a modulation source, runs on its own rather than restarting
+        /// with each note
+        #[serde(default)]
+        free_running: bool,
     },
+    /// Drawn envelope: straight or curved segments through each point in
+    /// turn. Held notes stop at the sustain point, or go round the loop,
+    /// until released; release then plays the points after them. Without
+    /// either, release heads straight for the last point. The envelope
+    /// ends at the last point, so that should be at 0.
+    Variable {
+        points: Breakpoints,
+        #[serde(default)]
+        sustain: Option<usize>,
+        /// First and last point of a loop. Reaching the last, the envelope
+        /// carries on towards the point after the first.
+        #[serde(default)]
+        loop_points: Option<(usize, usize)>,
+    },
+}
+
+/// One point of a `Variable` envelope.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Breakpoint {
+    /// Time to get here from the point before, or from the note's start
+    pub time_ms: f32,
+    pub level: f32,
+    /// Shape of the segment arriving here
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("An envelope holds at most {MAX_BREAKPOINTS} breakpoints")]
+pub struct TooManyBreakpoints;
+
+/// The points of a `Variable` envelope, held inline so shapes stay `Copy`
+/// and reach the audio thread without allocating. Derefs to a slice and
+/// serializes as a list.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Breakpoints {
+    points: [Breakpoint; MAX_BREAKPOINTS],
+    len: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self {
+            points: [Breakpoint::default(); MAX_BREAKPOINTS],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, point: Breakpoint) -> Result<(), TooManyBreakpoints> {
+        self.insert(self.len, point)
+    }
+
+    /// Inserts a point before `index`, or at the end if `index` is past it.
+    pub fn insert(&mut self, index: usize, point: Breakpoint) -> Result<(), TooManyBreakpoints> {
+        if self.len == MAX_BREAKPOINTS {
+            return Err(TooManyBreakpoints);
+        }
+        let index = index.min(self.len);
+        self.points.copy_within(index..self.len, index + 1);
+        self.points[index] = point;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Breakpoint> {
+        if index >= self.len {
+            return None;
+        }
+        let point = self.points[index];
+        self.points.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        Some(point)
+    }
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Breakpoints {
+    type Target = [Breakpoint];
+
+    fn deref(&self) -> &[Breakpoint] {
+        &self.points[..self.len]
+    }
+}
+
+impl DerefMut for Breakpoints {
+    fn deref_mut(&mut self) -> &mut [Breakpoint] {
+        &mut self.points[..self.len]
+    }
+}
+
+impl TryFrom<&[Breakpoint]> for Breakpoints {
+    type Error = TooManyBreakpoints;
+
+    fn try_from(points: &[Breakpoint]) -> Result<Self, Self::Error> {
+        let mut breakpoints = Self::new();
+        for &point in points {
+            breakpoints.push(point)?;
+        }
+        Ok(breakpoints)
+    }
+}
+
+impl fmt::Debug for Breakpoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for Breakpoints {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Breakpoints {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let points = Vec::<Breakpoint>::deserialize(deserializer)?;
+        Self::try_from(points.as_slice()).map_err(serde::de::Error::custom)
+    }
+}
+
+/// How long one cycle of a cycling envelope takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum CycleRate {
+    /// The segment times as given
+    #[default]
+    Times,
+    /// Cycles per second, the segment times setting only their proportions
+    Hz(f32),
+    /// Beats per cycle at the engine tempo, likewise
+    Beats(f32),
+}
+
+/// How an envelope segment travels between its levels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+    /// Curvature from -1 to 1. Negative bows the segment above a straight
+    /// line (logarithmic), positive below it (exponential: slow rises,
+    /// falls with long tails); 0 is linear.
+    Shaped(f32),
+    /// Analog-style: heads for the next level like a charging capacitor,
+    /// fast at first and slowing on arrival, in either direction
+    Rc,
+}
+
+impl Curve {
+    pub const LINEAR: Self = Self::Shaped(0.0);
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self::LINEAR
+    }
 }
 
 impl Default for EnvelopeShape {
@@ -26,10 +224,147 @@ impl Default for EnvelopeShape {
             decay_ms: 100.0,
             sustain: 0.7,
             release_ms: 200.0,
+            attack_curve: Curve::LINEAR,
+            decay_curve: Curve::LINEAR,
+            release_curve: Curve::LINEAR,
         }
     }
 }
 
+impl EnvelopeShape {
+    /// Sets the opening segment (attack, rise for trapezoids, the first
+    /// point for drawn envelopes).
+    pub fn set_attack_ms(&mut self, ms: f32) {
+        match self {
+            Self::ADSR { attack_ms, .. } | Self::AR { attack_ms, .. } => *attack_ms = ms,
+            Self::Trapezoid { rise_ms, .. } | Self::Cycling { rise_ms, .. } => *rise_ms = ms,
+            Self::Variable { points, .. } => {
+                if let Some(point) = points.first_mut() {
+                    point.time_ms = ms;
+                }
+            }
+        }
+    }
+
+    /// Sets the decay segment (hold for trapezoids and cycles; AR has none).
+    pub fn set_decay_ms(&mut self, ms: f32) {
+        match self {
+            Self::ADSR { decay_ms, .. } => *decay_ms = ms,
+            Self::Trapezoid { hold_ms, .. } | Self::Cycling { hold_ms, .. } => *hold_ms = ms,
+            Self::AR { .. } | Self::Variable { .. } => {}
+        }
+    }
+
+    /// Sets the sustain level, of ADSR or a drawn envelope's sustain point.
+    pub fn set_sustain(&mut self, level: f32) {
+        match self {
+            Self::ADSR { sustain, .. } => *sustain = level,
+            Self::Variable {
+                points,
+                sustain: Some(index),
+                ..
+            } => {
+                if let Some(point) = points.get_mut(*index) {
+                    point.level = level;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets the closing segment (release, fall for trapezoids and cycles,
+    /// the last point for drawn envelopes).
+    pub fn set_release_ms(&mut self, ms: f32) {
+        match self {
+            Self::ADSR { release_ms, .. } | Self::AR { release_ms, .. } => *release_ms = ms,
+            Self::Trapezoid { fall_ms, .. } | Self::Cycling { fall_ms, .. } => *fall_ms = ms,
+            Self::Variable { points, .. } => {
+                if let Some(point) = points.last_mut() {
+                    point.time_ms = ms;
+                }
+            }
+        }
+    }
+
+    /// Sets the opening segment's curve.
+    pub fn set_attack_curve(&mut self, curve: Curve) {
+        match self {
+            Self::ADSR { attack_curve, .. } | Self::AR { attack_curve, .. } => {
+                *attack_curve = curve;
+            }
+            Self::Trapezoid { rise_curve, .. } | Self::Cycling { rise_curve, .. } => {
+                *rise_curve = curve;
+            }
+            Self::Variable { points, .. } => {
+                if let Some(point) = points.first_mut() {
+                    point.curve = curve;
+                }
+            }
+        }
+    }
+
+    /// Sets the decay segment's curve; only ADSR has a curved decay.
+    pub fn set_decay_curve(&mut self, curve: Curve) {
+        if let Self::ADSR { decay_curve, .. } = self {
+            *decay_curve = curve;
+        }
+    }
+
+    /// Sets the closing segment's curve.
+    pub fn set_release_curve(&mut self, curve: Curve) {
+        match self {
+            Self::ADSR { release_curve, .. } | Self::AR { release_curve, .. } => {
+                *release_curve = curve;
+            }
+            Self::Trapezoid { fall_curve, .. } | Self::Cycling { fall_curve, .. } => {
+                *fall_curve = curve;
+            }
+            Self::Variable { points, .. } => {
+                if let Some(point) = points.last_mut() {
+                    point.curve = curve;
+                }
+            }
+        }
+    }
+
+    /// Gives every segment the same curve, e.g. `Curve::Rc` for an
+    /// analog-style envelope.
+    pub fn set_curves(&mut self, curve: Curve) {
+        if let Self::Variable { points, .. } = self {
+            for point in points.iter_mut() {
+                point.curve = curve;
+            }
+            return;
+        }
+        self.set_attack_curve(curve);
+        self.set_decay_curve(curve);
+        self.set_release_curve(curve);
+    }
+
+    /// Whether this is a cycling shape that runs regardless of notes.
+    pub fn is_free_running(&self) -> bool {
+        matches!(
+            self,
+            Self::Cycling {
+                free_running: true,
+                ..
+            }
+        )
+    }
+}
+
+// Exponent a segment's progress follows at curvature ±1
+const MAX_CURVATURE: f32 = 8.0;
+
+// Time constants an RC segment spans; 5 is all but fully charged
+const RC_TIME_CONSTANTS: f32 = 5.0;
+
+// Tempo cycles synced to beats follow until told otherwise
+const DEFAULT_TEMPO: f32 = 120.0;
+
+// Time constant for gliding to a new sustain level while a note is held
+const SUSTAIN_SLEW_MS: f32 = 5.0;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum EnvelopeStage {
     Idle,
@@ -37,18 +372,37 @@ enum EnvelopeStage {
     Decay,
     Sustain,
     Release,
-    Hold, // For trapezoid
+    Hold,  // For trapezoid
+    Point, // Heading for a drawn envelope's `point`
 }
 
+#[derive(Clone)]
 pub struct Envelope {
     shape: EnvelopeShape,
     stage: EnvelopeStage,
     current_value: f32,
     target_value: f32,
-    rate: f32,
+    // Each step of a segment is `value * coef + offset`
+    coef: f32,
+    offset: f32,
+    // Level at the start of the current four steps of a segment. Every
+    // level comes from one of these, so however the frames are split into
+    // blocks the envelope comes out the same.
+    anchor: f32,
     sample_rate: f32,
     samples_in_stage: usize,
     stage_counter: usize,
+    sustain_slew: f32,
+    // Beats per minute, for cycles synced to tempo
+    tempo: f32,
+    // Fraction of a sample the last segment was cut short by, so cycles
+    // keep their rate over time
+    carry: f32,
+    // Free-running cycles stop at the end of the cycle once released, and
+    // drawn envelopes leave their loop or sustain point
+    released: bool,
+    // Drawn envelope point being headed for
+    point: usize,
 }
 
 impl Envelope {
@@ -58,10 +412,17 @@ impl Envelope {
             stage: EnvelopeStage::Idle,
             current_value: 0.0,
             target_value: 0.0,
-            rate: 0.0,
+            coef: 1.0,
+            offset: 0.0,
+            anchor: 0.0,
             sample_rate,
             samples_in_stage: 0,
             stage_counter: 0,
+            sustain_slew: 1.0 - (-1.0 / ((SUSTAIN_SLEW_MS / 1000.0) * sample_rate)).exp(),
+            tempo: DEFAULT_TEMPO,
+            carry: 0.0,
+            released: false,
+            point: 0,
         }
     }
 
@@ -69,85 +430,256 @@ impl Envelope {
         self.shape = shape;
     }
 
+    pub fn shape(&self) -> EnvelopeShape {
+        self.shape
+    }
+
+    /// Tempo in beats per minute, from the next segment on.
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = bpm.max(1.0);
+    }
+
     pub fn trigger(&mut self) {
-        match self.shape {
-            EnvelopeShape::ADSR { attack_ms, .. } => {
-                self.stage = EnvelopeStage::Attack;
-                self.target_value = 1.0;
-                self.samples_in_stage = ((attack_ms / 1000.0) * self.sample_rate) as usize;
-                self.rate = if self.samples_in_stage > 0 {
-                    (self.target_value - self.current_value) / self.samples_in_stage as f32
-                } else {
-                    1.0
-                };
-            }
-            EnvelopeShape::AR { attack_ms, .. } => {
-                self.stage = EnvelopeStage::Attack;
-                self.target_value = 1.0;
-                self.samples_in_stage = ((attack_ms / 1000.0) * self.sample_rate) as usize;
-                self.rate = if self.samples_in_stage > 0 {
-                    (self.target_value - self.current_value) / self.samples_in_stage as f32
-                } else {
-                    1.0
-                };
-            }
-            EnvelopeShape::Trapezoid { rise_ms, .. } => {
-                self.stage = EnvelopeStage::Attack;
-                self.target_value = 1.0;
-                self.samples_in_stage = ((rise_ms / 1000.0) * self.sample_rate) as usize;
-                self.rate = if self.samples_in_stage > 0 {
-                    (self.target_value - self.current_value) / self.samples_in_stage as f32
-                } else {
-                    1.0
-                };
+        self.released = false;
+        self.carry = 0.0;
+        let (attack_ms, curve) = match self.shape {
+            EnvelopeShape::ADSR {
+                attack_ms,
+                attack_curve,
+                ..
             }
-        }
-        self.stage_counter = 0;
+            | EnvelopeShape::AR {
+                attack_ms,
+                attack_curve,
+                ..
+            } => (attack_ms, attack_curve),
+            EnvelopeShape::Trapezoid {
+                rise_ms,
+                rise_curve,
+                ..
+            }
+            | EnvelopeShape::Cycling {
+                rise_ms,
+                rise_curve,
+                ..
+            } => (rise_ms, rise_curve),
+            EnvelopeShape::Variable { .. } => {
+                self.go_to_point(0);
+                return;
+            }
+        };
+        self.stage = EnvelopeStage::Attack;
+        self.start_segment(1.0, attack_ms, curve);
+    }
+
+    /// Drops straight to silence, so the next trigger attacks from zero.
+    pub fn reset(&mut self) {
+        self.stage = EnvelopeStage::Idle;
+        self.current_value = 0.0;
     }
 
     pub fn release(&mut self) {
-        if self.stage != EnvelopeStage::Idle && self.stage != EnvelopeStage::Release {
-            self.stage = EnvelopeStage::Release;
-            self.target_value = 0.0;
+        if self.shape.is_free_running() {
+            self.released = true;
+            return;
+        }
+        if self.stage == EnvelopeStage::Idle || self.stage == EnvelopeStage::Release {
+            return;
+        }
+        let (release_ms, curve) = match self.shape {
+            EnvelopeShape::ADSR {
+                release_ms,
+                release_curve,
+                ..
+            }
+            | EnvelopeShape::AR {
+                release_ms,
+                release_curve,
+                ..
+            } => (release_ms, release_curve),
+            EnvelopeShape::Trapezoid {
+                fall_ms,
+                fall_curve,
+                ..
+            }
+            | EnvelopeShape::Cycling {
+                fall_ms,
+                fall_curve,
+                ..
+            } => (fall_ms, fall_curve),
+            EnvelopeShape::Variable {
+                points,
+                sustain,
+                loop_points,
+            } => {
+                self.release_points(&points, sustain.or(loop_points.map(|(_, end)| end)));
+                return;
+            }
+        };
+        self.stage = EnvelopeStage::Release;
+        self.start_segment(0.0, release_ms, curve);
+    }
 
-            let release_ms = match self.shape {
-                EnvelopeShape::ADSR { release_ms, .. } => release_ms,
-                EnvelopeShape::AR { release_ms, .. } => release_ms,
-                EnvelopeShape::Trapezoid { fall_ms, .. } => fall_ms,
-            };
+    /// Moves a drawn envelope on to the points after `hold`, where a held
+    /// note waits, or with none to the last point.
+    fn release_points(&mut self, points: &Breakpoints, hold: Option<usize>) {
+        if self.released {
+            return;
+        }
+        self.released = true;
 
-            self.samples_in_stage = ((release_ms / 1000.0) * self.sample_rate) as usize;
-            self.rate = if self.samples_in_stage > 0 {
-                -self.current_value / self.samples_in_stage as f32
-            } else {
-                -1.0
-            };
-            self.stage_counter = 0;
+        let next = hold.map_or(points.len().saturating_sub(1), |index| index + 1);
+        if self.stage == EnvelopeStage::Point && self.point >= next {
+            return;
+        }
+        match points.get(next).or(points.last()) {
+            Some(_) if next < points.len() => self.go_to_point(next),
+            // Nothing after the sustain point: fall to zero over its time
+            Some(last) => {
+                self.stage = EnvelopeStage::Release;
+                self.start_segment(0.0, last.time_ms, last.curve);
+            }
+            None => self.stage = EnvelopeStage::Idle,
         }
     }
 
-    pub fn process_sample(&mut self) {
-        match self.stage {
-            EnvelopeStage::Idle => {}
-            EnvelopeStage::Attack => {
-                self.current_value += self.rate;
-                self.stage_counter += 1;
+    /// Heads for point `index` of a drawn envelope, ending past the last.
+    fn go_to_point(&mut self, index: usize) {
+        let EnvelopeShape::Variable { points, .. } = &self.shape else {
+            return;
+        };
+        match points.get(index).copied() {
+            Some(point) => {
+                self.stage = EnvelopeStage::Point;
+                self.point = index;
+                self.start_segment(point.level, point.time_ms, point.curve);
+            }
+            None => self.stage = EnvelopeStage::Idle,
+        }
+    }
 
-                if self.stage_counter >= self.samples_in_stage || self.current_value >= 1.0 {
-                    self.current_value = 1.0;
-                    self.advance_stage();
+    /// Where a drawn envelope goes on reaching its current point.
+    fn next_point(&mut self) {
+        let EnvelopeShape::Variable {
+            sustain,
+            loop_points,
+            ..
+        } = self.shape
+        else {
+            return;
+        };
+        if !self.released {
+            if let Some((start, end)) = loop_points.filter(|(start, end)| start < end) {
+                if self.point == end {
+                    self.go_to_point(start + 1);
+                    return;
                 }
             }
-            EnvelopeStage::Decay => {
-                self.current_value += self.rate;
-                self.stage_counter += 1;
+            if sustain == Some(self.point) {
+                self.stage = EnvelopeStage::Sustain;
+                return;
+            }
+        }
+        self.go_to_point(self.point + 1);
+    }
 
-                if self.stage_counter >= self.samples_in_stage {
-                    self.advance_stage();
+    /// Sets up a segment from the current level to `target` over `ms`.
+    /// Curved segments follow `(e^(kt) - 1) / (e^k - 1)` of the way, which
+    /// steps as a one-pole recursion, so only the setup needs `exp`.
+    fn start_segment(&mut self, target: f32, ms: f32, curve: Curve) {
+        let start = self.current_value;
+        let span = target - start;
+        self.target_value = target;
+        self.samples_in_stage = self.segment_samples(ms);
+        self.stage_counter = 0;
+        self.anchor = start;
+
+        if self.samples_in_stage == 0 {
+            self.coef = 0.0;
+            self.offset = target;
+            return;
+        }
+        let steps = self.samples_in_stage as f32;
+
+        // Exponent for progress through the segment: below 0 starts fast,
+        // above 0 starts slow
+        let k = match curve {
+            Curve::Shaped(curvature) if span < 0.0 => -curvature.clamp(-1.0, 1.0) * MAX_CURVATURE,
+            Curve::Shaped(curvature) => curvature.clamp(-1.0, 1.0) * MAX_CURVATURE,
+            Curve::Rc => -RC_TIME_CONSTANTS,
+        };
+        if k.abs() < 1e-3 {
+            self.coef = 1.0;
+            self.offset = span / steps;
+            return;
+        }
+        let coef = (k / steps).exp();
+        let step = (coef - 1.0) / k.exp_m1();
+        self.coef = coef;
+        self.offset = start * (1.0 - coef) + span * step;
+    }
+
+    /// Samples in a segment of `ms`, stretched to a cycle's rate.
+    fn segment_samples(&mut self, ms: f32) -> usize {
+        let exact = self.cycle_ms(ms) / 1000.0 * self.sample_rate + self.carry;
+        let samples = exact.max(0.0) as usize;
+        self.carry = exact - samples as f32;
+        samples
+    }
+
+    /// A segment's time once a cycling shape's rate is applied.
+    fn cycle_ms(&self, ms: f32) -> f32 {
+        let EnvelopeShape::Cycling {
+            rise_ms,
+            hold_ms,
+            fall_ms,
+            rate,
+            ..
+        } = self.shape
+        else {
+            return ms;
+        };
+        let period_ms = match rate {
+            CycleRate::Hz(hz) if hz > 0.0 => 1000.0 / hz,
+            CycleRate::Beats(beats) => beats.max(0.0) * 60_000.0 / self.tempo,
+            // No rate, or none that can be met
+            CycleRate::Times | CycleRate::Hz(_) => return ms,
+        };
+        let total = rise_ms + hold_ms + fall_ms;
+        if total > 0.0 {
+            ms / total * period_ms
+        } else {
+            0.0
+        }
+    }
+
+    fn step(&mut self) {
+        let steps = Steps::new(self.coef, self.offset);
+        self.stage_counter += 1;
+        let phase = self.stage_counter % 4;
+        if phase == 0 {
+            self.anchor = steps.next_anchor(self.anchor);
+        }
+        self.current_value = steps.levels(self.anchor).to_array()[phase];
+    }
+
+    pub fn process_sample(&mut self) {
+        match self.stage {
+            EnvelopeStage::Idle => {}
+            EnvelopeStage::Attack
+            | EnvelopeStage::Decay
+            | EnvelopeStage::Point
+            | EnvelopeStage::Release => {
+                self.step();
+                if self.is_segment_done() {
+                    self.end_segment();
                 }
             }
             EnvelopeStage::Sustain => {
-                // Hold at sustain level
+                // Hold at sustain level, gliding if it was changed mid-note
+                if let EnvelopeShape::ADSR { sustain, .. } = self.shape {
+                    self.current_value += (sustain - self.current_value) * self.sustain_slew;
+                }
             }
             EnvelopeStage::Hold => {
                 self.stage_counter += 1;
@@ -155,77 +687,195 @@ impl Envelope {
                     self.advance_stage();
                 }
             }
-            EnvelopeStage::Release => {
-                self.current_value += self.rate;
-                self.stage_counter += 1;
+        }
 
-                if self.stage_counter >= self.samples_in_stage || self.current_value <= 0.0 {
-                    self.current_value = 0.0;
-                    self.stage = EnvelopeStage::Idle;
+        self.current_value = self.current_value.clamp(0.0, 1.0);
+    }
+
+    /// Fills `out` with the level for each of the next frames, stepping
+    /// past them: what `get_current_value` would read before each call to
+    /// `process_sample`. Stages change wherever they fall in the block, and
+    /// segments are stepped four frames at a time.
+    pub fn process_block(&mut self, out: &mut [f32]) {
+        let mut frame = 0;
+        while frame < out.len() {
+            let rest = &mut out[frame..];
+            // Frames left in the stage; even an empty segment takes one
+            let remaining = self
+                .samples_in_stage
+                .saturating_sub(self.stage_counter)
+                .max(1)
+                .min(rest.len());
+
+            match self.stage {
+                EnvelopeStage::Idle => {
+                    rest.fill(self.current_value);
+                    return;
+                }
+                EnvelopeStage::Attack
+                | EnvelopeStage::Decay
+                | EnvelopeStage::Point
+                | EnvelopeStage::Release => {
+                    let run = &mut rest[..remaining];
+                    let steps = Steps::new(self.coef, self.offset);
+                    let phase = self.stage_counter % 4;
+                    (self.anchor, self.current_value) = steps.ramp(run, self.anchor, phase);
+                    self.stage_counter += remaining;
+                    if self.is_segment_done() {
+                        self.end_segment();
+                    }
+                    frame += remaining;
+                }
+                EnvelopeStage::Sustain => match self.shape {
+                    EnvelopeShape::ADSR { sustain, .. } if self.current_value != sustain => {
+                        for value in rest {
+                            *value = self.current_value;
+                            self.current_value +=
+                                (sustain - self.current_value) * self.sustain_slew;
+                        }
+                        return;
+                    }
+                    _ => {
+                        rest.fill(self.current_value);
+                        return;
+                    }
+                },
+                EnvelopeStage::Hold => {
+                    rest[..remaining].fill(self.current_value);
+                    self.stage_counter += remaining;
+                    if self.stage_counter >= self.samples_in_stage {
+                        self.advance_stage();
+                    }
+                    frame += remaining;
                 }
             }
+            self.current_value = self.current_value.clamp(0.0, 1.0);
         }
+    }
 
-        self.current_value = self.current_value.clamp(0.0, 1.0);
+    fn is_segment_done(&self) -> bool {
+        self.stage_counter >= self.samples_in_stage
+            || match self.stage {
+                EnvelopeStage::Attack => self.current_value >= 1.0,
+                EnvelopeStage::Release => self.current_value <= 0.0,
+                _ => false,
+            }
+    }
+
+    /// Lands on the segment's target and moves to the next stage.
+    fn end_segment(&mut self) {
+        match self.stage {
+            EnvelopeStage::Attack => {
+                self.current_value = 1.0;
+                self.advance_stage();
+            }
+            EnvelopeStage::Decay => self.advance_stage(),
+            EnvelopeStage::Point => {
+                self.current_value = self.target_value;
+                self.next_point();
+            }
+            EnvelopeStage::Release => {
+                self.current_value = 0.0;
+                self.stage = EnvelopeStage::Idle;
+            }
+            EnvelopeStage::Idle | EnvelopeStage::Sustain | EnvelopeStage::Hold => {}
+        }
     }
 
     fn advance_stage(&mut self) {
-        match (&self.shape, self.stage) {
+        match (self.shape, self.stage) {
             (
                 EnvelopeShape::ADSR {
-                    decay_ms, sustain, ..
+                    decay_ms,
+                    sustain,
+                    decay_curve,
+                    ..
                 },
                 EnvelopeStage::Attack,
             ) => {
                 self.stage = EnvelopeStage::Decay;
-                self.target_value = *sustain;
-                self.samples_in_stage = ((*decay_ms / 1000.0) * self.sample_rate) as usize;
-                self.rate = if self.samples_in_stage > 0 {
-                    (self.target_value - self.current_value) / self.samples_in_stage as f32
+                self.start_segment(sustain, decay_ms, decay_curve);
+            }
+            (
+                EnvelopeShape::Cycling {
+                    hold_ms,
+                    fall_ms,
+                    fall_curve,
+                    ..
+                },
+                EnvelopeStage::Attack,
+            ) => {
+                if hold_ms > 0.0 {
+                    self.stage = EnvelopeStage::Hold;
+                    self.samples_in_stage = self.segment_samples(hold_ms);
+                    self.stage_counter = 0;
                 } else {
-                    0.0
-                };
-                self.stage_counter = 0;
+                    self.stage = EnvelopeStage::Decay;
+                    self.start_segment(0.0, fall_ms, fall_curve);
+                }
+            }
+            (
+                EnvelopeShape::Cycling {
+                    fall_ms,
+                    fall_curve,
+                    ..
+                },
+                EnvelopeStage::Hold,
+            ) => {
+                self.stage = EnvelopeStage::Decay;
+                self.start_segment(0.0, fall_ms, fall_curve);
+            }
+            (
+                EnvelopeShape::Cycling {
+                    rise_ms,
+                    rise_curve,
+                    ..
+                },
+                EnvelopeStage::Decay,
+            ) => {
+                self.current_value = 0.0;
+                if self.released {
+                    self.stage = EnvelopeStage::Idle;
+                } else {
+                    self.stage = EnvelopeStage::Attack;
+                    self.start_segment(1.0, rise_ms, rise_curve);
+                }
             }
             (EnvelopeShape::ADSR { sustain, .. }, EnvelopeStage::Decay) => {
                 self.stage = EnvelopeStage::Sustain;
-                self.current_value = *sustain;
+                self.current_value = sustain;
             }
             (EnvelopeShape::AR { .. }, EnvelopeStage::Attack) => {
                 self.stage = EnvelopeStage::Sustain;
             }
             (
                 EnvelopeShape::Trapezoid {
-                    hold_ms, fall_ms, ..
+                    hold_ms,
+                    fall_ms,
+                    fall_curve,
+                    ..
                 },
                 EnvelopeStage::Attack,
             ) => {
-                if *hold_ms > 0.0 {
+                if hold_ms > 0.0 {
                     self.stage = EnvelopeStage::Hold;
-                    self.samples_in_stage = ((*hold_ms / 1000.0) * self.sample_rate) as usize;
+                    self.samples_in_stage = self.segment_samples(hold_ms);
                     self.stage_counter = 0;
                 } else {
                     self.stage = EnvelopeStage::Release;
-                    self.target_value = 0.0;
-                    self.samples_in_stage = ((*fall_ms / 1000.0) * self.sample_rate) as usize;
-                    self.rate = if self.samples_in_stage > 0 {
-                        -self.current_value / self.samples_in_stage as f32
-                    } else {
-                        -1.0
-                    };
-                    self.stage_counter = 0;
+                    self.start_segment(0.0, fall_ms, fall_curve);
                 }
             }
-            (EnvelopeShape::Trapezoid { fall_ms, .. }, EnvelopeStage::Hold) => {
+            (
+                EnvelopeShape::Trapezoid {
+                    fall_ms,
+                    fall_curve,
+                    ..
+                },
+                EnvelopeStage::Hold,
+            ) => {
                 self.stage = EnvelopeStage::Release;
-                self.target_value = 0.0;
-                self.samples_in_stage = ((*fall_ms / 1000.0) * self.sample_rate) as usize;
-                self.rate = if self.samples_in_stage > 0 {
-                    -self.current_value / self.samples_in_stage as f32
-                } else {
-                    -1.0
-                };
-                self.stage_counter = 0;
+                self.start_segment(0.0, fall_ms, fall_curve);
             }
             _ => {}
         }
@@ -239,3 +889,334 @@ impl Envelope {
         matches!(self.stage, EnvelopeStage::Idle)
     }
 }
+
+/// Steps of a segment, `value * coef + offset`, taken four at a time
+/// straight from the level at the start of the four, which keeps lanes
+/// independent.
+struct Steps {
+    // Powers of the coefficient, and what the offsets add up to, `i` steps in
+    powers: f32x4,
+    sums: f32x4,
+    coef4: f32,
+    offset4: f32,
+}
+
+impl Steps {
+    fn new(coef: f32, offset: f32) -> Self {
+        let powers = [1.0, coef, coef * coef, coef * coef * coef];
+        let sums = [
+            0.0,
+            offset,
+            offset * (1.0 + coef),
+            offset * (1.0 + coef + coef * coef),
+        ];
+        Self {
+            coef4: powers[3] * coef,
+            offset4: sums[3] * coef + offset,
+            powers: f32x4::from(powers),
+            sums: f32x4::from(sums),
+        }
+    }
+
+    /// Levels 0-3 steps on from `anchor`.
+    fn levels(&self, anchor: f32) -> f32x4 {
+        f32x4::splat(anchor).mul_add(self.powers, self.sums)
+    }
+
+    fn next_anchor(&self, anchor: f32) -> f32 {
+        anchor * self.coef4 + self.offset4
+    }
+
+    /// Writes levels starting `phase` steps on from `anchor`, and returns
+    /// the anchor of the four the next level falls in and that level.
+    fn ramp(&self, mut out: &mut [f32], mut anchor: f32, mut phase: usize) -> (f32, f32) {
+        while !out.is_empty() {
+            let levels = self.levels(anchor).max(f32x4::ZERO).min(f32x4::ONE);
+            let count = (4 - phase).min(out.len());
+            let (run, rest) = out.split_at_mut(count);
+            run.copy_from_slice(&levels.to_array()[phase..phase + count]);
+            out = rest;
+            phase += count;
+            if phase == 4 {
+                anchor = self.next_anchor(anchor);
+                phase = 0;
+            }
+        }
+        (anchor, self.levels(anchor).to_array()[phase])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48000.0;
+
+    fn envelope(shape: EnvelopeShape) -> Envelope {
+        let mut envelope = Envelope::new(RATE);
+        envelope.set_shape(shape);
+        envelope.trigger();
+        envelope
+    }
+
+    /// The level before each of the next `frames` samples, a sample at a time.
+    fn levels(envelope: &mut Envelope, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|_| {
+                let level = envelope.get_current_value();
+                envelope.process_sample();
+                level
+            })
+            .collect()
+    }
+
+    fn drawn(
+        points: &[(f32, f32)],
+        sustain: Option<usize>,
+        loop_points: Option<(usize, usize)>,
+    ) -> EnvelopeShape {
+        let points: Vec<Breakpoint> = points
+            .iter()
+            .map(|&(time_ms, level)| Breakpoint {
+                time_ms,
+                level,
+                curve: Curve::LINEAR,
+            })
+            .collect();
+        EnvelopeShape::Variable {
+            points: Breakpoints::try_from(points.as_slice()).unwrap(),
+            sustain,
+            loop_points,
+        }
+    }
+
+    #[test]
+    fn blocks_match_single_samples() {
+        let mut curved = EnvelopeShape::default();
+        curved.set_attack_curve(Curve::Rc);
+        curved.set_decay_curve(Curve::Shaped(0.6));
+        curved.set_release_curve(Curve::Shaped(-0.4));
+        let shapes = [
+            EnvelopeShape::default(),
+            curved,
+            drawn(
+                &[(3.0, 1.0), (7.0, 0.3), (5.0, 0.8), (20.0, 0.0)],
+                None,
+                Some((0, 2)),
+            ),
+            drawn(&[(3.0, 1.0), (7.0, 0.3), (20.0, 0.0)], Some(1), None),
+        ];
+        // Through attack, decay and sustain or the loop, a note-off partway
+        // through a block, then release to silence
+        let (frames, release_at) = (24000, 9001);
+
+        for shape in shapes {
+            let mut by_sample = envelope(shape);
+            let mut expected = levels(&mut by_sample, release_at);
+            by_sample.release();
+            expected.extend(levels(&mut by_sample, frames - release_at));
+
+            let mut by_block = envelope(shape);
+            let mut actual = vec![0.0; frames];
+            let (held, released) = actual.split_at_mut(release_at);
+            // Odd sizes, so stages change and four-step groups break anywhere
+            for block in held.chunks_mut(37) {
+                by_block.process_block(block);
+            }
+            by_block.release();
+            for block in released.chunks_mut(101) {
+                by_block.process_block(block);
+            }
+
+            assert!(by_block.is_finished() && by_sample.is_finished());
+            for (frame, (a, b)) in actual.iter().zip(&expected).enumerate() {
+                assert!((a - b).abs() < 1e-6, "{shape:?} frame {frame}: {a} vs {b}");
+            }
+        }
+    }
+
+    fn ar(attack_ms: f32, release_ms: f32, curve: Curve) -> EnvelopeShape {
+        EnvelopeShape::AR {
+            attack_ms,
+            release_ms,
+            attack_curve: curve,
+            release_curve: curve,
+        }
+    }
+
+    #[test]
+    fn curves_land_on_time_and_never_turn_back() {
+        // 10 ms rise and 20 ms fall at 48 kHz
+        let (rise, fall) = (480, 960);
+        for curve in [
+            Curve::Rc,
+            Curve::Shaped(-1.0),
+            Curve::Shaped(-0.3),
+            Curve::LINEAR,
+            Curve::Shaped(0.3),
+            Curve::Shaped(1.0),
+        ] {
+            let mut envelope = envelope(ar(10.0, 20.0, curve));
+            let attack = levels(&mut envelope, rise + 1);
+            assert!(attack[rise - 1] < 1.0, "{curve:?} arrived early");
+            assert_eq!(attack[rise], 1.0, "{curve:?}");
+            assert!(attack.windows(2).all(|w| w[0] <= w[1]), "{curve:?} rise");
+
+            envelope.release();
+            let release = levels(&mut envelope, fall + 1);
+            assert!(release[fall - 1] > 0.0, "{curve:?} arrived early");
+            assert_eq!(release[fall], 0.0, "{curve:?}");
+            assert!(release.windows(2).all(|w| w[0] >= w[1]), "{curve:?} fall");
+            assert!(envelope.is_finished());
+        }
+    }
+
+    #[test]
+    fn curves_follow_their_shape() {
+        let at = |curve, fraction: f32| {
+            let attack = levels(&mut envelope(ar(10.0, 20.0, curve)), 481);
+            attack[(480.0 * fraction) as usize]
+        };
+        // RC spans five time constants: 1 - e^-1 of the way a fifth in
+        let rc = (-1.0f32).exp_m1() / (-RC_TIME_CONSTANTS).exp_m1();
+        assert!((at(Curve::Rc, 0.2) - rc).abs() < 1e-3);
+        // Halfway, bowed above and below a straight line
+        assert!((at(Curve::LINEAR, 0.5) - 0.5).abs() < 1e-4);
+        assert!(at(Curve::Shaped(-1.0), 0.5) > 0.9);
+        assert!(at(Curve::Shaped(1.0), 0.5) < 0.1);
+
+        // Falls bow the other way, so positive curvature leaves a long tail
+        let fall_at = |curve, fraction: f32| {
+            let mut envelope = envelope(ar(0.0, 20.0, curve));
+            levels(&mut envelope, 2);
+            envelope.release();
+            levels(&mut envelope, 961)[(960.0 * fraction) as usize]
+        };
+        assert!(fall_at(Curve::Shaped(1.0), 0.5) < 0.1);
+        assert!(fall_at(Curve::Shaped(-1.0), 0.5) > 0.9);
+        assert!((fall_at(Curve::Rc, 0.2) - (1.0 - rc)).abs() < 1e-3);
+    }
+
+    fn cycling(rise_ms: f32, fall_ms: f32, rate: CycleRate) -> EnvelopeShape {
+        EnvelopeShape::Cycling {
+            rise_ms,
+            hold_ms: 0.0,
+            fall_ms,
+            rise_curve: Curve::LINEAR,
+            fall_curve: Curve::LINEAR,
+            rate,
+            free_running: false,
+        }
+    }
+
+    /// Frames where the cycle tops out and where it starts again.
+    fn turns(levels: &[f32]) -> (Vec<usize>, Vec<usize>) {
+        let frames = |level| {
+            (1..levels.len())
+                .filter(|&i| levels[i] == level && levels[i - 1] != level)
+                .collect()
+        };
+        (frames(1.0), frames(0.0))
+    }
+
+    #[test]
+    fn cycle_rates_set_the_period() {
+        // 4 Hz: 12000 frames a cycle, split evenly by equal segment times
+        let mut hz = envelope(cycling(1.0, 1.0, CycleRate::Hz(4.0)));
+        let (peaks, starts) = turns(&levels(&mut hz, 48000));
+        assert_eq!(peaks, [6000, 18000, 30000, 42000]);
+        assert_eq!(starts, [12000, 24000, 36000]);
+
+        // Two beats at 90 bpm: 64000 frames, a quarter of it rising
+        let mut beats = Envelope::new(RATE);
+        beats.set_tempo(90.0);
+        beats.set_shape(cycling(1.0, 3.0, CycleRate::Beats(2.0)));
+        beats.trigger();
+        let (peaks, starts) = turns(&levels(&mut beats, 150_000));
+        assert_eq!(peaks, [16000, 80000, 144_000]);
+        assert_eq!(starts, [64000, 128_000]);
+
+        // Times plays the segments as given
+        let mut times = envelope(cycling(10.0, 30.0, CycleRate::Times));
+        let (peaks, starts) = turns(&levels(&mut times, 4000));
+        assert_eq!(peaks, [480, 2400]);
+        assert_eq!(starts, [1920, 3840]);
+    }
+
+    #[test]
+    fn tempo_changes_keep_the_cycle_in_place() {
+        // One beat at 120 bpm: a 12000 frame rise and fall
+        let mut envelope = envelope(cycling(1.0, 1.0, CycleRate::Beats(1.0)));
+        let mut output = levels(&mut envelope, 6000);
+        envelope.set_tempo(60.0);
+        output.extend(levels(&mut envelope, 60000));
+
+        // The rise carries on from where it was, without a jump
+        let step = output[1] - output[0];
+        assert!((output[6000] - output[5999] - step).abs() < 1e-6);
+        // and the segments after it take the new tempo
+        let (peaks, starts) = turns(&output);
+        assert_eq!(peaks, [12000, 60000]);
+        assert_eq!(starts, [36000]);
+    }
+
+    #[test]
+    fn breakpoints_stop_at_the_cap() {
+        let point = Breakpoint::default();
+        let mut points = Breakpoints::new();
+        for _ in 0..MAX_BREAKPOINTS {
+            points.push(point).unwrap();
+        }
+        assert!(points.push(point).is_err());
+        assert!(points.insert(0, point).is_err());
+        assert_eq!(points.len(), MAX_BREAKPOINTS);
+
+        let too_many = vec![point; MAX_BREAKPOINTS + 1];
+        assert!(Breakpoints::try_from(too_many.as_slice()).is_err());
+        let text = ron::to_string(&too_many).unwrap();
+        assert!(ron::from_str::<Breakpoints>(&text).is_err());
+
+        // Full lists still round trip
+        let text = ron::to_string(&points).unwrap();
+        assert_eq!(ron::from_str::<Breakpoints>(&text).unwrap(), points);
+    }
+
+    #[test]
+    fn drawn_envelopes_wait_at_the_sustain_point() {
+        // 10 ms to each point
+        let shape = drawn(&[(10.0, 1.0), (10.0, 0.5), (10.0, 0.0)], Some(1), None);
+        let mut envelope = envelope(shape);
+        let held = levels(&mut envelope, 48000);
+        assert_eq!(held[480], 1.0);
+        assert_eq!(held[960], 0.5);
+        assert!(held[960..].iter().all(|x| *x == 0.5));
+
+        envelope.release();
+        let released = levels(&mut envelope, 481);
+        assert!(released[479] > 0.0);
+        assert_eq!(released[480], 0.0);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn drawn_envelopes_go_round_the_loop_until_released() {
+        // Up, down to 0.2 and back up, looping back to the second point
+        let shape = drawn(
+            &[(10.0, 1.0), (10.0, 0.2), (10.0, 1.0), (10.0, 0.0)],
+            None,
+            Some((0, 2)),
+        );
+        let mut envelope = envelope(shape);
+        let held = levels(&mut envelope, 4000);
+        for lap in 0..3 {
+            assert_eq!(held[480 + lap * 960], 1.0, "lap {lap}");
+            assert!((held[960 + lap * 960] - 0.2).abs() < 1e-6, "lap {lap}");
+        }
+
+        // Released, it leaves the loop for the last point
+        envelope.release();
+        let released = levels(&mut envelope, 481);
+        assert_eq!(released[480], 0.0);
+        assert!(envelope.is_finished());
+    }
+}
@@ -2,19 +2,34 @@
 #![allow(clippy::must_use_candidate)]
 #![allow(clippy::missing_const_for_fn)]
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use zimler_dsp::Interpolator;
 
 pub mod api;
+pub mod bundle;
 pub mod envelope;
+pub mod midi;
 pub mod mixer;
+pub mod modulation;
+pub mod preset;
+pub mod render;
 pub mod sample;
+pub mod sfz;
 pub mod voice;
 
 pub use api::*;
+pub use bundle::*;
 pub use envelope::*;
+pub use midi::*;
 pub use mixer::*;
+pub use modulation::*;
+pub use preset::*;
+pub use render::*;
 pub use sample::*;
+pub use sfz::*;
 pub use voice::*;
 
 #[derive(Debug, Clone)]
@@ -37,14 +52,92 @@ impl Default for EngineConfig {
 }
 
 pub struct ZimlerEngine {
-    #[allow(dead_code)]
     config: EngineConfig,
     voices: Vec<Voice>,
     mixer: Mixer,
-    sample_bank: Arc<RwLock<SampleBank>>,
+    // The audio thread's own copy of the bank; it never takes a lock
+    sample_bank: SampleBank,
+    shared_bank: Arc<RwLock<SampleBank>>,
+    updates: crossbeam::channel::Receiver<EngineUpdate>,
+    update_sender: crossbeam::channel::Sender<EngineUpdate>,
+    retired: Arc<Mutex<Retired>>,
+    settings: Arc<Mutex<EngineSettings>>,
     engine_state: Arc<RwLock<EngineState>>,
-    commands: crossbeam::channel::Receiver<EngineCommand>,
-    command_sender: crossbeam::channel::Sender<EngineCommand>,
+    commands: crossbeam::channel::Receiver<TimedCommand>,
+    command_sender: crossbeam::channel::Sender<TimedCommand>,
+    // Future commands turned away for want of queue space, for handles to collect
+    refused: crossbeam::channel::Receiver<TimedCommand>,
+    refused_sender: crossbeam::channel::Sender<TimedCommand>,
+    // Commands waiting for their frame, kept sorted by frame; never grown
+    // past its starting capacity
+    scheduled: VecDeque<TimedCommand>,
+    // Voices render into this before mixing, so the audio thread never
+    // allocates
+    voice_buffer: Vec<f32>,
+    frame_clock: Arc<AtomicU64>,
+    envelope_shape: EnvelopeShape,
+    sample_start_offset: f32,
+    sample_end_offset: f32,
+    voice_stealing: VoiceStealing,
+    trigger_count: u64,
+    // Scratch space for the zones layered on a note
+    layers: Vec<Layer>,
+    // Next voice in line when allocating round-robin
+    rotate_index: usize,
+    // Keys down, as opposed to notes only sounding on a pedal
+    keys_down: [bool; 128],
+    // When each key was last pressed, counting presses, and how hard
+    key_order: [u64; 128],
+    key_presses: u64,
+    key_velocity: [f32; 128],
+    voice_mode: VoiceMode,
+    retrigger: RetriggerMode,
+    // Note the mono voices are playing
+    mono_note: Option<u8>,
+    sustain: bool,
+    sostenuto: bool,
+    // Notes the sostenuto pedal caught when it went down
+    sostenuto_notes: [bool; 128],
+    modulation: Option<Modulation>,
+    // Runs free-running modulation between notes; voices sync to it
+    mod_clock: Envelope,
+}
+
+const SCHEDULE_CAPACITY: usize = 1024;
+
+// Queue slots only commands due in the current block may fill, so a backlog
+// of far-off commands can't hold up notes being played now
+const DUE_RESERVE: usize = 256;
+
+// Most zones one note can layer
+const MAX_LAYERS: usize = 16;
+
+/// State prepared off the audio thread and handed over without locking.
+pub(crate) enum EngineUpdate {
+    Sample { slot: usize, sample: Option<Sample> },
+    Interpolation(Interpolator),
+    Mapping(Arc<SampleMapping>),
+}
+
+/// Shared data the audio thread has let go of (or is about to), parked so
+/// it is freed on the control side once nothing else refers to it.
+#[derive(Default)]
+pub(crate) struct Retired {
+    pub(crate) samples: Vec<Sample>,
+    pub(crate) interpolators: Vec<Interpolator>,
+    pub(crate) mappings: Vec<Arc<SampleMapping>>,
+    // The interpolator the voices were last given
+    pub(crate) interpolator: Interpolator,
+}
+
+impl Retired {
+    pub(crate) fn prune(&mut self) {
+        self.samples
+            .retain(|sample| Arc::strong_count(&sample.data) > 1);
+        self.interpolators.retain(Interpolator::is_shared);
+        self.mappings
+            .retain(|mapping| Arc::strong_count(mapping) > 1);
+    }
 }
 
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
@@ -52,80 +145,936 @@ pub struct EngineState {
     pub active_voices: usize,
     pub cpu_load: f32,
     pub current_preset: Option<String>,
+    /// One entry per voice, refreshed every block
+    pub voices: Vec<VoiceInfo>,
 }
 
 impl ZimlerEngine {
     pub fn new(config: EngineConfig) -> Self {
         let voices = (0..config.num_voices)
-            .map(|_| Voice::new(config.sample_rate))
+            .map(|_| Voice::with_format(config.sample_rate, config.num_channels))
             .collect();
 
+        let sample_rate = config.sample_rate;
+        let voice_buffer = vec![0.0; config.block_size.max(1) * config.num_channels.max(1)];
+        let mixer = Mixer::with_format(sample_rate, config.num_channels);
         let (tx, rx) = crossbeam::channel::unbounded();
+        let (update_tx, update_rx) = crossbeam::channel::unbounded();
+        let (refused_tx, refused_rx) = crossbeam::channel::bounded(SCHEDULE_CAPACITY);
+        let state = EngineState {
+            voices: vec![VoiceInfo::default(); config.num_voices],
+            ..EngineState::default()
+        };
 
         Self {
             config,
             voices,
-            mixer: Mixer::new(),
-            sample_bank: Arc::new(RwLock::new(SampleBank::new())),
-            engine_state: Arc::new(RwLock::new(EngineState::default())),
+            mixer,
+            sample_bank: SampleBank::with_sample_rate(sample_rate),
+            shared_bank: Arc::new(RwLock::new(SampleBank::with_sample_rate(sample_rate))),
+            updates: update_rx,
+            update_sender: update_tx,
+            retired: Arc::new(Mutex::new(Retired::default())),
+            settings: Arc::new(Mutex::new(EngineSettings::default())),
+            engine_state: Arc::new(RwLock::new(state)),
             commands: rx,
             command_sender: tx,
+            refused: refused_rx,
+            refused_sender: refused_tx,
+            scheduled: VecDeque::with_capacity(SCHEDULE_CAPACITY),
+            voice_buffer,
+            frame_clock: Arc::new(AtomicU64::new(0)),
+            envelope_shape: EnvelopeShape::default(),
+            sample_start_offset: 0.0,
+            sample_end_offset: 0.0,
+            voice_stealing: VoiceStealing::default(),
+            trigger_count: 0,
+            layers: Vec::with_capacity(MAX_LAYERS),
+            rotate_index: 0,
+            keys_down: [false; 128],
+            key_order: [0; 128],
+            key_presses: 0,
+            key_velocity: [0.0; 128],
+            voice_mode: VoiceMode::default(),
+            retrigger: RetriggerMode::default(),
+            mono_note: None,
+            sustain: false,
+            sostenuto: false,
+            sostenuto_notes: [false; 128],
+            modulation: None,
+            mod_clock: Envelope::new(sample_rate),
         }
     }
 
     pub fn process_block(&mut self, output: &mut [f32]) {
-        // Process any pending commands
+        // Pick up samples and kernels prepared on other threads. Whatever they
+        // replace is kept alive by the handle, so dropping it here never frees memory.
+        while let Ok(update) = self.updates.try_recv() {
+            match update {
+                EngineUpdate::Sample { slot, sample } => {
+                    let _ = self.sample_bank.insert_sample(slot, sample);
+                }
+                EngineUpdate::Mapping(mapping) => {
+                    self.sample_bank.set_mapping(mapping);
+                }
+                EngineUpdate::Interpolation(interpolator) => {
+                    for voice in &mut self.voices {
+                        voice.set_interpolator(interpolator.clone());
+                    }
+                }
+            }
+        }
+
+        let channels = self.config.num_channels.max(1);
+        let block_start = self.frame_clock.load(Ordering::Relaxed);
+        let block_end = block_start + (output.len() / channels) as u64;
+        let mut cursor = block_start;
+
+        // Queue pending commands in frame order; equal frames keep arrival
+        // order. Later blocks' commands leave room for this one's, and past
+        // that are handed back to the sender.
         while let Ok(command) = self.commands.try_recv() {
-            self.handle_command(command);
+            let due = command.frame < block_end;
+            if self.scheduled.len() >= SCHEDULE_CAPACITY - DUE_RESERVE && !due {
+                let _ = self.refused_sender.try_send(command);
+            } else if self.scheduled.len() >= SCHEDULE_CAPACITY {
+                // Too many for even the reserve: play it now, a little early
+                self.handle_command(command.command);
+            } else {
+                let index = self
+                    .scheduled
+                    .partition_point(|queued| queued.frame <= command.frame);
+                self.scheduled.insert(index, command);
+            }
         }
 
-        output.fill(0.0);
+        loop {
+            while let Some(command) = self.scheduled.front() {
+                if command.frame > cursor {
+                    break;
+                }
+                if let Some(command) = self.scheduled.pop_front() {
+                    self.handle_command(command.command);
+                }
+            }
+
+            // Split the block at the next scheduled command so it lands on its exact frame
+            let split = self
+                .scheduled
+                .front()
+                .map_or(block_end, |command| command.frame.min(block_end));
+
+            let from = (cursor - block_start) as usize * channels;
+            let to = (split - block_start) as usize * channels;
+            self.render(&mut output[from..to]);
+            cursor = split;
+
+            if cursor >= block_end {
+                break;
+            }
+        }
+
+        self.frame_clock.store(block_end, Ordering::Relaxed);
+    }
 
-        let mut temp_buffer = vec![0.0; output.len()];
+    /// Renders voices into `output` without touching the command channel,
+    /// a voice buffer's worth at a time.
+    fn render(&mut self, output: &mut [f32]) {
+        let mut voice_buffer = std::mem::take(&mut self.voice_buffer);
+        for chunk in output.chunks_mut(voice_buffer.len()) {
+            self.render_voices(chunk, &mut voice_buffer[..chunk.len()]);
+        }
+        self.voice_buffer = voice_buffer;
+    }
+
+    fn render_voices(&mut self, output: &mut [f32], voice_buffer: &mut [f32]) {
+        output.fill(0.0);
+        voice_buffer.fill(0.0);
         let mut active_count = 0;
 
         for voice in &mut self.voices {
             if voice.is_active() {
-                voice.process_block(&mut temp_buffer);
+                voice.process_block(voice_buffer);
                 active_count += 1;
             }
         }
 
-        self.mixer.mix(&temp_buffer, output);
+        self.mixer.mix(voice_buffer, output);
 
-        let mut state = self.engine_state.write();
-        state.active_voices = active_count;
+        if self.is_mod_free_running() {
+            for _ in 0..output.len() / self.config.num_channels.max(1) {
+                self.mod_clock.process_sample();
+            }
+        }
+
+        // Skip the update rather than block if a reader holds the lock
+        if let Some(mut state) = self.engine_state.try_write() {
+            state.active_voices = active_count;
+            for (info, voice) in state.voices.iter_mut().zip(&self.voices) {
+                *info = voice.info();
+            }
+        }
     }
 
     fn handle_command(&mut self, command: EngineCommand) {
         match command {
             EngineCommand::TriggerNote { note, velocity } => {
-                // Find an idle voice
-                if let Some(voice) = self.voices.iter_mut().find(|v| !v.is_active()) {
-                    // Get the sample for this note
-                    let mut bank = self.sample_bank.write();
-                    if let Some(sample) = bank.get_sample_for_note(note, velocity) {
-                        voice.trigger(note, velocity, sample.clone());
+                let key = usize::from(note.min(127));
+                self.keys_down[key] = true;
+                self.key_presses += 1;
+                self.key_order[key] = self.key_presses;
+                self.key_velocity[key] = velocity;
+
+                match self.voice_mode {
+                    VoiceMode::Poly => self.trigger_poly(note, velocity),
+                    // Keys the priority passes over wait their turn
+                    VoiceMode::Mono { priority, .. } => {
+                        if self.mono_choice(priority) == Some(note) {
+                            let held_over = self.is_held_over(self.mono_note, note);
+                            self.trigger_mono(note, velocity, held_over);
+                        }
                     }
                 }
             }
             EngineCommand::ReleaseNote { note } => {
-                // Release all voices playing this note
-                for voice in &mut self.voices {
-                    if voice.get_note() == Some(note) {
-                        voice.release();
+                if let Some(key) = self.keys_down.get_mut(usize::from(note)) {
+                    *key = false;
+                }
+                match self.voice_mode {
+                    VoiceMode::Mono { priority, .. } if self.mono_note == Some(note) => {
+                        match self.mono_choice(priority) {
+                            // Back to a key still held
+                            Some(next) => {
+                                let velocity = self.key_velocity[usize::from(next)];
+                                self.trigger_mono(next, velocity, true);
+                            }
+                            None if !self.is_pedal_held(note) => self.release_voices(note),
+                            None => {}
+                        }
+                    }
+                    _ => {
+                        if !self.is_pedal_held(note) {
+                            self.release_voices(note);
+                        }
                     }
                 }
             }
-            _ => {} // Other commands handled elsewhere
+            EngineCommand::PitchBend { amount } => {
+                for voice in &mut self.voices {
+                    voice.set_pitch_bend(amount);
+                }
+            }
+            EngineCommand::ModWheel { amount } => {
+                for voice in &mut self.voices {
+                    voice.set_mod_wheel(amount);
+                }
+            }
+            EngineCommand::SetSustain { on } => {
+                self.sustain = on;
+                if !on {
+                    self.release_unheld();
+                }
+            }
+            // Pedals resend their state, so only a change catches or lets go
+            EngineCommand::SetSostenuto { on } if on != self.sostenuto => {
+                self.sostenuto = on;
+                self.sostenuto_notes = if on { self.keys_down } else { [false; 128] };
+                if !on {
+                    self.release_unheld();
+                }
+            }
+            EngineCommand::SetSostenuto { .. } => {}
+            EngineCommand::SetModulation { modulation } => self.set_modulation(modulation),
+            EngineCommand::AllNotesOff => {
+                self.keys_down = [false; 128];
+                self.mono_note = None;
+                self.release_unheld();
+            }
+            EngineCommand::SetEnvelope { envelope } => self.set_envelope(envelope),
+            EngineCommand::SetMixMode { mode } => self.mixer.set_mode(mode),
+            EngineCommand::SetParameter { param, value } => self.set_parameter(param, value),
+            EngineCommand::SetVoiceStealing { mode } => self.voice_stealing = mode,
+            EngineCommand::SetVoiceMode { mode } => self.set_voice_mode(mode),
+            EngineCommand::SetRetrigger { mode } => self.retrigger = mode,
+            EngineCommand::SetFilterMode { mode } => {
+                for voice in &mut self.voices {
+                    voice.set_filter_mode(mode);
+                }
+            }
+            // Prepared by EngineHandle and delivered as updates
+            EngineCommand::LoadSample { .. }
+            | EngineCommand::SetInterpolation { .. }
+            | EngineCommand::SetLoop { .. }
+            | EngineCommand::SetMapping { .. }
+            | EngineCommand::AddZone { .. }
+            | EngineCommand::SetZone { .. }
+            | EngineCommand::RemoveZone { .. }
+            | EngineCommand::LoadPreset { .. }
+            | EngineCommand::LoadBundle { .. } => {}
+        }
+    }
+
+    /// Picks the voice for a new note: the next in rotation for
+    /// `MixMode::Rotate`, otherwise an idle voice, otherwise one chosen by
+    /// the voice stealing policy. Voices just started for other layers of
+    /// the same note are never stolen.
+    fn allocate_voice(&mut self, note: u8) -> Option<usize> {
+        if self.voices.is_empty() {
+            return None;
+        }
+
+        if matches!(self.mixer.mode(), MixMode::Rotate) {
+            let index = self.rotate_index % self.voices.len();
+            self.rotate_index = (index + 1) % self.voices.len();
+            return Some(index);
+        }
+
+        if let Some(index) = self.voices.iter().position(|v| !v.is_active()) {
+            return Some(index);
+        }
+
+        let current = self.trigger_count;
+        let voices = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(move |(_, v)| v.trigger_order() != current);
+        let oldest = || {
+            voices
+                .clone()
+                .min_by_key(|(_, v)| v.trigger_order())
+                .map(|(i, _)| i)
+        };
+
+        match self.voice_stealing {
+            VoiceStealing::Oldest => oldest(),
+            VoiceStealing::Quietest => voices
+                .min_by(|(_, a), (_, b)| a.level().total_cmp(&b.level()))
+                .map(|(i, _)| i),
+            VoiceStealing::SameNote => voices
+                .clone()
+                .find(|(_, v)| v.get_note() == Some(note))
+                .map(|(i, _)| i)
+                .or_else(oldest),
+            // Ties go to the oldest voice
+            VoiceStealing::LowestNotePriority => voices
+                .max_by_key(|(_, v)| (v.get_note(), std::cmp::Reverse(v.trigger_order())))
+                .map(|(i, _)| i),
+            VoiceStealing::HighestNotePriority => voices
+                .min_by_key(|(_, v)| (v.get_note(), v.trigger_order()))
+                .map(|(i, _)| i),
+        }
+    }
+
+    /// Gives `note` a voice per layered zone, stealing busy ones if need be.
+    fn trigger_poly(&mut self, note: u8, velocity: f32) {
+        // One voice per layered zone, all sharing this trigger
+        let mut layers = std::mem::take(&mut self.layers);
+        self.sample_bank
+            .layers_for_note(note, velocity, &mut layers);
+        if !layers.is_empty() {
+            self.trigger_count += 1;
+        }
+
+        for layer in layers.drain(..) {
+            let Some(index) = self.allocate_voice(note) else {
+                break;
+            };
+            let held_over = self.is_held_over(self.voices[index].get_note(), note);
+            let mode = self.retrigger_mode(held_over);
+            let setup = self.prepare_voice(index, &layer);
+            self.voices[index].retrigger(note, velocity * layer.gain, layer.sample, setup, mode);
+        }
+        self.layers = layers;
+    }
+
+    /// Moves the mono voices, one per layered zone, on to `note`.
+    /// `held_over` if the note they were playing is still held.
+    fn trigger_mono(&mut self, note: u8, velocity: f32, held_over: bool) {
+        let mut layers = std::mem::take(&mut self.layers);
+        self.sample_bank
+            .layers_for_note(note, velocity, &mut layers);
+        if !layers.is_empty() {
+            self.trigger_count += 1;
+        }
+
+        let mode = self.retrigger_mode(held_over);
+        let count = layers.len().min(self.voices.len());
+        for (index, layer) in layers.drain(..).take(count).enumerate() {
+            let setup = self.prepare_voice(index, &layer);
+            self.voices[index].retrigger(note, velocity * layer.gain, layer.sample, setup, mode);
+        }
+        // Layers the last note had and this one doesn't
+        for voice in &mut self.voices[count..] {
+            voice.release();
+        }
+        self.layers = layers;
+        self.mono_note = Some(note);
+    }
+
+    /// Sets a voice up for a layer of the note about to start on it, and
+    /// returns the settings that wait for the note itself to start.
+    fn prepare_voice(&mut self, index: usize, layer: &Layer) -> NoteSetup {
+        let free_running = self.is_mod_free_running();
+        let voice = &mut self.voices[index];
+        voice.set_trigger_order(self.trigger_count);
+        if free_running {
+            voice.sync_modulator(&self.mod_clock);
+        }
+        NoteSetup {
+            slot: Some(layer.slot),
+            pan: layer.pan,
+            envelope: layer.envelope.unwrap_or(self.envelope_shape),
+        }
+    }
+
+    /// Whether a voice playing `playing` is being moved to `note` while
+    /// the key for `playing` is still down.
+    fn is_held_over(&self, playing: Option<u8>, note: u8) -> bool {
+        playing.is_some_and(|playing| {
+            playing != note && self.keys_down.get(usize::from(playing)) == Some(&true)
+        })
+    }
+
+    /// Legato only carries a note on while the one before is held over.
+    fn retrigger_mode(&self, held_over: bool) -> RetriggerMode {
+        match self.retrigger {
+            RetriggerMode::Legato if !held_over => RetriggerMode::FromCurrent,
+            mode => mode,
+        }
+    }
+
+    /// The held key a mono voice should be playing.
+    fn mono_choice(&self, priority: NotePriority) -> Option<u8> {
+        let mut held = (0..128u8).filter(|&note| self.keys_down[usize::from(note)]);
+        match priority {
+            NotePriority::Last => held.max_by_key(|&note| self.key_order[usize::from(note)]),
+            NotePriority::Lowest => held.next(),
+            NotePriority::Highest => held.next_back(),
+        }
+    }
+
+    fn set_voice_mode(&mut self, mode: VoiceMode) {
+        // Notes from the other mode would never be let go of properly
+        if matches!(mode, VoiceMode::Poly) != matches!(self.voice_mode, VoiceMode::Poly) {
+            for voice in &mut self.voices {
+                voice.release();
+            }
+            self.mono_note = None;
+        }
+        self.voice_mode = mode;
+
+        let glide_ms = match mode {
+            VoiceMode::Poly => 0.0,
+            VoiceMode::Mono { glide_ms, .. } => glide_ms,
+        };
+        for voice in &mut self.voices {
+            voice.set_glide_ms(glide_ms);
+        }
+    }
+
+    fn is_mod_free_running(&self) -> bool {
+        self.modulation
+            .is_some_and(|modulation| modulation.shape.is_free_running())
+    }
+
+    fn set_modulation(&mut self, modulation: Option<Modulation>) {
+        self.modulation = modulation;
+        if let Some(modulation) = modulation {
+            self.mod_clock.set_shape(modulation.shape);
+            self.mod_clock.trigger();
+        }
+        let free_running = self.is_mod_free_running();
+        for voice in &mut self.voices {
+            voice.set_modulation(modulation);
+            if free_running {
+                voice.sync_modulator(&self.mod_clock);
+            }
+        }
+    }
+
+    /// Whether a pedal keeps `note` sounding after its key is up.
+    fn is_pedal_held(&self, note: u8) -> bool {
+        self.sustain
+            || (self.sostenuto && self.sostenuto_notes.get(usize::from(note)) == Some(&true))
+    }
+
+    fn release_voices(&mut self, note: u8) {
+        for voice in &mut self.voices {
+            if voice.get_note() == Some(note) {
+                voice.release();
+            }
+        }
+    }
+
+    /// Releases notes whose key is up and that no pedal holds any more.
+    fn release_unheld(&mut self) {
+        for note in 0..128u8 {
+            if !self.keys_down[usize::from(note)] && !self.is_pedal_held(note) {
+                self.release_voices(note);
+            }
+        }
+    }
+
+    fn set_envelope(&mut self, shape: EnvelopeShape) {
+        self.envelope_shape = shape;
+        for voice in &mut self.voices {
+            voice.set_envelope(shape);
+        }
+    }
+
+    /// Applies a parameter change. Times are in ms, levels, offsets and
+    /// resonance 0-1, curvature -1 to 1, pitch bend range and vibrato depth
+    /// in semitones, filter cutoff in Hz, tempo in beats per minute.
+    fn set_parameter(&mut self, param: Parameter, value: f32) {
+        let mut shape = self.envelope_shape;
+        match param {
+            Parameter::MasterVolume => self.mixer.set_master_volume(value),
+            Parameter::VoiceBlur => self.mixer.set_blur_amount(value),
+            Parameter::EnvelopeAttack => shape.set_attack_ms(value.max(0.0)),
+            Parameter::EnvelopeDecay => shape.set_decay_ms(value.max(0.0)),
+            Parameter::EnvelopeSustain => shape.set_sustain(value.clamp(0.0, 1.0)),
+            Parameter::EnvelopeRelease => shape.set_release_ms(value.max(0.0)),
+            Parameter::EnvelopeAttackCurve => shape.set_attack_curve(Curve::Shaped(value)),
+            Parameter::EnvelopeDecayCurve => shape.set_decay_curve(Curve::Shaped(value)),
+            Parameter::EnvelopeReleaseCurve => shape.set_release_curve(Curve::Shaped(value)),
+            Parameter::SampleStartOffset => {
+                self.sample_start_offset = value.clamp(0.0, 1.0);
+                self.update_sample_offsets();
+            }
+            Parameter::SampleEndOffset => {
+                self.sample_end_offset = value.clamp(0.0, 1.0);
+                self.update_sample_offsets();
+            }
+            Parameter::PitchBendRange => {
+                for voice in &mut self.voices {
+                    voice.set_pitch_bend_range(value);
+                }
+            }
+            Parameter::FilterCutoff => {
+                for voice in &mut self.voices {
+                    voice.set_filter_cutoff(value);
+                }
+            }
+            Parameter::FilterResonance => {
+                for voice in &mut self.voices {
+                    voice.set_filter_resonance(value);
+                }
+            }
+            Parameter::StereoWidth => {
+                for voice in &mut self.voices {
+                    voice.set_width(value);
+                }
+            }
+            Parameter::VibratoDepth => {
+                for voice in &mut self.voices {
+                    voice.set_vibrato_depth(value);
+                }
+            }
+            Parameter::Tempo => {
+                self.mod_clock.set_tempo(value);
+                for voice in &mut self.voices {
+                    voice.set_tempo(value);
+                }
+            }
+        }
+
+        if shape != self.envelope_shape {
+            self.set_envelope(shape);
+        }
+    }
+
+    fn update_sample_offsets(&mut self) {
+        for voice in &mut self.voices {
+            voice.set_sample_offsets(self.sample_start_offset, self.sample_end_offset);
         }
     }
 
     pub fn get_api_handle(&self) -> EngineHandle {
         EngineHandle {
-            sample_bank: Arc::clone(&self.sample_bank),
+            sample_bank: Arc::clone(&self.shared_bank),
             engine_state: Arc::clone(&self.engine_state),
+            update_sender: self.update_sender.clone(),
+            retired: Arc::clone(&self.retired),
+            settings: Arc::clone(&self.settings),
             command_sender: self.command_sender.clone(),
+            refused: self.refused.clone(),
+            frame_clock: Arc::clone(&self.frame_clock),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 256;
+
+    /// An engine playing a sustained DC sample on every key.
+    fn dc_engine() -> (ZimlerEngine, EngineHandle) {
+        let engine = ZimlerEngine::new(EngineConfig {
+            block_size: BLOCK,
+            ..EngineConfig::default()
+        });
+        let handle = engine.get_api_handle();
+        let mut sample = Sample::new(vec![1.0; 48000], 48000.0, 1);
+        sample.root_note = Some(60);
+        handle.install_sample(0, Some(sample)).unwrap();
+        handle.install_mapping(SampleMapping::single(0)).unwrap();
+        (engine, handle)
+    }
+
+    #[test]
+    fn scheduled_note_starts_on_its_frame() {
+        let (mut engine, handle) = dc_engine();
+        let channels = EngineConfig::default().num_channels;
+        let mut output = vec![0.0; BLOCK * channels];
+        engine.process_block(&mut output);
+
+        // Frame 100 of the second block
+        let start = handle.current_frame() + 100;
+        handle
+            .send_command_at(
+                EngineCommand::TriggerNote {
+                    note: 60,
+                    velocity: 1.0,
+                },
+                start,
+            )
+            .unwrap();
+        engine.process_block(&mut output);
+
+        let (before, after) = output.split_at(100 * channels);
+        assert!(before.iter().all(|&s| s == 0.0));
+        // The envelope's first level is its starting one, silence
+        assert!(after[channels..].iter().all(|&s| s > 0.0));
+    }
+
+    #[test]
+    fn blocks_longer_than_the_voice_buffer_render_whole() {
+        let (mut engine, handle) = dc_engine();
+        handle
+            .send_command(EngineCommand::TriggerNote {
+                note: 60,
+                velocity: 1.0,
+            })
+            .unwrap();
+        let mut output = vec![0.0; BLOCK * 2 * 3];
+        engine.process_block(&mut output);
+        assert!(output[2..].iter().all(|&s| s > 0.0));
+        assert_eq!(handle.current_frame(), (BLOCK * 3) as u64);
+    }
+
+    #[test]
+    fn commands_past_the_queue_capacity_are_handed_back() {
+        let (mut engine, handle) = dc_engine();
+        let far = 1 << 40;
+        for _ in 0..SCHEDULE_CAPACITY + 10 {
+            handle
+                .send_command_at(EngineCommand::AllNotesOff, far)
+                .unwrap();
+        }
+        let mut output = vec![0.0; BLOCK * 2];
+        engine.process_block(&mut output);
+        assert_eq!(engine.scheduled.len(), SCHEDULE_CAPACITY - DUE_RESERVE);
+        assert_eq!(engine.scheduled.capacity(), SCHEDULE_CAPACITY);
+        assert!(engine.commands.is_empty());
+
+        let refused = handle.refused_commands();
+        assert_eq!(refused.len(), DUE_RESERVE + 10);
+        assert!(refused.iter().all(|command| command.frame == far));
+    }
+
+    #[test]
+    fn a_full_schedule_does_not_hold_up_note_offs() {
+        let (mut engine, handle) = dc_engine();
+        note_on(&handle, 60);
+        let mut output = vec![0.0; BLOCK * 2];
+        engine.process_block(&mut output);
+
+        for _ in 0..SCHEDULE_CAPACITY {
+            handle
+                .send_command_at(EngineCommand::AllNotesOff, 1 << 40)
+                .unwrap();
+        }
+        send(&handle, EngineCommand::ReleaseNote { note: 60 });
+        assert_eq!(sounding(&mut engine), []);
+    }
+
+    fn send(handle: &EngineHandle, command: EngineCommand) {
+        handle.send_command(command).unwrap();
+    }
+
+    fn note_on(handle: &EngineHandle, note: u8) {
+        send(
+            handle,
+            EngineCommand::TriggerNote {
+                note,
+                velocity: 1.0,
+            },
+        );
+    }
+
+    #[test]
+    fn mono_legato_carries_the_note_on_while_held_over() {
+        let (mut engine, handle) = dc_engine();
+        send(
+            &handle,
+            EngineCommand::SetVoiceMode {
+                mode: VoiceMode::Mono {
+                    priority: NotePriority::Last,
+                    glide_ms: 0.0,
+                },
+            },
+        );
+        send(
+            &handle,
+            EngineCommand::SetRetrigger {
+                mode: RetriggerMode::Legato,
+            },
+        );
+        let mut output = vec![0.0; BLOCK * 2];
+
+        note_on(&handle, 60);
+        engine.process_block(&mut output);
+        let first = engine.voices[0].info();
+        assert_eq!(first.note, Some(60));
+
+        // A new key over the held one moves the same voice along, at pitch
+        note_on(&handle, 64);
+        engine.process_block(&mut output);
+        assert!(output.iter().all(|&s| s > 0.0));
+        let moved = engine.voices[0].info();
+        assert_eq!(moved.note, Some(64));
+        let step = 2f64.powf(4.0 / 12.0);
+        assert!((moved.position - first.position - BLOCK as f64 * step).abs() < 1e-6);
+
+        // Letting go returns to the key still held, still without a restart
+        send(&handle, EngineCommand::ReleaseNote { note: 64 });
+        engine.process_block(&mut output);
+        assert!(output.iter().all(|&s| s > 0.0));
+        let back = engine.voices[0].info();
+        assert_eq!(back.note, Some(60));
+        assert!((back.position - moved.position - BLOCK as f64).abs() < 1e-6);
+        assert!(engine.voices[1..].iter().all(|voice| !voice.is_active()));
+
+        // With nothing held over, the next note restarts the sample
+        send(&handle, EngineCommand::ReleaseNote { note: 60 });
+        engine.process_block(&mut output);
+        note_on(&handle, 67);
+        engine.process_block(&mut output);
+        let restarted = engine.voices[0].info();
+        assert_eq!(restarted.note, Some(67));
+        assert!(restarted.position < back.position);
+    }
+
+    #[test]
+    fn stolen_voice_keeps_its_settings_until_the_fade_ends() {
+        let mut engine = ZimlerEngine::new(EngineConfig {
+            block_size: BLOCK,
+            num_voices: 1,
+            ..EngineConfig::default()
+        });
+        let handle = engine.get_api_handle();
+        let mut sample = Sample::new(vec![1.0; 48000], 48000.0, 1);
+        sample.root_note = Some(60);
+        // Hard left, hard right and centre, each from its own slot
+        let zones = [(0, 61, -1.0), (62, 63, 1.0), (64, 127, 0.0)]
+            .into_iter()
+            .enumerate()
+            .map(|(slot, (key_low, key_high, pan))| {
+                handle.install_sample(slot, Some(sample.clone())).unwrap();
+                Zone {
+                    key_low,
+                    key_high,
+                    pan,
+                    envelope: Some(EnvelopeShape::AR {
+                        attack_ms: 0.0,
+                        release_ms: 100.0 * (slot + 1) as f32,
+                        attack_curve: Curve::LINEAR,
+                        release_curve: Curve::LINEAR,
+                    }),
+                    ..Zone::new(slot)
+                }
+            })
+            .collect();
+        handle.install_mapping(SampleMapping::new(zones)).unwrap();
+
+        let mut output = vec![0.0; BLOCK * 2];
+        note_on(&handle, 60);
+        engine.process_block(&mut output);
+
+        // A steal, then another note while its fade is still running
+        let mut short = vec![0.0; 32 * 2];
+        for note in [62, 64] {
+            note_on(&handle, note);
+            engine.process_block(&mut short);
+            let (left, right): (Vec<f32>, Vec<f32>) =
+                short.chunks(2).map(|frame| (frame[0], frame[1])).unzip();
+            assert!(left.iter().all(|&s| s > 0.0));
+            assert!(right.iter().all(|&s| s == 0.0), "panned on the fade");
+            assert_eq!(engine.voices[0].info().slot, Some(0));
+        }
+
+        // Once the fade is done the last note plays, with its own settings
+        engine.process_block(&mut output);
+        let info = engine.voices[0].info();
+        assert_eq!((info.note, info.slot), (Some(64), Some(2)));
+        let last = &output[output.len() - 2..];
+        assert!(last[0] > 0.0 && (last[0] - last[1]).abs() < 1e-6);
+    }
+
+    /// Writes a second of a constant mono level to a WAV file in `dir`.
+    fn write_dc_wav(dir: &std::path::Path, name: &str, level: f32) -> std::path::PathBuf {
+        let path = dir.join(name);
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..48000 {
+            writer.write_sample(level).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    /// Holds middle C until it sustains, and returns the level it settles at.
+    fn play_middle_c(engine: &mut ZimlerEngine, handle: &EngineHandle) -> f32 {
+        note_on(handle, 60);
+        let mut output = vec![0.0; 9600 * 2];
+        engine.process_block(&mut output);
+        send(handle, EngineCommand::AllNotesOff);
+        engine.process_block(&mut vec![0.0; 48000 * 2]);
+        output[output.len() - 1]
+    }
+
+    #[test]
+    fn samples_loaded_through_the_handle_reach_the_audio_thread() {
+        let dir = std::env::temp_dir().join(format!("zimler-load-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let quiet = write_dc_wav(&dir, "quiet.wav", 0.25);
+        let loud = write_dc_wav(&dir, "loud.wav", 0.5);
+
+        let mut engine = ZimlerEngine::new(EngineConfig {
+            block_size: BLOCK,
+            ..EngineConfig::default()
+        });
+        let handle = engine.get_api_handle();
+        handle.install_mapping(SampleMapping::single(0)).unwrap();
+        assert_eq!(play_middle_c(&mut engine, &handle), 0.0);
+
+        handle.load_sample(0, quiet.to_str().unwrap()).unwrap();
+        let quiet_level = play_middle_c(&mut engine, &handle);
+        assert!(quiet_level > 0.0);
+
+        send(
+            &handle,
+            EngineCommand::LoadSample {
+                slot: 0,
+                path: loud.to_str().unwrap().to_string(),
+            },
+        );
+        let loud_level = play_middle_c(&mut engine, &handle);
+        assert!((loud_level / quiet_level - 2.0).abs() < 1e-3);
+
+        // An SFZ swaps samples and mapping together
+        let sfz = dir.join("quiet.sfz");
+        std::fs::write(&sfz, "<region> sample=quiet.wav key=60").unwrap();
+        assert!(handle.load_sfz(&sfz).unwrap().is_empty());
+        assert!((play_middle_c(&mut engine, &handle) - quiet_level).abs() < 1e-6);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn note_off(handle: &EngineHandle, note: u8) {
+        send(handle, EngineCommand::ReleaseNote { note });
+    }
+
+    /// Runs the engine just past the release time and lists the notes still
+    /// sounding, which are the ones a key or pedal holds.
+    fn sounding(engine: &mut ZimlerEngine) -> Vec<u8> {
+        engine.process_block(&mut vec![0.0; 12000 * 2]);
+        let mut notes: Vec<u8> = engine
+            .voices
+            .iter()
+            .filter(|voice| voice.is_active())
+            .filter_map(Voice::get_note)
+            .collect();
+        notes.sort_unstable();
+        notes
+    }
+
+    #[test]
+    fn sostenuto_holds_only_what_was_down_as_it_went_down() {
+        let (mut engine, handle) = dc_engine();
+        note_on(&handle, 60);
+        send(&handle, EngineCommand::SetSostenuto { on: true });
+        note_on(&handle, 62);
+        // A continuous pedal keeps sending "on"
+        send(&handle, EngineCommand::SetSostenuto { on: true });
+        note_on(&handle, 64);
+        send(&handle, EngineCommand::SetSostenuto { on: true });
+        for note in [60, 62, 64] {
+            note_off(&handle, note);
+        }
+        assert_eq!(sounding(&mut engine), [60]);
+
+        // "Off" repeated, then on again with nothing down, catches nothing
+        send(&handle, EngineCommand::SetSostenuto { on: false });
+        send(&handle, EngineCommand::SetSostenuto { on: false });
+        assert_eq!(sounding(&mut engine), []);
+        send(&handle, EngineCommand::SetSostenuto { on: true });
+        note_on(&handle, 65);
+        note_off(&handle, 65);
+        assert_eq!(sounding(&mut engine), []);
+    }
+
+    #[test]
+    fn sustain_and_sostenuto_hold_independently() {
+        let (mut engine, handle) = dc_engine();
+        note_on(&handle, 60);
+        send(&handle, EngineCommand::SetSostenuto { on: true });
+        note_off(&handle, 60);
+        send(&handle, EngineCommand::SetSustain { on: true });
+        note_on(&handle, 62);
+        note_off(&handle, 62);
+        assert_eq!(sounding(&mut engine), [60, 62]);
+
+        // Lifting sustain lets go of what only it held
+        send(&handle, EngineCommand::SetSustain { on: false });
+        assert_eq!(sounding(&mut engine), [60]);
+
+        // And sostenuto up, under a held sustain, leaves its notes to that
+        send(&handle, EngineCommand::SetSustain { on: true });
+        send(&handle, EngineCommand::SetSostenuto { on: false });
+        assert_eq!(sounding(&mut engine), [60]);
+        send(&handle, EngineCommand::SetSustain { on: false });
+        assert_eq!(sounding(&mut engine), []);
+    }
+
+    #[test]
+    fn all_notes_off_forgets_the_mono_note() {
+        let (mut engine, handle) = dc_engine();
+        send(
+            &handle,
+            EngineCommand::SetVoiceMode {
+                mode: VoiceMode::Mono {
+                    priority: NotePriority::Last,
+                    glide_ms: 0.0,
+                },
+            },
+        );
+        note_on(&handle, 60);
+        send(&handle, EngineCommand::AllNotesOff);
+        engine.process_block(&mut vec![0.0; BLOCK * 2]);
+        assert_eq!(engine.mono_note, None);
+
+        // Letting go of the old key afterwards doesn't touch the new note
+        note_on(&handle, 64);
+        note_off(&handle, 60);
+        assert_eq!(sounding(&mut engine), [64]);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{EngineCommand, EngineHandle};
+use serde::{Deserialize, Serialize};
+use zimler_midi::MidiMessage;
+
+// Controllers the dispatcher acts on
+const MOD_WHEEL: u8 = 1;
+const SUSTAIN_PEDAL: u8 = 64;
+const SOSTENUTO_PEDAL: u8 = 66;
+const ALL_NOTES_OFF: u8 = 123;
+
+// Pitch bend's 14-bit centre
+const BEND_CENTRE: f32 = 8192.0;
+
+/// How note-on velocity (1-127) maps to note level (0-1).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum VelocityCurve {
+    #[default]
+    Linear,
+    /// Velocity raised to the exponent: above 1 takes a harder touch to get
+    /// loud, below 1 a softer one
+    Power(f32),
+    /// Every note at the same level
+    Fixed(f32),
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: u8) -> f32 {
+        let linear = f32::from(velocity.min(127)) / 127.0;
+        match *self {
+            Self::Linear => linear,
+            Self::Power(exponent) => linear.powf(exponent.max(0.0)),
+            Self::Fixed(level) => level.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Turns incoming MIDI into engine commands: notes through the velocity
+/// curve, pitch bend, mod wheel, the sustain and sostenuto pedals and all
+/// notes off. Other controllers are ignored.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MidiDispatcher {
+    // `None` listens on every channel
+    channel: Option<u8>,
+    velocity_curve: VelocityCurve,
+}
+
+impl MidiDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listens on one channel (0-15), or all of them with `None`.
+    pub fn set_channel(&mut self, channel: Option<u8>) {
+        self.channel = channel;
+    }
+
+    pub fn channel(&self) -> Option<u8> {
+        self.channel
+    }
+
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_curve = curve;
+    }
+
+    pub fn velocity_curve(&self) -> VelocityCurve {
+        self.velocity_curve
+    }
+
+    /// The command for a message, or `None` if it is on another channel or
+    /// has no effect on the engine.
+    pub fn translate(&self, message: &MidiMessage) -> Option<EngineCommand> {
+        let channel = match *message {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => channel,
+        };
+        if self.channel.is_some_and(|listening| listening != channel) {
+            return None;
+        }
+
+        match *message {
+            MidiMessage::NoteOn { note, velocity, .. } => Some(EngineCommand::TriggerNote {
+                note,
+                velocity: self.velocity_curve.apply(velocity),
+            }),
+            MidiMessage::NoteOff { note, .. } => Some(EngineCommand::ReleaseNote { note }),
+            MidiMessage::PitchBend { value, .. } => {
+                // Centre to -1..1, reaching 1 at the top despite the odd range
+                let offset = f32::from(value.min(0x3fff)) - BEND_CENTRE;
+                let amount = if offset < 0.0 {
+                    offset / BEND_CENTRE
+                } else {
+                    offset / (BEND_CENTRE - 1.0)
+                };
+                Some(EngineCommand::PitchBend { amount })
+            }
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => match controller {
+                MOD_WHEEL => Some(EngineCommand::ModWheel {
+                    amount: f32::from(value.min(127)) / 127.0,
+                }),
+                // Pedals are on from 64 up
+                SUSTAIN_PEDAL => Some(EngineCommand::SetSustain { on: value >= 64 }),
+                SOSTENUTO_PEDAL => Some(EngineCommand::SetSostenuto { on: value >= 64 }),
+                ALL_NOTES_OFF => Some(EngineCommand::AllNotesOff),
+                _ => None,
+            },
+        }
+    }
+
+    /// Translates a message and sends it to the engine, to take effect at
+    /// the start of the next block.
+    pub fn dispatch(&self, message: &MidiMessage, handle: &EngineHandle) -> Result<(), String> {
+        match self.translate(message) {
+            Some(command) => handle.send_command(command),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EngineConfig, ZimlerEngine};
+
+    fn cc(channel: u8, controller: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel,
+            controller,
+            value,
+        }
+    }
+
+    #[test]
+    fn pedals_switch_at_64() {
+        let dispatcher = MidiDispatcher::new();
+        for (value, on) in [(0, false), (63, false), (64, true), (127, true)] {
+            assert!(matches!(
+                dispatcher.translate(&cc(0, SUSTAIN_PEDAL, value)),
+                Some(EngineCommand::SetSustain { on: sent }) if sent == on
+            ));
+            assert!(matches!(
+                dispatcher.translate(&cc(0, SOSTENUTO_PEDAL, value)),
+                Some(EngineCommand::SetSostenuto { on: sent }) if sent == on
+            ));
+        }
+        assert!(matches!(
+            dispatcher.translate(&cc(0, ALL_NOTES_OFF, 0)),
+            Some(EngineCommand::AllNotesOff)
+        ));
+        // Soft pedal and the rest are ignored
+        assert!(dispatcher.translate(&cc(0, 67, 127)).is_none());
+    }
+
+    #[test]
+    fn listens_on_its_channel_only() {
+        let mut dispatcher = MidiDispatcher::new();
+        dispatcher.set_channel(Some(3));
+        assert!(dispatcher.translate(&cc(2, SUSTAIN_PEDAL, 127)).is_none());
+        assert!(dispatcher.translate(&cc(3, SUSTAIN_PEDAL, 127)).is_some());
+        dispatcher.set_channel(None);
+        assert!(dispatcher.translate(&cc(15, SUSTAIN_PEDAL, 127)).is_some());
+    }
+
+    #[test]
+    fn notes_and_bend_are_scaled() {
+        let mut dispatcher = MidiDispatcher::new();
+        dispatcher.set_velocity_curve(VelocityCurve::Power(2.0));
+        let note_on = MidiMessage::NoteOn {
+            channel: 0,
+            note: 60,
+            velocity: 127,
+        };
+        assert!(matches!(
+            dispatcher.translate(&note_on),
+            Some(EngineCommand::TriggerNote { note: 60, velocity }) if velocity == 1.0
+        ));
+        for (value, expected) in [(0, -1.0), (8192, 0.0), (0x3fff, 1.0)] {
+            let bend = MidiMessage::PitchBend { channel: 0, value };
+            assert!(matches!(
+                dispatcher.translate(&bend),
+                Some(EngineCommand::PitchBend { amount }) if amount == expected
+            ));
+        }
+    }
+
+    #[test]
+    fn dispatched_pedals_reach_the_engine() {
+        let mut engine = ZimlerEngine::new(EngineConfig::default());
+        let handle = engine.get_api_handle();
+        let dispatcher = MidiDispatcher::new();
+        let mut output = vec![0.0; 256 * 2];
+
+        dispatcher
+            .dispatch(&cc(0, SUSTAIN_PEDAL, 100), &handle)
+            .unwrap();
+        dispatcher
+            .dispatch(&cc(0, SOSTENUTO_PEDAL, 64), &handle)
+            .unwrap();
+        engine.process_block(&mut output);
+        assert!(engine.sustain && engine.sostenuto);
+
+        dispatcher
+            .dispatch(&cc(0, SUSTAIN_PEDAL, 10), &handle)
+            .unwrap();
+        engine.process_block(&mut output);
+        assert!(!engine.sustain && engine.sostenuto);
+    }
+}
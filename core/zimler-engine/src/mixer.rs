@@ -1,10 +1,18 @@
 use crate::api::MixMode;
+use zimler_dsp::{SmoothedParam, DEFAULT_RAMP_MS};
+
+// Longest blur crossfade the preallocated buffer can hold
+const MAX_BLUR_MS: f32 = 1000.0;
 
 pub struct Mixer {
     mode: MixMode,
-    master_volume: f32,
+    master_volume: SmoothedParam,
+    blur_amount: SmoothedParam,
     blur_buffer: Vec<f32>,
+    blur_len: usize,
     blur_position: usize,
+    sample_rate: f32,
+    channels: usize,
 }
 
 impl Default for Mixer {
@@ -15,61 +23,74 @@ impl Default for Mixer {
 
 impl Mixer {
     pub fn new() -> Self {
+        Self::with_format(48000.0, 2)
+    }
+
+    pub fn with_format(sample_rate: f32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        let max_blur_len = ((MAX_BLUR_MS / 1000.0) * sample_rate) as usize * channels;
+
         Self {
             mode: MixMode::Poly,
-            master_volume: 0.8,
-            blur_buffer: vec![0.0; 4096],
+            master_volume: SmoothedParam::new(0.8, sample_rate, DEFAULT_RAMP_MS),
+            blur_amount: SmoothedParam::new(0.3, sample_rate, DEFAULT_RAMP_MS),
+            blur_buffer: vec![0.0; max_blur_len.max(channels)],
+            blur_len: channels,
             blur_position: 0,
+            sample_rate,
+            channels,
         }
     }
 
     pub fn set_mode(&mut self, mode: MixMode) {
         self.mode = mode;
         if let MixMode::Blur { crossfade_ms } = mode {
-            let buffer_size = (crossfade_ms * 48.0) as usize; // Assuming 48kHz
-            self.blur_buffer.resize(buffer_size, 0.0);
+            // Whole frames only, so each channel keeps its own blur history
+            let frames = ((crossfade_ms.max(0.0) / 1000.0) * self.sample_rate) as usize;
+            self.blur_len = (frames.max(1) * self.channels).min(self.blur_buffer.len());
+            self.blur_position %= self.blur_len;
         }
     }
 
+    pub fn mode(&self) -> MixMode {
+        self.mode
+    }
+
     pub fn mix(&mut self, input: &[f32], output: &mut [f32]) {
-        match self.mode {
-            MixMode::Poly => {
-                // Standard mixing - just add
-                for (i, sample) in input.iter().enumerate() {
-                    output[i] += sample * self.master_volume;
-                }
-            }
-            MixMode::Blur { crossfade_ms } => {
-                // Serge-style blurring with overlap
-                let blur_len = (crossfade_ms * 48.0) as usize;
+        let channels = self.channels;
 
-                for (i, sample) in input.iter().enumerate() {
-                    // Mix with previous content in circular buffer
-                    let blur_idx = (self.blur_position + i) % blur_len;
-                    let blurred = self.blur_buffer[blur_idx] * 0.3 + sample * 0.7;
-                    self.blur_buffer[blur_idx] = blurred;
-                    output[i] += blurred * self.master_volume;
-                }
+        for (frame_in, frame_out) in input.chunks(channels).zip(output.chunks_mut(channels)) {
+            let volume = self.master_volume.next_value();
+            let blur = self.blur_amount.next_value();
 
-                self.blur_position = (self.blur_position + input.len()) % blur_len;
-            }
-            MixMode::Stack => {
-                // All voices stacked with compression
-                for (i, sample) in input.iter().enumerate() {
-                    let stacked = sample.tanh(); // Soft clipping
-                    output[i] += stacked * self.master_volume;
-                }
-            }
-            MixMode::Rotate => {
-                // Voice rotation handled at voice allocation level
-                for (i, sample) in input.iter().enumerate() {
-                    output[i] += sample * self.master_volume;
-                }
+            for (sample, out) in frame_in.iter().zip(frame_out.iter_mut()) {
+                let mixed = match self.mode {
+                    // Standard mixing - just add
+                    MixMode::Poly => *sample,
+                    MixMode::Blur { .. } => {
+                        // Serge-style blurring with overlap
+                        let blurred =
+                            self.blur_buffer[self.blur_position] * blur + sample * (1.0 - blur);
+                        self.blur_buffer[self.blur_position] = blurred;
+                        self.blur_position = (self.blur_position + 1) % self.blur_len;
+                        blurred
+                    }
+                    // All voices stacked with compression
+                    MixMode::Stack => sample.tanh(),
+                    // Voices rotate at allocation, see ZimlerEngine::allocate_voice
+                    MixMode::Rotate => *sample,
+                };
+                *out += mixed * volume;
             }
         }
     }
 
     pub fn set_master_volume(&mut self, volume: f32) {
-        self.master_volume = volume.clamp(0.0, 1.0);
+        self.master_volume.set_target(volume.clamp(0.0, 1.0));
+    }
+
+    /// How much of the previous blur pass feeds back, from 0 (none) to 1.
+    pub fn set_blur_amount(&mut self, amount: f32) {
+        self.blur_amount.set_target(amount.clamp(0.0, 0.99));
     }
 }
@@ -0,0 +1,122 @@
+use crate::{Envelope, EnvelopeShape};
+use serde::{Deserialize, Serialize};
+
+/// An envelope routed to part of every voice: a pitch or filter envelope,
+/// or with a cycling shape an LFO.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Modulation {
+    pub shape: EnvelopeShape,
+    pub target: ModTarget,
+    /// Semitones for pitch, octaves for filter cutoff, 0-1 for amplitude
+    pub depth: f32,
+    /// Swing either side of the unmodulated value rather than only above
+    /// it. Amplitude is always pulled down from full.
+    #[serde(default)]
+    pub bipolar: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModTarget {
+    Pitch,
+    FilterCutoff,
+    Amplitude,
+}
+
+/// What a modulator does to its voice for one frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ModOffsets {
+    pub(crate) semitones: f32,
+    pub(crate) octaves: f32,
+    pub(crate) gain: f32,
+}
+
+impl ModOffsets {
+    pub(crate) const NONE: Self = Self {
+        semitones: 0.0,
+        octaves: 0.0,
+        gain: 1.0,
+    };
+}
+
+/// A voice's modulation envelope. Gated ones follow the voice's notes;
+/// free-running ones are synced to the engine's copy on each note, so every
+/// voice shares one phase.
+pub(crate) struct Modulator {
+    envelope: Envelope,
+    modulation: Option<Modulation>,
+}
+
+impl Modulator {
+    pub(crate) fn new(sample_rate: f32) -> Self {
+        Self {
+            envelope: Envelope::new(sample_rate),
+            modulation: None,
+        }
+    }
+
+    pub(crate) fn set(&mut self, modulation: Option<Modulation>) {
+        if let Some(modulation) = modulation {
+            self.envelope.set_shape(modulation.shape);
+        }
+        self.modulation = modulation;
+    }
+
+    pub(crate) fn set_tempo(&mut self, bpm: f32) {
+        self.envelope.set_tempo(bpm);
+    }
+
+    fn is_gated(&self) -> bool {
+        self.modulation
+            .is_some_and(|modulation| !modulation.shape.is_free_running())
+    }
+
+    pub(crate) fn trigger(&mut self) {
+        if self.is_gated() {
+            self.envelope.trigger();
+        }
+    }
+
+    pub(crate) fn release(&mut self) {
+        if self.is_gated() {
+            self.envelope.release();
+        }
+    }
+
+    /// Takes up the phase of a free-running envelope.
+    pub(crate) fn sync(&mut self, clock: &Envelope) {
+        self.envelope.clone_from(clock);
+    }
+
+    /// Steps the envelope, returning its effect this frame.
+    pub(crate) fn next(&mut self) -> ModOffsets {
+        let Some(modulation) = self.modulation else {
+            return ModOffsets::NONE;
+        };
+        let level = self.envelope.get_current_value();
+        self.envelope.process_sample();
+
+        let swing = if modulation.bipolar {
+            level * 2.0 - 1.0
+        } else {
+            level
+        };
+        let amount = swing * modulation.depth;
+        match modulation.target {
+            ModTarget::Pitch => ModOffsets {
+                semitones: amount,
+                ..ModOffsets::NONE
+            },
+            ModTarget::FilterCutoff => ModOffsets {
+                octaves: amount,
+                ..ModOffsets::NONE
+            },
+            ModTarget::Amplitude => {
+                let depth = modulation.depth.clamp(0.0, 1.0);
+                ModOffsets {
+                    gain: 1.0 - depth + depth * level,
+                    ..ModOffsets::NONE
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use crate::{
+    ContentHash, Curve, DecodeError, DecoderRegistry, EngineCommand, EnvelopeShape, MixMode,
+    Modulation, Parameter, RateConversion, RetriggerMode, Sample, SampleLoop, SampleMapping,
+    VoiceMode, VoiceStealing,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use zimler_dsp::{FilterMode, InterpolationMode, ResampleError};
+
+/// Format version this build writes. Fields added later get serde defaults;
+/// anything that changes the meaning of existing data bumps the version and
+/// adds a step to `MIGRATIONS`.
+pub const PRESET_VERSION: u32 = 1;
+
+/// Upgrade steps, the first taking version 1 to 2 and so on. Each works on
+/// the parsed preset, reinterpreting fields whose meaning changed. Version
+/// 1 is the first format, so there are none yet.
+const MIGRATIONS: &[fn(&mut Preset)] = &[];
+
+// A version bump without its migration step fails to build
+const _: () = assert!(MIGRATIONS.len() + 1 == PRESET_VERSION as usize);
+
+#[derive(Debug, thiserror::Error)]
+pub enum PresetError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid RON preset: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("Invalid MessagePack preset: {0}")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+    #[error("Could not encode preset: {0}")]
+    Encode(String),
+    #[error("Preset version {0} is newer than this build supports ({PRESET_VERSION})")]
+    TooNew(u32),
+    #[error("Preset version {0} was never written; versions start at 1")]
+    UnknownVersion(u32),
+    #[error("Sample for slot {slot}: {error}")]
+    Sample { slot: usize, error: DecodeError },
+    #[error("Sample for slot {slot}: {error}")]
+    Resample { slot: usize, error: ResampleError },
+}
+
+/// How a preset is stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresetFormat {
+    /// Human-readable, for hand editing and version control
+    Ron,
+    /// Compact MessagePack with named fields, so it migrates like RON
+    MessagePack,
+}
+
+impl PresetFormat {
+    /// RON for `.ron` files, MessagePack for anything else.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("ron") => Self::Ron,
+            _ => Self::MessagePack,
+        }
+    }
+}
+
+/// Everything needed to bring an engine back to a sound: which files go in
+/// which slots, the zone mapping and the engine-wide settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub version: u32,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub samples: Vec<PresetSample>,
+    #[serde(default)]
+    pub mapping: SampleMapping,
+    #[serde(default)]
+    pub settings: EngineSettings,
+}
+
+/// A sample referenced by file, plus the edits made on top of the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresetSample {
+    pub slot: usize,
+    /// Relative paths resolve against the preset's directory
+    pub path: PathBuf,
+    #[serde(default)]
+    pub root_note: Option<u8>,
+    #[serde(default)]
+    pub tune_cents: f32,
+    #[serde(default)]
+    pub sample_loop: Option<SampleLoop>,
+    /// Rate the loop points were counted at
+    pub sample_rate: f32,
+    /// Embedded audio to play instead of the file, in a bundle
+    #[serde(default)]
+    pub content_hash: Option<ContentHash>,
+}
+
+/// Engine-wide settings, as last sent through an `EngineHandle`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineSettings {
+    pub envelope: EnvelopeShape,
+    pub mix_mode: MixMode,
+    pub voice_stealing: VoiceStealing,
+    pub voice_mode: VoiceMode,
+    pub retrigger: RetriggerMode,
+    pub interpolation: InterpolationMode,
+    pub filter_mode: Option<FilterMode>,
+    pub master_volume: f32,
+    pub voice_blur: f32,
+    pub sample_start_offset: f32,
+    pub sample_end_offset: f32,
+    pub pitch_bend_range: f32,
+    pub filter_cutoff: f32,
+    pub filter_resonance: f32,
+    pub stereo_width: f32,
+    pub vibrato_depth: f32,
+    pub tempo: f32,
+    pub modulation: Option<Modulation>,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        Self {
+            envelope: EnvelopeShape::default(),
+            mix_mode: MixMode::default(),
+            voice_stealing: VoiceStealing::default(),
+            voice_mode: VoiceMode::default(),
+            retrigger: RetriggerMode::default(),
+            interpolation: InterpolationMode::default(),
+            filter_mode: None,
+            master_volume: 0.8,
+            voice_blur: 0.3,
+            sample_start_offset: 0.0,
+            sample_end_offset: 0.0,
+            pitch_bend_range: 2.0,
+            filter_cutoff: 1000.0,
+            filter_resonance: 0.5,
+            stereo_width: 1.0,
+            vibrato_depth: 0.5,
+            tempo: 120.0,
+            modulation: None,
+        }
+    }
+}
+
+impl EngineSettings {
+    /// Tracks the effect of a command. Commands that don't change a
+    /// setting are ignored.
+    pub fn record(&mut self, command: &EngineCommand) {
+        match command {
+            EngineCommand::SetEnvelope { envelope } => self.envelope = *envelope,
+            EngineCommand::SetMixMode { mode } => self.mix_mode = *mode,
+            EngineCommand::SetVoiceStealing { mode } => self.voice_stealing = *mode,
+            EngineCommand::SetVoiceMode { mode } => self.voice_mode = *mode,
+            EngineCommand::SetRetrigger { mode } => self.retrigger = *mode,
+            EngineCommand::SetInterpolation { mode } => self.interpolation = *mode,
+            EngineCommand::SetFilterMode { mode } => self.filter_mode = *mode,
+            EngineCommand::SetModulation { modulation } => self.modulation = *modulation,
+            EngineCommand::SetParameter { param, value } => self.set_parameter(*param, *value),
+            _ => {}
+        }
+    }
+
+    fn set_parameter(&mut self, param: Parameter, value: f32) {
+        match param {
+            Parameter::MasterVolume => self.master_volume = value,
+            Parameter::VoiceBlur => self.voice_blur = value,
+            // Same limits the engine applies
+            Parameter::EnvelopeAttack => self.envelope.set_attack_ms(value.max(0.0)),
+            Parameter::EnvelopeDecay => self.envelope.set_decay_ms(value.max(0.0)),
+            Parameter::EnvelopeSustain => self.envelope.set_sustain(value.clamp(0.0, 1.0)),
+            Parameter::EnvelopeRelease => self.envelope.set_release_ms(value.max(0.0)),
+            Parameter::EnvelopeAttackCurve => {
+                self.envelope.set_attack_curve(Curve::Shaped(value));
+            }
+            Parameter::EnvelopeDecayCurve => self.envelope.set_decay_curve(Curve::Shaped(value)),
+            Parameter::EnvelopeReleaseCurve => {
+                self.envelope.set_release_curve(Curve::Shaped(value));
+            }
+            Parameter::SampleStartOffset => self.sample_start_offset = value,
+            Parameter::SampleEndOffset => self.sample_end_offset = value,
+            Parameter::PitchBendRange => self.pitch_bend_range = value,
+            Parameter::FilterCutoff => self.filter_cutoff = value,
+            Parameter::FilterResonance => self.filter_resonance = value,
+            Parameter::StereoWidth => self.stereo_width = value,
+            Parameter::VibratoDepth => self.vibrato_depth = value,
+            Parameter::Tempo => self.tempo = value,
+        }
+    }
+
+    /// Commands that bring an engine to these settings.
+    pub fn commands(&self) -> Vec<EngineCommand> {
+        let parameter = |param, value| EngineCommand::SetParameter { param, value };
+        vec![
+            EngineCommand::SetEnvelope {
+                envelope: self.envelope,
+            },
+            EngineCommand::SetMixMode {
+                mode: self.mix_mode,
+            },
+            EngineCommand::SetVoiceStealing {
+                mode: self.voice_stealing,
+            },
+            EngineCommand::SetVoiceMode {
+                mode: self.voice_mode,
+            },
+            EngineCommand::SetRetrigger {
+                mode: self.retrigger,
+            },
+            EngineCommand::SetInterpolation {
+                mode: self.interpolation,
+            },
+            EngineCommand::SetFilterMode {
+                mode: self.filter_mode,
+            },
+            parameter(Parameter::MasterVolume, self.master_volume),
+            parameter(Parameter::VoiceBlur, self.voice_blur),
+            parameter(Parameter::SampleStartOffset, self.sample_start_offset),
+            parameter(Parameter::SampleEndOffset, self.sample_end_offset),
+            parameter(Parameter::PitchBendRange, self.pitch_bend_range),
+            parameter(Parameter::FilterCutoff, self.filter_cutoff),
+            parameter(Parameter::FilterResonance, self.filter_resonance),
+            parameter(Parameter::StereoWidth, self.stereo_width),
+            parameter(Parameter::VibratoDepth, self.vibrato_depth),
+            parameter(Parameter::Tempo, self.tempo),
+            EngineCommand::SetModulation {
+                modulation: self.modulation,
+            },
+        ]
+    }
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self {
+            version: PRESET_VERSION,
+            name: String::new(),
+            samples: Vec::new(),
+            mapping: SampleMapping::default(),
+            settings: EngineSettings::default(),
+        }
+    }
+}
+
+impl Preset {
+    pub fn to_bytes(&self, format: PresetFormat) -> Result<Vec<u8>, PresetError> {
+        match format {
+            PresetFormat::Ron => {
+                ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+                    .map(String::into_bytes)
+                    .map_err(|e| PresetError::Encode(e.to_string()))
+            }
+            PresetFormat::MessagePack => {
+                rmp_serde::to_vec_named(self).map_err(|e| PresetError::Encode(e.to_string()))
+            }
+        }
+    }
+
+    /// Reads either format, telling them apart by the first byte, and
+    /// migrates older versions.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PresetError> {
+        // A MessagePack preset is a map: fixmap, map16 or map32
+        let preset: Self = match bytes.first() {
+            Some(0x80..=0x8f | 0xde | 0xdf) => rmp_serde::from_slice(bytes)?,
+            _ => ron::de::from_bytes(bytes)?,
+        };
+        preset.migrate()
+    }
+
+    /// Writes the preset in the format its extension asks for. Sample
+    /// paths under the preset's directory are stored relative to it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PresetError> {
+        let path = path.as_ref();
+        let mut preset = self.clone();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            for sample in &mut preset.samples {
+                if let Ok(relative) = sample.path.strip_prefix(dir) {
+                    sample.path = relative.to_path_buf();
+                }
+            }
+        }
+        std::fs::write(path, preset.to_bytes(PresetFormat::from_path(path))?)?;
+        Ok(())
+    }
+
+    /// Reads a preset file, resolving sample paths against its directory.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PresetError> {
+        let path = path.as_ref();
+        let mut preset = Self::from_bytes(&std::fs::read(path)?)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for sample in &mut preset.samples {
+            if sample.path.is_relative() {
+                sample.path = dir.join(&sample.path);
+            }
+        }
+        Ok(preset)
+    }
+
+    /// Upgrades a preset written by an older build, one version at a time.
+    fn migrate(mut self) -> Result<Self, PresetError> {
+        if self.version > PRESET_VERSION {
+            return Err(PresetError::TooNew(self.version));
+        }
+        let steps = self
+            .version
+            .checked_sub(1)
+            .and_then(|done| MIGRATIONS.get(done as usize..))
+            .ok_or(PresetError::UnknownVersion(self.version))?;
+        for step in steps {
+            step(&mut self);
+            self.version += 1;
+        }
+        Ok(self)
+    }
+
+    /// Decodes every referenced sample and reapplies its edits, ready to
+    /// install. Fails on the first sample that can't be loaded.
+    pub fn load_samples(
+        &self,
+        rate_conversion: RateConversion,
+        engine_rate: f32,
+    ) -> Result<Vec<(usize, Sample)>, PresetError> {
+        let registry = DecoderRegistry::default();
+        self.samples
+            .iter()
+            .map(|entry| {
+                let slot = entry.slot;
+                let sample = registry
+                    .decode_file(&entry.path)
+                    .map_err(|error| PresetError::Sample { slot, error })?;
+                let sample = entry
+                    .prepare(sample, rate_conversion, engine_rate)
+                    .map_err(|error| PresetError::Resample { slot, error })?;
+                Ok((slot, sample))
+            })
+            .collect()
+    }
+}
+
+impl PresetSample {
+    /// Reference to an installed sample, or `None` if it wasn't loaded
+    /// from a file.
+    pub fn from_sample(slot: usize, sample: &Sample) -> Option<Self> {
+        Some(Self {
+            slot,
+            path: sample.source.as_deref()?.to_path_buf(),
+            root_note: sample.root_note,
+            tune_cents: sample.tune_cents,
+            sample_loop: sample.sample_loop,
+            sample_rate: sample.sample_rate,
+            content_hash: None,
+        })
+    }
+
+    /// Reapplies the stored edits to freshly decoded audio and converts it
+    /// for the engine.
+    pub fn prepare(
+        &self,
+        mut sample: Sample,
+        rate_conversion: RateConversion,
+        engine_rate: f32,
+    ) -> Result<Sample, ResampleError> {
+        sample.root_note = self.root_note;
+        sample.tune_cents = self.tune_cents;
+        let mut sample = rate_conversion.prepare(sample, engine_rate)?;
+
+        // Loop points follow the audio if its rate changed
+        let ratio = f64::from(sample.sample_rate) / f64::from(self.sample_rate);
+        let scale = |frame: usize| (frame as f64 * ratio).round() as usize;
+        sample.set_loop(self.sample_loop.map(|l| SampleLoop {
+            start: scale(l.start),
+            end: scale(l.end),
+            crossfade: scale(l.crossfade),
+            ..l
+        }));
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LoopMode, ModTarget, NotePriority};
+
+    /// A preset with something other than the default in every section.
+    fn preset() -> Preset {
+        let mut sample_loop = SampleLoop::new(1200, 48000, LoopMode::PingPong);
+        sample_loop.crossfade = 256;
+        sample_loop.sustain = true;
+        Preset {
+            name: "Round trip".to_string(),
+            samples: vec![
+                PresetSample {
+                    slot: 0,
+                    path: PathBuf::from("/samples/soft.wav"),
+                    root_note: Some(57),
+                    tune_cents: -12.5,
+                    sample_loop: Some(sample_loop),
+                    sample_rate: 44100.0,
+                    content_hash: None,
+                },
+                PresetSample {
+                    slot: 3,
+                    path: PathBuf::from("/samples/hard.wav"),
+                    root_note: None,
+                    tune_cents: 0.0,
+                    sample_loop: None,
+                    sample_rate: 48000.0,
+                    content_hash: Some(ContentHash::of(&Sample::new(vec![0.5; 8], 48000.0, 2))),
+                },
+            ],
+            mapping: SampleMapping::velocity_layers([(64, 0), (127, 3)]),
+            settings: EngineSettings {
+                envelope: EnvelopeShape::AR {
+                    attack_ms: 3.0,
+                    release_ms: 250.0,
+                    attack_curve: Curve::Rc,
+                    release_curve: Curve::Shaped(0.4),
+                },
+                mix_mode: MixMode::Blur { crossfade_ms: 40.0 },
+                voice_mode: VoiceMode::Mono {
+                    priority: NotePriority::Lowest,
+                    glide_ms: 80.0,
+                },
+                retrigger: RetriggerMode::Legato,
+                interpolation: InterpolationMode::Polyphase { taps: 16 },
+                filter_mode: Some(FilterMode::Bandpass),
+                master_volume: 0.6,
+                modulation: Some(Modulation {
+                    shape: EnvelopeShape::default(),
+                    target: ModTarget::FilterCutoff,
+                    depth: 2.0,
+                    bipolar: true,
+                }),
+                ..EngineSettings::default()
+            },
+            ..Preset::default()
+        }
+    }
+
+    #[test]
+    fn round_trips_through_ron() {
+        let preset = preset();
+        let bytes = preset.to_bytes(PresetFormat::Ron).unwrap();
+        assert!(std::str::from_utf8(&bytes).unwrap().contains("Round trip"));
+        assert_eq!(Preset::from_bytes(&bytes).unwrap(), preset);
+    }
+
+    #[test]
+    fn round_trips_through_message_pack() {
+        let preset = preset();
+        let bytes = preset.to_bytes(PresetFormat::MessagePack).unwrap();
+        assert_eq!(Preset::from_bytes(&bytes).unwrap(), preset);
+        assert!(bytes.len() < preset.to_bytes(PresetFormat::Ron).unwrap().len());
+    }
+
+    #[test]
+    fn saves_sample_paths_relative_to_the_preset() {
+        let dir = std::env::temp_dir().join(format!("zimler-preset-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut preset = preset();
+        preset.samples[0].path = dir.join("soft.wav");
+
+        for name in ["preset.ron", "preset.zmp"] {
+            let path = dir.join(name);
+            preset.save(&path).unwrap();
+            let stored = Preset::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(stored.samples[0].path, PathBuf::from("soft.wav"));
+            assert_eq!(stored.samples[1].path, PathBuf::from("/samples/hard.wav"));
+            assert_eq!(Preset::load(&path).unwrap(), preset);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_fields_take_their_defaults() {
+        let preset = Preset::from_bytes(b"(version: 1, settings: (master_volume: 0.5))").unwrap();
+        assert_eq!(preset.name, "");
+        assert!(preset.samples.is_empty());
+        assert_eq!(preset.settings.master_volume, 0.5);
+        assert_eq!(preset.settings.tempo, EngineSettings::default().tempo);
+    }
+
+    #[test]
+    fn rejects_versions_it_cannot_read() {
+        let mut preset = preset();
+        preset.version = PRESET_VERSION + 1;
+        let bytes = preset.to_bytes(PresetFormat::Ron).unwrap();
+        assert!(matches!(
+            Preset::from_bytes(&bytes),
+            Err(PresetError::TooNew(v)) if v == PRESET_VERSION + 1
+        ));
+
+        preset.version = 0;
+        let bytes = preset.to_bytes(PresetFormat::MessagePack).unwrap();
+        assert!(matches!(
+            Preset::from_bytes(&bytes),
+            Err(PresetError::UnknownVersion(0))
+        ));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{EngineConfig, EngineHandle, TimedCommand, ZimlerEngine};
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+/// Drives a `ZimlerEngine` without an audio device, applying timed commands
+/// at their exact frame and collecting the interleaved output.
+pub struct OfflineRenderer {
+    config: EngineConfig,
+    engine: ZimlerEngine,
+    handle: EngineHandle,
+}
+
+impl OfflineRenderer {
+    /// Fails if the config has no channels, an empty block size or a
+    /// sample rate that isn't positive.
+    pub fn new(config: EngineConfig) -> Result<Self> {
+        if config.num_channels == 0 {
+            return Err(anyhow!("Offline render needs at least one channel"));
+        }
+        if config.block_size == 0 {
+            return Err(anyhow!("Offline render needs a block size above 0"));
+        }
+        if !(config.sample_rate.is_finite() && config.sample_rate > 0.0) {
+            return Err(anyhow!("Invalid sample rate: {}", config.sample_rate));
+        }
+
+        let engine = ZimlerEngine::new(config.clone());
+        let handle = engine.get_api_handle();
+        Ok(Self {
+            config,
+            engine,
+            handle,
+        })
+    }
+
+    /// Handle to the underlying engine, e.g. for loading samples up front.
+    pub fn handle(&self) -> &EngineHandle {
+        &self.handle
+    }
+
+    /// Renders `duration_secs` of audio and returns interleaved samples.
+    /// Event frames count from the start of this call.
+    pub fn render(&mut self, events: &[TimedCommand], duration_secs: f32) -> Result<Vec<f32>> {
+        if duration_secs < 0.0 {
+            return Err(anyhow!("Negative render duration: {duration_secs}"));
+        }
+
+        let channels = self.config.num_channels;
+        let block_size = self.config.block_size;
+        let total_frames =
+            (f64::from(duration_secs) * f64::from(self.config.sample_rate)).round() as u64;
+
+        let mut events = events.to_vec();
+        events.sort_by_key(|event| event.frame);
+        let mut pending = events.into_iter().peekable();
+
+        let mut output = vec![0.0; total_frames as usize * channels];
+        let mut block_start = 0u64;
+        // Event frames are relative to the start of this render
+        let origin = self.handle.current_frame();
+
+        while block_start < total_frames {
+            let mut block_end = (block_start + block_size as u64).min(total_frames);
+
+            // Hand this block's events to the engine, which splits the block
+            // so each one lands on its exact frame. Control-side commands
+            // take effect as they are sent, so the block ends at the next.
+            while let Some(event) = pending.peek() {
+                if event.frame >= block_end {
+                    break;
+                }
+                if event.command.is_control_side() && event.frame > block_start {
+                    block_end = event.frame;
+                    break;
+                }
+                if let Some(event) = pending.next() {
+                    self.handle
+                        .send_command_at(event.command, origin + event.frame)
+                        .map_err(|e| anyhow!(e))?;
+                }
+            }
+
+            let from = block_start as usize * channels;
+            let to = block_end as usize * channels;
+            self.engine.process_block(&mut output[from..to]);
+            block_start = block_end;
+        }
+
+        Ok(output)
+    }
+
+    /// Renders `duration_secs` of audio to a 32-bit float WAV file.
+    pub fn render_to_wav<P: AsRef<Path>>(
+        &mut self,
+        events: &[TimedCommand],
+        duration_secs: f32,
+        path: P,
+    ) -> Result<()> {
+        let output = self.render(events, duration_secs)?;
+
+        let spec = hound::WavSpec {
+            channels: self.config.num_channels as u16,
+            sample_rate: self.config.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for sample in output {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EngineCommand, Sample, SampleMapping};
+    use zimler_dsp::InterpolationMode;
+
+    #[test]
+    fn rejects_configs_it_cannot_render() {
+        for config in [
+            EngineConfig {
+                num_channels: 0,
+                ..EngineConfig::default()
+            },
+            EngineConfig {
+                block_size: 0,
+                ..EngineConfig::default()
+            },
+            EngineConfig {
+                sample_rate: 0.0,
+                ..EngineConfig::default()
+            },
+            EngineConfig {
+                sample_rate: f32::NAN,
+                ..EngineConfig::default()
+            },
+        ] {
+            assert!(OfflineRenderer::new(config).is_err());
+        }
+    }
+
+    #[test]
+    fn renders_events_on_their_frames() {
+        let config = EngineConfig {
+            block_size: 64,
+            num_channels: 1,
+            ..EngineConfig::default()
+        };
+        let mut renderer = OfflineRenderer::new(config).unwrap();
+        let mut sample = Sample::new(vec![1.0; 48000], 48000.0, 1);
+        sample.root_note = Some(60);
+        renderer.handle().install_sample(0, Some(sample)).unwrap();
+        renderer
+            .handle()
+            .install_mapping(SampleMapping::single(0))
+            .unwrap();
+
+        // Not on a block boundary, and out of order
+        let events = [
+            TimedCommand {
+                frame: 300,
+                command: EngineCommand::ReleaseNote { note: 60 },
+            },
+            TimedCommand {
+                frame: 150,
+                command: EngineCommand::TriggerNote {
+                    note: 60,
+                    velocity: 1.0,
+                },
+            },
+        ];
+        let output = renderer.render(&events, 0.01).unwrap();
+        assert_eq!(output.len(), 480);
+        // The envelope's first frame is silent
+        assert!(output[..151].iter().all(|x| *x == 0.0));
+        assert!(output[151..300].iter().all(|x| *x > 0.0));
+
+        assert!(renderer.render(&[], -1.0).is_err());
+        assert!(renderer.render(&[], 0.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn control_side_events_land_on_their_frames_at_any_block_size() {
+        let render = |block_size| {
+            let config = EngineConfig {
+                block_size,
+                num_channels: 1,
+                ..EngineConfig::default()
+            };
+            let mut renderer = OfflineRenderer::new(config).unwrap();
+            for (slot, level) in [(0, 0.5), (1, -0.25)] {
+                let mut sample = Sample::new(vec![level; 48000], 48000.0, 1);
+                sample.root_note = Some(60);
+                renderer
+                    .handle()
+                    .install_sample(slot, Some(sample))
+                    .unwrap();
+            }
+            renderer
+                .handle()
+                .install_mapping(SampleMapping::single(0))
+                .unwrap();
+
+            let event = |frame, command| TimedCommand { frame, command };
+            let note_on = |note| EngineCommand::TriggerNote {
+                note,
+                velocity: 1.0,
+            };
+            let events = [
+                event(10, note_on(60)),
+                event(
+                    123,
+                    EngineCommand::SetMapping {
+                        mapping: SampleMapping::single(1),
+                    },
+                ),
+                event(123, note_on(62)),
+                event(
+                    250,
+                    EngineCommand::SetInterpolation {
+                        mode: InterpolationMode::Cubic,
+                    },
+                ),
+                event(251, note_on(64)),
+                event(300, EngineCommand::ReleaseNote { note: 60 }),
+            ];
+            renderer.render(&events, 0.01).unwrap()
+        };
+
+        let reference = render(64);
+        for block_size in [1, 100, 480] {
+            assert_eq!(render(block_size), reference, "block size {block_size}");
+        }
+    }
+}
@@ -1,42 +1,399 @@
+use crate::EnvelopeShape;
 use anyhow::{anyhow, Result};
-use hound;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
+use std::sync::Arc;
+use zimler_dsp::{PitchEstimate, ResampleError};
 
+mod decode;
+mod encode;
+
+pub use decode::*;
+pub use encode::*;
+
+/// Number of slots in a `SampleBank`. Slots are preallocated so installing a
+/// sample on the audio thread never grows the bank.
+pub const MAX_SAMPLE_SLOTS: usize = 1024;
+
+/// Decoded audio. The data is shared and immutable, so cloning a `Sample`
+/// (e.g. when a voice is triggered) never copies or allocates.
 #[derive(Debug, Clone)]
 pub struct Sample {
-    pub data: Vec<f32>,
+    /// Display name, usually the file name without its extension
+    pub name: Arc<str>,
+    /// File the audio was decoded from, if any
+    pub source: Option<Arc<Path>>,
+    pub data: Arc<[f32]>,
     pub sample_rate: f32,
     pub channels: usize,
     pub root_note: Option<u8>,
+    /// Transposes playback by this many cents on top of the root note
+    pub tune_cents: f32,
+    pub sample_loop: Option<SampleLoop>,
+}
+
+/// Direction a voice travels through a loop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LoopMode {
+    /// Jump from the loop end back to the start
+    #[default]
+    Forward,
+    /// Bounce between the loop end and start
+    PingPong,
+    /// Play up to the loop end, then run the loop backwards repeatedly
+    Reverse,
+}
+
+/// A loop region in frames. `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SampleLoop {
+    pub start: usize,
+    pub end: usize,
+    pub mode: LoopMode,
+    /// Frames blended across the loop seam (forward and reverse loops only)
+    pub crossfade: usize,
+    /// Loop only while the note is held, then play on to the end of the sample
+    pub sustain: bool,
+}
+
+impl SampleLoop {
+    pub fn new(start: usize, end: usize, mode: LoopMode) -> Self {
+        Self {
+            start,
+            end,
+            mode,
+            crossfade: 0,
+            sustain: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fits the loop inside a sample of `frames` frames, shrinking the
+    /// crossfade to the audio available on the far side of the seam.
+    /// Returns `None` if nothing of the loop is left.
+    pub fn clamped(mut self, frames: usize) -> Option<Self> {
+        self.end = self.end.min(frames);
+        if self.start >= self.end {
+            return None;
+        }
+        let outside = match self.mode {
+            LoopMode::Forward => self.start,
+            LoopMode::Reverse => frames - self.end,
+            LoopMode::PingPong => 0,
+        };
+        self.crossfade = self.crossfade.min(self.len()).min(outside);
+        Some(self)
+    }
 }
 
 impl Sample {
+    /// Audio with no channels is taken as mono.
     pub fn new(data: Vec<f32>, sample_rate: f32, channels: usize) -> Self {
         Self {
-            data,
+            name: Arc::from(""),
+            source: None,
+            data: data.into(),
             sample_rate,
-            channels,
-            root_note: Some(60), // Middle C default
+            channels: channels.max(1),
+            root_note: None,
+            tune_cents: 0.0,
+            sample_loop: None,
         }
     }
 
+    pub fn frames(&self) -> usize {
+        self.data.len() / self.channels.max(1)
+    }
+
+    /// Sets or clears the loop, clamped to the sample's length.
+    pub fn set_loop(&mut self, sample_loop: Option<SampleLoop>) {
+        self.sample_loop = sample_loop.and_then(|l| l.clamped(self.frames()));
+    }
+
+    /// Sets the root note and tuning from the pitch of the audio, if the
+    /// detector is at least `min_confidence` (0-1) sure of it. Leaves both
+    /// alone otherwise, returning whatever estimate there was. Runs an FFT
+    /// analysis, so keep it off the audio thread.
+    pub fn detect_root(&mut self, min_confidence: f32) -> Option<PitchEstimate> {
+        let channels = self.channels.max(1);
+        let mono: Vec<f32> = self
+            .data
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        let estimate = zimler_dsp::detect_pitch(&mono, self.sample_rate)?;
+        if estimate.confidence < min_confidence {
+            return Some(estimate);
+        }
+        let (note, cents) = estimate.to_note();
+        self.root_note = Some(note);
+        // Tune back down by however sharp the recording is
+        self.tune_cents = -cents;
+        Some(estimate)
+    }
+
+    /// Min/max summary of frames `start..end` in `points` columns, across
+    /// all channels, for drawing. Columns narrower than a frame repeat it.
+    pub fn peaks(&self, start: usize, end: usize, points: usize) -> WaveformPeaks {
+        let channels = self.channels.max(1);
+        let end = end.min(self.frames());
+        let start = start.min(end);
+        let points = if start == end { 0 } else { points };
+        let span = (end - start) as f64;
+
+        let mut peaks = WaveformPeaks {
+            start_frame: start,
+            end_frame: end,
+            position: None,
+            min: Vec::with_capacity(points),
+            max: Vec::with_capacity(points),
+        };
+        for point in 0..points {
+            let first = start + (span * point as f64 / points as f64) as usize;
+            let last = (start + (span * (point + 1) as f64 / points as f64) as usize)
+                .clamp(first + 1, end);
+            let column = &self.data[first * channels..last * channels];
+            let (min, max) = column.iter().fold((f32::MAX, f32::MIN), |(min, max), &x| {
+                (min.min(x), max.max(x))
+            });
+            peaks.min.push(min);
+            peaks.max.push(max);
+        }
+        peaks
+    }
+
     pub fn duration_ms(&self) -> f32 {
-        (self.data.len() as f32 / self.channels as f32 / self.sample_rate) * 1000.0
+        (self.frames() as f32 / self.sample_rate) * 1000.0
+    }
+
+    /// A copy of this sample converted to `sample_rate`.
+    pub fn resampled(&self, sample_rate: f32) -> Result<Self, ResampleError> {
+        let data =
+            zimler_dsp::resample_buffer(&self.data, self.channels, self.sample_rate, sample_rate)?;
+        // Keep loop points on the same audio
+        let ratio = f64::from(sample_rate) / f64::from(self.sample_rate);
+        let scale = |frame: usize| (frame as f64 * ratio).round() as usize;
+        let mut resampled = Self {
+            data: data.into(),
+            sample_rate,
+            ..self.clone()
+        };
+        resampled.set_loop(self.sample_loop.map(|l| SampleLoop {
+            start: scale(l.start),
+            end: scale(l.end),
+            crossfade: scale(l.crossfade),
+            ..l
+        }));
+        Ok(resampled)
+    }
+}
+
+/// How a bank keeps samples in tune when their rate differs from the engine's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateConversion {
+    /// Keep the source data; voices scale their playback increment instead
+    #[default]
+    Playback,
+    /// Resample to the engine rate once, when the sample is loaded
+    OnLoad,
+}
+
+impl RateConversion {
+    /// Readies a freshly decoded sample for an engine running at `engine_rate`.
+    pub fn prepare(self, sample: Sample, engine_rate: f32) -> Result<Sample, ResampleError> {
+        match self {
+            Self::OnLoad if sample.sample_rate != engine_rate => sample.resampled(engine_rate),
+            _ => Ok(sample),
+        }
+    }
+}
+
+/// Whether loading guesses a root note for files whose metadata names none.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RootDetection {
+    /// Leave the root unset, so the default applies
+    Off,
+    /// Take the detected pitch when the detector is at least this sure of
+    /// it (0-1); drums, noise and chords rarely are
+    MinConfidence(f32),
+}
+
+impl Default for RootDetection {
+    fn default() -> Self {
+        Self::MinConfidence(0.9)
     }
 }
 
 pub struct SampleBank {
-    samples: HashMap<usize, Sample>,
-    current_mapping: SampleMapping,
+    samples: Vec<Option<Sample>>,
+    // Shared so a new mapping can be handed to the audio thread without
+    // it ever freeing the old one
+    current_mapping: Arc<SampleMapping>,
+    // Notes played so far on each key, for round robin
+    key_counts: [u32; 128],
+    sample_rate: f32,
+    rate_conversion: RateConversion,
+    root_detection: RootDetection,
+}
+
+/// Which slots play for a note: every zone whose key range, velocity range
+/// and round robin position match, layered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleMapping {
+    pub zones: Vec<Zone>,
+    /// When no zone covers a key, play the zones whose root is nearest
+    pub nearest_root_fallback: bool,
+}
+
+/// A slot played across a range of keys and MIDI velocities.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Zone {
+    pub slot: usize,
+    pub key_low: u8,
+    pub key_high: u8,
+    pub velocity_low: u8,
+    pub velocity_high: u8,
+    /// Key the sample plays at its recorded pitch; `None` uses the sample's own
+    pub root_note: Option<u8>,
+    /// Added to the sample's own tuning
+    pub fine_tune_cents: f32,
+    /// Linear gain applied on top of velocity
+    pub gain: f32,
+    /// -1 (left) to 1 (right)
+    pub pan: f32,
+    /// Round robin: the zone plays on note `seq_position` (from 1) of every
+    /// `seq_length` played on the same key
+    pub seq_length: u32,
+    pub seq_position: u32,
+    /// Replaces the engine envelope for notes from this zone
+    pub envelope: Option<EnvelopeShape>,
+}
+
+impl Zone {
+    /// A zone playing `slot` across every key and velocity.
+    pub fn new(slot: usize) -> Self {
+        Self {
+            slot,
+            key_low: 0,
+            key_high: 127,
+            velocity_low: 0,
+            velocity_high: 127,
+            root_note: None,
+            fine_tune_cents: 0.0,
+            gain: 1.0,
+            pan: 0.0,
+            seq_length: 1,
+            seq_position: 1,
+            envelope: None,
+        }
+    }
+
+    pub fn covers_key(&self, note: u8) -> bool {
+        (self.key_low..=self.key_high).contains(&note)
+    }
+
+    /// Whether the zone plays at MIDI velocity `velocity` when this is the
+    /// `count`th note (from 0) on the key.
+    pub fn matches_velocity(&self, velocity: u8, count: u32) -> bool {
+        (self.velocity_low..=self.velocity_high).contains(&velocity)
+            && count % self.seq_length.max(1) + 1 == self.seq_position
+    }
+}
+
+impl Default for SampleMapping {
+    fn default() -> Self {
+        Self::single(0)
+    }
 }
 
+impl SampleMapping {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Self {
+            zones,
+            nearest_root_fallback: true,
+        }
+    }
+
+    /// One sample pitched across the whole keyboard.
+    pub fn single(slot: usize) -> Self {
+        Self::new(vec![Zone::new(slot)])
+    }
+
+    /// A sample per key, each played at its recorded pitch on that key.
+    pub fn per_note(notes: impl IntoIterator<Item = (u8, usize)>) -> Self {
+        let zones = notes
+            .into_iter()
+            .map(|(note, slot)| Zone {
+                key_low: note,
+                key_high: note,
+                root_note: Some(note),
+                ..Zone::new(slot)
+            })
+            .collect();
+        Self::new(zones)
+    }
+
+    /// Velocity layers as (highest MIDI velocity, slot), in any order.
+    pub fn velocity_layers(layers: impl IntoIterator<Item = (u8, usize)>) -> Self {
+        let mut layers: Vec<_> = layers.into_iter().collect();
+        layers.sort_by_key(|(top, _)| *top);
+
+        let mut low = 0;
+        let mut zones = Vec::with_capacity(layers.len());
+        for (top, slot) in layers {
+            zones.push(Zone {
+                velocity_low: low,
+                velocity_high: top,
+                ..Zone::new(slot)
+            });
+            low = top.saturating_add(1);
+        }
+        Self::new(zones)
+    }
+
+    /// Cycles through `slots`, one per note played on a key.
+    pub fn round_robin(slots: &[usize]) -> Self {
+        let zones = slots
+            .iter()
+            .enumerate()
+            .map(|(index, &slot)| Zone {
+                seq_length: slots.len() as u32,
+                seq_position: index as u32 + 1,
+                ..Zone::new(slot)
+            })
+            .collect();
+        Self::new(zones)
+    }
+}
+
+/// Decimated outline of part of a sample, one min/max pair per column.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WaveformPeaks {
+    pub start_frame: usize,
+    /// Exclusive
+    pub end_frame: usize,
+    /// Playhead in frames, when summarising a playing voice
+    pub position: Option<f64>,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+}
+
+/// One zone's contribution to a note: the sample, already carrying the
+/// zone's root and tuning, plus how to play it.
 #[derive(Debug, Clone)]
-pub enum SampleMapping {
-    ChromaticSingle { slot: usize },
-    MultiSample(HashMap<u8, usize>),
-    Velocity(Vec<(f32, usize)>),
-    RoundRobin { slots: Vec<usize>, current: usize },
+pub struct Layer {
+    pub slot: usize,
+    pub sample: Sample,
+    pub gain: f32,
+    pub pan: f32,
+    pub envelope: Option<EnvelopeShape>,
 }
 
 impl Default for SampleBank {
@@ -47,65 +404,224 @@ impl Default for SampleBank {
 
 impl SampleBank {
     pub fn new() -> Self {
+        Self::with_sample_rate(48000.0)
+    }
+
+    /// A bank for an engine running at `sample_rate`.
+    pub fn with_sample_rate(sample_rate: f32) -> Self {
         Self {
-            samples: HashMap::new(),
-            current_mapping: SampleMapping::ChromaticSingle { slot: 0 },
+            samples: vec![None; MAX_SAMPLE_SLOTS],
+            current_mapping: Arc::new(SampleMapping::default()),
+            key_counts: [0; 128],
+            sample_rate,
+            rate_conversion: RateConversion::default(),
+            root_detection: RootDetection::default(),
         }
     }
 
-    pub fn load_sample(&mut self, slot: usize, path: &str) -> Result<()> {
-        let sample = Self::load_wav(path)?;
-        self.samples.insert(slot, sample);
-        Ok(())
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn rate_conversion(&self) -> RateConversion {
+        self.rate_conversion
     }
 
-    fn load_wav(path: &str) -> Result<Sample> {
-        let path = Path::new(path);
-        if !path.exists() {
-            return Err(anyhow!("File not found: {:?}", path));
+    /// Applies to samples loaded from now on.
+    pub fn set_rate_conversion(&mut self, rate_conversion: RateConversion) {
+        self.rate_conversion = rate_conversion;
+    }
+
+    pub fn root_detection(&self) -> RootDetection {
+        self.root_detection
+    }
+
+    /// Applies to samples loaded from now on.
+    pub fn set_root_detection(&mut self, root_detection: RootDetection) {
+        self.root_detection = root_detection;
+    }
+
+    /// Puts `sample` into `slot` (or clears it), returning what was there.
+    pub fn insert_sample(&mut self, slot: usize, sample: Option<Sample>) -> Result<Option<Sample>> {
+        let entry = self
+            .samples
+            .get_mut(slot)
+            .ok_or_else(|| anyhow!("Sample slot {slot} out of range (max {MAX_SAMPLE_SLOTS})"))?;
+        Ok(std::mem::replace(entry, sample))
+    }
+
+    pub fn get_sample(&self, slot: usize) -> Option<&Sample> {
+        self.samples.get(slot).and_then(Option::as_ref)
+    }
+
+    /// Occupied slots and their samples, in slot order.
+    pub fn samples(&self) -> impl Iterator<Item = (usize, &Sample)> {
+        self.samples
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, sample)| Some((slot, sample.as_ref()?)))
+    }
+
+    pub fn mapping(&self) -> &Arc<SampleMapping> {
+        &self.current_mapping
+    }
+
+    /// Swaps in a new mapping, returning the old one.
+    pub fn set_mapping(&mut self, mapping: Arc<SampleMapping>) -> Arc<SampleMapping> {
+        std::mem::replace(&mut self.current_mapping, mapping)
+    }
+
+    /// Decodes an audio file with the built-in decoders. Use a
+    /// `DecoderRegistry` directly to add formats of your own. The root note
+    /// comes from the file's metadata, else from pitch detection as
+    /// `root_detection` allows.
+    pub fn load_file(path: &str, root_detection: RootDetection) -> Result<Sample, DecodeError> {
+        let mut sample = DecoderRegistry::default().decode_file(path)?;
+        if let (None, RootDetection::MinConfidence(min)) = (sample.root_note, root_detection) {
+            sample.detect_root(min);
         }
+        Ok(sample)
+    }
 
-        let mut reader = hound::WavReader::open(path)?;
-        let spec = reader.spec();
+    /// Fills `layers` with what to play for a note; `velocity` runs from 0
+    /// to 1. Stops at the capacity of `layers` so the audio thread never
+    /// allocates here.
+    pub fn layers_for_note(&mut self, note: u8, velocity: f32, layers: &mut Vec<Layer>) {
+        layers.clear();
+        let key = usize::from(note.min(127));
+        let count = self.key_counts[key];
+        self.key_counts[key] = count.wrapping_add(1);
 
-        // Convert to f32 samples
-        let mut data = Vec::new();
-        match spec.sample_format {
-            hound::SampleFormat::Float => {
-                for sample in reader.samples::<f32>() {
-                    data.push(sample?);
-                }
-            }
-            hound::SampleFormat::Int => {
-                let max_val = (1 << (spec.bits_per_sample - 1)) as f32;
-                for sample in reader.samples::<i32>() {
-                    data.push(sample? as f32 / max_val);
-                }
+        let velocity = (velocity.clamp(0.0, 1.0) * 127.0).round() as u8;
+        let samples = &self.samples;
+        let mapping = self.current_mapping.as_ref();
+        let sample_in = |zone: &Zone| samples.get(zone.slot).and_then(Option::as_ref);
+        let root_of = |zone: &Zone| {
+            zone.root_note
+                .or_else(|| sample_in(zone).and_then(|sample| sample.root_note))
+                .unwrap_or(60)
+        };
+        let playable = mapping
+            .zones
+            .iter()
+            .filter(|zone| zone.matches_velocity(velocity, count) && sample_in(zone).is_some());
+
+        let covers = playable.clone().any(|zone| zone.covers_key(note));
+        let nearest = if covers || !mapping.nearest_root_fallback {
+            None
+        } else {
+            playable
+                .clone()
+                .map(|zone| root_of(zone).abs_diff(note))
+                .min()
+        };
+
+        for zone in playable {
+            let chosen = match nearest {
+                Some(distance) => root_of(zone).abs_diff(note) == distance,
+                None => zone.covers_key(note),
+            };
+            if !chosen || layers.len() == layers.capacity() {
+                continue;
             }
+            let Some(sample) = sample_in(zone) else {
+                continue;
+            };
+
+            let mut sample = sample.clone();
+            sample.root_note = Some(root_of(zone));
+            sample.tune_cents += zone.fine_tune_cents;
+            layers.push(Layer {
+                slot: zone.slot,
+                sample,
+                gain: zone.gain,
+                pan: zone.pan,
+                envelope: zone.envelope,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    const RATE: u32 = 48000;
+
+    /// Writes mono float WAV data to a temporary file named `name`.
+    fn write_wav(name: &str, data: &[f32]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("zimler-{}-{name}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &value in data {
+            writer.write_sample(value).unwrap();
         }
+        writer.finalize().unwrap();
+        path
+    }
 
-        // If stereo, interleave the channels
-        let channels = spec.channels as usize;
-        let sample_rate = spec.sample_rate as f32;
+    /// A second of a decaying sine, like a plucked note.
+    fn sine(frequency: f32) -> Vec<f32> {
+        (0..RATE)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                0.5 * (-2.0 * t).exp() * (TAU * frequency * t).sin()
+            })
+            .collect()
+    }
 
-        Ok(Sample::new(data, sample_rate, channels))
+    fn load(name: &str, data: &[f32], root_detection: RootDetection) -> Sample {
+        let path = write_wav(name, data);
+        let sample = SampleBank::load_file(path.to_str().unwrap(), root_detection).unwrap();
+        std::fs::remove_file(path).unwrap();
+        sample
     }
 
-    pub fn get_sample_for_note(&mut self, note: u8, velocity: f32) -> Option<&Sample> {
-        match &mut self.current_mapping {
-            SampleMapping::ChromaticSingle { slot } => self.samples.get(slot),
-            SampleMapping::MultiSample(map) => {
-                map.get(&note).and_then(|slot| self.samples.get(slot))
-            }
-            SampleMapping::Velocity(layers) => layers
-                .iter()
-                .find(|(thresh, _)| velocity <= *thresh)
-                .and_then(|(_, slot)| self.samples.get(slot)),
-            SampleMapping::RoundRobin { slots, current } => {
-                let slot = slots.get(*current)?;
-                *current = (*current + 1) % slots.len();
-                self.samples.get(slot)
-            }
+    #[test]
+    fn sines_get_their_pitch_as_root() {
+        for (frequency, note) in [(110.0, 45), (261.63, 60), (440.0, 69), (1046.5, 84)] {
+            let sample = load("sine", &sine(frequency), RootDetection::default());
+            assert_eq!(sample.root_note, Some(note), "{frequency} Hz");
+            assert!(sample.tune_cents.abs() < 5.0, "{frequency} Hz");
         }
     }
+
+    #[test]
+    fn detuned_sine_is_tuned_back() {
+        // 440 Hz, 30 cents sharp
+        let sample = load(
+            "sharp",
+            &sine(440.0 * 2f32.powf(0.3 / 12.0)),
+            RootDetection::default(),
+        );
+        assert_eq!(sample.root_note, Some(69));
+        assert!((sample.tune_cents + 30.0).abs() < 3.0);
+    }
+
+    #[test]
+    fn noise_keeps_the_default_root() {
+        // Deterministic white noise from a linear congruential generator
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<f32> = (0..RATE)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        let sample = load("noise", &noise, RootDetection::default());
+        assert_eq!(sample.root_note, None);
+        assert_eq!(sample.tune_cents, 0.0);
+    }
+
+    #[test]
+    fn detection_can_be_turned_off() {
+        let sample = load("off", &sine(440.0), RootDetection::Off);
+        assert_eq!(sample.root_note, None);
+    }
 }
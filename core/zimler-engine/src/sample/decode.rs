@@ -0,0 +1,868 @@
+use crate::{LoopMode, Sample, SampleLoop};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("File not found: {0:?}")]
+    NotFound(PathBuf),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unsupported sample format (extension: {})", .extension.as_deref().unwrap_or("none"))]
+    UnsupportedFormat { extension: Option<String> },
+    #[error("Invalid {format} data: {message}")]
+    Invalid {
+        format: &'static str,
+        message: String,
+    },
+}
+
+impl DecodeError {
+    fn invalid(format: &'static str, message: impl ToString) -> Self {
+        Self::Invalid {
+            format,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Decodes one audio file format into an interleaved f32 `Sample`.
+pub trait SampleDecoder: Send + Sync {
+    /// Short format name used in errors, e.g. "FLAC".
+    fn name(&self) -> &'static str;
+
+    /// Lower-case file extensions this decoder claims.
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Whether the first bytes of a file look like this format.
+    fn sniff(&self, header: &[u8]) -> bool;
+
+    fn decode(&self, data: &[u8]) -> Result<Sample, DecodeError>;
+}
+
+/// Picks a decoder by magic bytes, falling back to the file extension.
+pub struct DecoderRegistry {
+    decoders: Vec<Box<dyn SampleDecoder>>,
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.decoders.push(Box::new(WavDecoder));
+        registry.decoders.push(Box::new(AiffDecoder));
+        registry.decoders.push(Box::new(FlacDecoder));
+        registry.decoders.push(Box::new(VorbisDecoder));
+        registry.decoders.push(Box::new(Mp3Decoder));
+        registry
+    }
+}
+
+impl DecoderRegistry {
+    pub fn empty() -> Self {
+        Self {
+            decoders: Vec::new(),
+        }
+    }
+
+    /// Adds a decoder, taking precedence over those already registered.
+    pub fn register(&mut self, decoder: Box<dyn SampleDecoder>) {
+        self.decoders.insert(0, decoder);
+    }
+
+    pub fn decode_file<P: AsRef<Path>>(&self, path: P) -> Result<Sample, DecodeError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(DecodeError::NotFound(path.to_path_buf()));
+        }
+
+        let data = std::fs::read(path)?;
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let mut sample = self.decode_bytes(&data, extension)?;
+        if let Some(stem) = path.file_stem() {
+            sample.name = stem.to_string_lossy().into();
+        }
+        sample.source = Some(path.into());
+        Ok(sample)
+    }
+
+    pub fn decode_bytes(
+        &self,
+        data: &[u8],
+        extension: Option<&str>,
+    ) -> Result<Sample, DecodeError> {
+        let extension = extension.map(str::to_ascii_lowercase);
+        let header = &data[..data.len().min(16)];
+
+        let decoder = self
+            .decoders
+            .iter()
+            .find(|decoder| decoder.sniff(header))
+            .or_else(|| {
+                let extension = extension.as_deref()?;
+                self.decoders
+                    .iter()
+                    .find(|decoder| decoder.extensions().contains(&extension))
+            })
+            .ok_or(DecodeError::UnsupportedFormat { extension })?;
+
+        decoder.decode(data)
+    }
+}
+
+fn build_sample(
+    format: &'static str,
+    data: Vec<f32>,
+    sample_rate: u32,
+    channels: usize,
+) -> Result<Sample, DecodeError> {
+    if channels == 0 || sample_rate == 0 {
+        return Err(DecodeError::invalid(
+            format,
+            format!("{channels} channels at {sample_rate} Hz"),
+        ));
+    }
+    Ok(Sample::new(data, sample_rate as f32, channels))
+}
+
+pub struct WavDecoder;
+
+impl SampleDecoder for WavDecoder {
+    fn name(&self) -> &'static str {
+        "WAV"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["wav", "wave"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE"
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Sample, DecodeError> {
+        let invalid = |e: hound::Error| DecodeError::invalid(self.name(), e);
+        let mut reader = hound::WavReader::new(Cursor::new(data)).map_err(invalid)?;
+        let spec = reader.spec();
+
+        // Convert to f32 samples
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .samples::<f32>()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid)?,
+            hound::SampleFormat::Int => {
+                let max_val = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|s| s as f32 / max_val))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(invalid)?
+            }
+        };
+
+        let mut sample = build_sample(
+            self.name(),
+            samples,
+            spec.sample_rate,
+            spec.channels as usize,
+        )?;
+        let smpl = riff_chunk(data, b"smpl");
+        sample.set_loop(smpl.and_then(Self::smpl_loop));
+
+        let root = smpl
+            .and_then(Self::smpl_root)
+            .or_else(|| riff_chunk(data, b"inst").and_then(Self::inst_root));
+        if let Some((root_note, tune_cents)) = root {
+            sample.root_note = Some(root_note);
+            sample.tune_cents = tune_cents;
+        }
+        Ok(sample)
+    }
+}
+
+impl WavDecoder {
+    /// Unity note and tuning from a `smpl` chunk. The pitch fraction says
+    /// how far above the note the recording is, so playback tunes it back down.
+    fn smpl_root(body: &[u8]) -> Option<(u8, f32)> {
+        let word = |offset: usize| {
+            body.get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let note = u8::try_from(word(12)?).ok().filter(|note| *note <= 127)?;
+        let fraction = word(16)? as f32 / 4_294_967_296.0;
+        Some((note, -fraction * 100.0))
+    }
+
+    /// Unshifted note and fine tune (cents) from an `inst` chunk.
+    fn inst_root(body: &[u8]) -> Option<(u8, f32)> {
+        let note = *body.first().filter(|note| **note <= 127)?;
+        let fine_tune = *body.get(1)? as i8;
+        Some((note, f32::from(fine_tune)))
+    }
+
+    /// Reads the first loop of a `smpl` chunk. Loop ends there are inclusive.
+    fn smpl_loop(body: &[u8]) -> Option<SampleLoop> {
+        let word = |offset: usize| {
+            body.get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        };
+
+        if word(28)? == 0 {
+            return None;
+        }
+        // Header is 36 bytes; each loop is cue id, type, start, end, fraction, count
+        let mode = match word(40)? {
+            1 => LoopMode::PingPong,
+            2 => LoopMode::Reverse,
+            _ => LoopMode::Forward,
+        };
+        Some(SampleLoop::new(word(44)?, word(48)? + 1, mode))
+    }
+}
+
+/// Finds a top-level chunk in a RIFF file.
+fn riff_chunk<'a>(data: &'a [u8], id: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let body_start = pos + 8;
+        if &data[pos..pos + 4] == id {
+            return data.get(body_start..(body_start + size).min(data.len()));
+        }
+        // Chunks are padded to an even length
+        pos = body_start + size + (size & 1);
+    }
+    None
+}
+
+pub struct AiffDecoder;
+
+impl AiffDecoder {
+    /// Converts the 80-bit IEEE extended float AIFF uses for its sample rate.
+    fn extended_to_f64(bytes: &[u8]) -> f64 {
+        let sign = if bytes[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+        let exponent = i32::from(u16::from_be_bytes([bytes[0] & 0x7F, bytes[1]]));
+        let mut mantissa_bytes = [0u8; 8];
+        mantissa_bytes.copy_from_slice(&bytes[2..10]);
+        let mantissa = u64::from_be_bytes(mantissa_bytes);
+
+        if exponent == 0 && mantissa == 0 {
+            return 0.0;
+        }
+        sign * mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+    }
+}
+
+impl SampleDecoder for AiffDecoder {
+    fn name(&self) -> &'static str {
+        "AIFF"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["aif", "aiff", "aifc"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.len() >= 12
+            && &header[0..4] == b"FORM"
+            && (&header[8..12] == b"AIFF" || &header[8..12] == b"AIFC")
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Sample, DecodeError> {
+        let format = self.name();
+        if !self.sniff(data) {
+            return Err(DecodeError::invalid(format, "missing FORM header"));
+        }
+        let is_aifc = &data[8..12] == b"AIFC";
+
+        let mut channels = 0usize;
+        let mut bits = 0u16;
+        let mut sample_rate = 0.0f64;
+        let mut compression = *b"NONE";
+        let mut sound: Option<&[u8]> = None;
+        // Base note and detune in cents from an INST chunk
+        let mut root: Option<(u8, f32)> = None;
+
+        let mut pos = 12;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let size =
+                u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+                    as usize;
+            let body_start = pos + 8;
+            let body_end = (body_start + size).min(data.len());
+            let body = &data[body_start..body_end];
+
+            match id {
+                b"COMM" if body.len() >= 18 => {
+                    channels = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    bits = u16::from_be_bytes([body[6], body[7]]);
+                    sample_rate = Self::extended_to_f64(&body[8..18]);
+                    if is_aifc && body.len() >= 22 {
+                        compression.copy_from_slice(&body[18..22]);
+                    }
+                }
+                b"SSND" if body.len() >= 8 => {
+                    let offset = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+                    sound = body.get(8 + offset..);
+                }
+                b"INST" if body.len() >= 2 && body[0] <= 127 => {
+                    root = Some((body[0], f32::from(body[1] as i8)));
+                }
+                _ => {}
+            }
+
+            // Chunks are padded to an even length
+            pos = body_start + size + (size & 1);
+        }
+
+        let sound = sound.ok_or_else(|| DecodeError::invalid(format, "missing SSND chunk"))?;
+        if channels == 0 {
+            return Err(DecodeError::invalid(format, "missing COMM chunk"));
+        }
+
+        let data: Vec<f32> = match (&compression, bits) {
+            (b"NONE" | b"twos", 8) => sound.iter().map(|&b| f32::from(b as i8) / 128.0).collect(),
+            (b"NONE" | b"twos", 16) => sound
+                .chunks_exact(2)
+                .map(|b| f32::from(i16::from_be_bytes([b[0], b[1]])) / 32768.0)
+                .collect(),
+            (b"sowt", 16) => sound
+                .chunks_exact(2)
+                .map(|b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32768.0)
+                .collect(),
+            (b"NONE" | b"twos", 24) => sound
+                .chunks_exact(3)
+                .map(|b| (i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8) as f32 / 8_388_608.0)
+                .collect(),
+            (b"sowt", 24) => sound
+                .chunks_exact(3)
+                .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0)
+                .collect(),
+            (b"NONE" | b"twos", 32) => sound
+                .chunks_exact(4)
+                .map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+                .collect(),
+            (b"fl32" | b"FL32", _) => sound
+                .chunks_exact(4)
+                .map(|b| f32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            (b"fl64" | b"FL64", _) => sound
+                .chunks_exact(8)
+                .map(|b| {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(b);
+                    f64::from_be_bytes(bytes) as f32
+                })
+                .collect(),
+            _ => {
+                return Err(DecodeError::invalid(
+                    format,
+                    format!(
+                        "unsupported encoding {} at {bits} bits",
+                        String::from_utf8_lossy(&compression)
+                    ),
+                ))
+            }
+        };
+
+        let mut sample = build_sample(format, data, sample_rate.round() as u32, channels)?;
+        if let Some((root_note, tune_cents)) = root {
+            sample.root_note = Some(root_note);
+            sample.tune_cents = tune_cents;
+        }
+        Ok(sample)
+    }
+}
+
+pub struct FlacDecoder;
+
+impl SampleDecoder for FlacDecoder {
+    fn name(&self) -> &'static str {
+        "FLAC"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["flac"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.starts_with(b"fLaC")
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Sample, DecodeError> {
+        let invalid = |e: claxon::Error| DecodeError::invalid(self.name(), e);
+        let mut reader = claxon::FlacReader::new(Cursor::new(data)).map_err(invalid)?;
+        let info = reader.streaminfo();
+        let max_val = (1_i64 << (info.bits_per_sample - 1)) as f32;
+
+        let data = reader
+            .samples()
+            .map(|sample| sample.map(|s| s as f32 / max_val))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+
+        build_sample(self.name(), data, info.sample_rate, info.channels as usize)
+    }
+}
+
+pub struct VorbisDecoder;
+
+impl SampleDecoder for VorbisDecoder {
+    fn name(&self) -> &'static str {
+        "Ogg Vorbis"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ogg", "oga"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.starts_with(b"OggS")
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Sample, DecodeError> {
+        use lewton::inside_ogg::OggStreamReader;
+        use lewton::samples::InterleavedSamples;
+
+        let invalid = |e: lewton::VorbisError| DecodeError::invalid(self.name(), e);
+        let mut reader = OggStreamReader::new(Cursor::new(data)).map_err(invalid)?;
+
+        let mut samples = Vec::new();
+        while let Some(packet) = reader
+            .read_dec_packet_generic::<InterleavedSamples<f32>>()
+            .map_err(invalid)?
+        {
+            samples.extend_from_slice(&packet.samples);
+        }
+
+        build_sample(
+            self.name(),
+            samples,
+            reader.ident_hdr.audio_sample_rate,
+            reader.ident_hdr.audio_channels as usize,
+        )
+    }
+}
+
+pub struct Mp3Decoder;
+
+impl SampleDecoder for Mp3Decoder {
+    fn name(&self) -> &'static str {
+        "MP3"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["mp3"]
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        // ID3 tag, or a bare MPEG frame sync
+        header.starts_with(b"ID3")
+            || (header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Sample, DecodeError> {
+        let mut decoder = minimp3::Decoder::new(Cursor::new(data));
+        let mut samples = Vec::new();
+        let mut format = None;
+
+        loop {
+            match decoder.next_frame() {
+                Ok(frame) => {
+                    format.get_or_insert((frame.sample_rate, frame.channels));
+                    samples.extend(frame.data.iter().map(|&s| f32::from(s) / 32768.0));
+                }
+                Err(minimp3::Error::SkippedData) => {}
+                Err(minimp3::Error::Eof | minimp3::Error::InsufficientData) => break,
+                Err(minimp3::Error::Io(e)) => return Err(e.into()),
+            }
+        }
+
+        let (sample_rate, channels) =
+            format.ok_or_else(|| DecodeError::invalid(self.name(), "no MPEG frames found"))?;
+        build_sample(self.name(), samples, sample_rate.max(0) as u32, channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A WAV file holding `samples` as `bits`-bit integers.
+    fn wav_bytes(channels: u16, sample_rate: u32, bits: u16, samples: &[i32]) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: bits,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    /// An integer sample rate as an 80-bit IEEE extended float.
+    fn extended(rate: u32) -> [u8; 10] {
+        let shift = rate.leading_zeros();
+        let exponent = 16383 + 31 - shift as u16;
+        let mantissa = u64::from(rate) << (32 + shift);
+        let mut bytes = [0; 10];
+        bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+        bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+        bytes
+    }
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    /// An AIFF (or AIFC, given a compression type) file around `sound`.
+    fn aiff_bytes(
+        channels: u16,
+        sample_rate: u32,
+        bits: u16,
+        compression: Option<&[u8; 4]>,
+        sound: &[u8],
+        inst: Option<(u8, i8)>,
+    ) -> Vec<u8> {
+        let frames = sound.len() / (usize::from(channels) * usize::from(bits / 8));
+        let mut comm = channels.to_be_bytes().to_vec();
+        comm.extend_from_slice(&(frames as u32).to_be_bytes());
+        comm.extend_from_slice(&bits.to_be_bytes());
+        comm.extend_from_slice(&extended(sample_rate));
+        if let Some(compression) = compression {
+            comm.extend_from_slice(compression);
+            comm.extend_from_slice(b"\x00");
+        }
+
+        let mut body = if compression.is_some() {
+            b"AIFC".to_vec()
+        } else {
+            b"AIFF".to_vec()
+        };
+        body.extend(chunk(b"COMM", &comm));
+        if let Some((note, detune)) = inst {
+            let mut inst = vec![note, detune as u8, 0, 127, 1, 127];
+            inst.resize(20, 0);
+            body.extend(chunk(b"INST", &inst));
+        }
+        let mut ssnd = vec![0; 8];
+        ssnd.extend_from_slice(sound);
+        body.extend(chunk(b"SSND", &ssnd));
+
+        let mut out = b"FORM".to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend(body);
+        out
+    }
+
+    fn is_invalid(result: Result<Sample, DecodeError>, expected: &str) -> bool {
+        matches!(result, Err(DecodeError::Invalid { format, .. }) if format == expected)
+    }
+
+    #[test]
+    fn decodes_integer_wav() {
+        let bytes = wav_bytes(2, 44100, 16, &[0, 16384, -32768, 32767, 8192, -8192]);
+        let sample = WavDecoder.decode(&bytes).unwrap();
+        assert_eq!(sample.channels, 2);
+        assert_eq!(sample.sample_rate, 44100.0);
+        assert_eq!(sample.frames(), 3);
+        assert_eq!(
+            &sample.data[..],
+            [0.0, 0.5, -1.0, 32767.0 / 32768.0, 0.25, -0.25]
+        );
+        assert_eq!(sample.sample_loop, None);
+        assert_eq!(sample.root_note, None);
+
+        let sample = WavDecoder
+            .decode(&wav_bytes(1, 96000, 24, &[-4_194_304, 4_194_304]))
+            .unwrap();
+        assert_eq!((sample.channels, sample.sample_rate), (1, 96000.0));
+        assert_eq!(&sample.data[..], [-0.5, 0.5]);
+    }
+
+    #[test]
+    fn decodes_float_wav() {
+        let spec = hound::WavSpec {
+            channels: 3,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for sample in [0.1f32, -0.2, 0.3, 1.5, -1.5, 0.0] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let sample = WavDecoder.decode(cursor.get_ref()).unwrap();
+        assert_eq!((sample.channels, sample.frames()), (3, 2));
+        assert_eq!(&sample.data[..], [0.1, -0.2, 0.3, 1.5, -1.5, 0.0]);
+    }
+
+    #[test]
+    fn truncated_wav_is_an_error() {
+        let bytes = wav_bytes(2, 44100, 16, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        for len in [0, 11, 30, bytes.len() - 3] {
+            assert!(
+                is_invalid(WavDecoder.decode(&bytes[..len]), "WAV"),
+                "{len} bytes"
+            );
+        }
+    }
+
+    /// `wav` with extra RIFF chunks appended.
+    fn with_chunks(mut wav: Vec<u8>, chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        for (id, body) in chunks {
+            wav.extend_from_slice(*id);
+            wav.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            wav.extend_from_slice(body);
+            if body.len() % 2 == 1 {
+                wav.push(0);
+            }
+        }
+        let riff_size = (wav.len() - 8) as u32;
+        wav[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        wav
+    }
+
+    /// A `smpl` chunk with a unity note, pitch fraction and loops of
+    /// (type, start, inclusive end).
+    fn smpl(note: u32, fraction: u32, loops: &[(u32, u32, u32)]) -> Vec<u8> {
+        let mut words = vec![0, 0, 0, note, fraction, 0, 0, loops.len() as u32, 0];
+        for &(kind, start, end) in loops {
+            words.extend([0, kind, start, end, 0, 0]);
+        }
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn decode_wav_with(chunks: &[(&[u8; 4], Vec<u8>)]) -> Sample {
+        let wav = wav_bytes(1, 44100, 16, &[0; 1000]);
+        WavDecoder.decode(&with_chunks(wav, chunks)).unwrap()
+    }
+
+    #[test]
+    fn reads_wav_smpl_loops() {
+        for (kind, mode) in [
+            (0, LoopMode::Forward),
+            (1, LoopMode::PingPong),
+            (2, LoopMode::Reverse),
+            (32, LoopMode::Forward),
+        ] {
+            let sample = decode_wav_with(&[(b"smpl", smpl(60, 0, &[(kind, 100, 199)]))]);
+            // The chunk's inclusive end becomes an exclusive one
+            assert_eq!(
+                sample.sample_loop,
+                Some(SampleLoop::new(100, 200, mode)),
+                "loop type {kind}"
+            );
+        }
+
+        // Only the first loop plays
+        let sample = decode_wav_with(&[(b"smpl", smpl(60, 0, &[(0, 10, 19), (1, 30, 39)]))]);
+        assert_eq!(
+            sample.sample_loop,
+            Some(SampleLoop::new(10, 20, LoopMode::Forward))
+        );
+
+        // Loops past the audio are cut to it, or dropped if nothing is left
+        let sample = decode_wav_with(&[(b"smpl", smpl(60, 0, &[(0, 900, 1999)]))]);
+        assert_eq!(
+            sample.sample_loop,
+            Some(SampleLoop::new(900, 1000, LoopMode::Forward))
+        );
+        let sample = decode_wav_with(&[(b"smpl", smpl(60, 0, &[(0, 1500, 1999)]))]);
+        assert_eq!(sample.sample_loop, None);
+
+        let sample = decode_wav_with(&[(b"smpl", smpl(60, 0, &[]))]);
+        assert_eq!(sample.sample_loop, None);
+    }
+
+    #[test]
+    fn reads_wav_root_from_smpl_or_inst() {
+        // Half a semitone sharp, so it tunes down 50 cents
+        let sample = decode_wav_with(&[(b"smpl", smpl(62, 1 << 31, &[]))]);
+        assert_eq!(sample.root_note, Some(62));
+        assert_eq!(sample.tune_cents, -50.0);
+
+        let inst = vec![64, -7i8 as u8, 0, 0, 127, 1, 127];
+        let sample = decode_wav_with(&[(b"inst", inst.clone())]);
+        assert_eq!(sample.root_note, Some(64));
+        assert_eq!(sample.tune_cents, -7.0);
+        assert_eq!(sample.sample_loop, None);
+
+        // smpl wins over inst, unless its note is out of range
+        let sample = decode_wav_with(&[(b"inst", inst.clone()), (b"smpl", smpl(62, 0, &[]))]);
+        assert_eq!(sample.root_note, Some(62));
+        let sample = decode_wav_with(&[(b"smpl", smpl(200, 0, &[])), (b"inst", inst)]);
+        assert_eq!(sample.root_note, Some(64));
+
+        // Chunks too short to hold what's read are ignored
+        let sample = decode_wav_with(&[(b"smpl", vec![0; 14]), (b"inst", vec![64])]);
+        assert_eq!((sample.root_note, sample.sample_loop), (None, None));
+    }
+
+    #[test]
+    fn decodes_aiff() {
+        let sound: Vec<u8> = [0i16, 16384, -32768, -16384]
+            .iter()
+            .flat_map(|x| x.to_be_bytes())
+            .collect();
+        let sample = AiffDecoder
+            .decode(&aiff_bytes(2, 44100, 16, None, &sound, Some((57, -12))))
+            .unwrap();
+        assert_eq!(sample.channels, 2);
+        assert_eq!(sample.sample_rate, 44100.0);
+        assert_eq!(sample.frames(), 2);
+        assert_eq!(&sample.data[..], [0.0, 0.5, -1.0, -0.5]);
+        assert_eq!(sample.root_note, Some(57));
+        assert_eq!(sample.tune_cents, -12.0);
+
+        // 24-bit big-endian, mono, at an odd rate
+        let sound = [0x40, 0, 0, 0xC0, 0, 0, 0, 0, 1];
+        let sample = AiffDecoder
+            .decode(&aiff_bytes(1, 22050, 24, None, &sound, None))
+            .unwrap();
+        assert_eq!((sample.channels, sample.sample_rate), (1, 22050.0));
+        assert_eq!(&sample.data[..], [0.5, -0.5, 1.0 / 8_388_608.0]);
+        assert_eq!(sample.root_note, None);
+    }
+
+    #[test]
+    fn decodes_aifc_encodings() {
+        let little: Vec<u8> = [16384i16, -16384]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let sample = AiffDecoder
+            .decode(&aiff_bytes(1, 48000, 16, Some(b"sowt"), &little, None))
+            .unwrap();
+        assert_eq!(&sample.data[..], [0.5, -0.5]);
+
+        let float: Vec<u8> = [0.25f32, -0.75]
+            .iter()
+            .flat_map(|x| x.to_be_bytes())
+            .collect();
+        let sample = AiffDecoder
+            .decode(&aiff_bytes(1, 48000, 32, Some(b"fl32"), &float, None))
+            .unwrap();
+        assert_eq!(&sample.data[..], [0.25, -0.75]);
+
+        let compressed = aiff_bytes(1, 48000, 16, Some(b"ulaw"), &[0; 4], None);
+        assert!(is_invalid(AiffDecoder.decode(&compressed), "AIFF"));
+    }
+
+    #[test]
+    fn truncated_aiff_is_an_error() {
+        let bytes = aiff_bytes(1, 44100, 16, None, &[0; 64], None);
+        // Header only, cut inside COMM, and cut before SSND
+        for len in [0, 12, 30, 50] {
+            assert!(
+                is_invalid(AiffDecoder.decode(&bytes[..len]), "AIFF"),
+                "{len} bytes"
+            );
+        }
+        // A cut inside the sound data keeps the whole frames that are there
+        let sample = AiffDecoder.decode(&bytes[..bytes.len() - 7]).unwrap();
+        assert_eq!(sample.frames(), 28);
+    }
+
+    #[test]
+    fn registry_sniffs_before_trusting_the_extension() {
+        let registry = DecoderRegistry::default();
+        let wav = wav_bytes(1, 44100, 16, &[0, 16384]);
+        let aiff = aiff_bytes(1, 22050, 16, None, &[0x40, 0], None);
+
+        // Misnamed files decode as what they are
+        assert_eq!(
+            registry
+                .decode_bytes(&wav, Some("aiff"))
+                .unwrap()
+                .sample_rate,
+            44100.0
+        );
+        assert_eq!(
+            registry
+                .decode_bytes(&aiff, Some("WAV"))
+                .unwrap()
+                .sample_rate,
+            22050.0
+        );
+        assert_eq!(
+            registry.decode_bytes(&aiff, None).unwrap().sample_rate,
+            22050.0
+        );
+
+        // Unrecognised bytes go to the decoder the extension names
+        let garbage = [0x55; 64];
+        assert!(is_invalid(
+            registry.decode_bytes(&garbage, Some("Wav")),
+            "WAV"
+        ));
+        assert!(is_invalid(
+            registry.decode_bytes(&garbage, Some("aif")),
+            "AIFF"
+        ));
+        assert!(matches!(
+            registry.decode_bytes(&garbage, Some("xyz")),
+            Err(DecodeError::UnsupportedFormat { extension: Some(ext) }) if ext == "xyz"
+        ));
+        assert!(matches!(
+            registry.decode_bytes(&[], None),
+            Err(DecodeError::UnsupportedFormat { extension: None })
+        ));
+        assert!(matches!(
+            DecoderRegistry::empty().decode_bytes(&wav, Some("wav")),
+            Err(DecodeError::UnsupportedFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn registered_decoders_take_precedence() {
+        struct Silence;
+        impl SampleDecoder for Silence {
+            fn name(&self) -> &'static str {
+                "Silence"
+            }
+            fn extensions(&self) -> &'static [&'static str] {
+                &["wav"]
+            }
+            fn sniff(&self, header: &[u8]) -> bool {
+                header.starts_with(b"RIFF")
+            }
+            fn decode(&self, _: &[u8]) -> Result<Sample, DecodeError> {
+                Ok(Sample::new(vec![0.0; 4], 1000.0, 1))
+            }
+        }
+
+        let mut registry = DecoderRegistry::default();
+        registry.register(Box::new(Silence));
+        let wav = wav_bytes(1, 44100, 16, &[0, 16384]);
+        assert_eq!(
+            registry.decode_bytes(&wav, None).unwrap().sample_rate,
+            1000.0
+        );
+    }
+
+    #[test]
+    fn missing_file_is_not_found() {
+        let path = std::env::temp_dir().join("zimler-no-such-sample.wav");
+        assert!(matches!(
+            DecoderRegistry::default().decode_file(&path),
+            Err(DecodeError::NotFound(missing)) if missing == path
+        ));
+    }
+}
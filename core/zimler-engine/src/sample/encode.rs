@@ -0,0 +1,406 @@
+use crate::Sample;
+
+// Frames per FLAC block; the reference encoder's default
+const BLOCK_SIZE: usize = 4096;
+
+const BITS_PER_SAMPLE: u32 = 24;
+
+// Largest Rice parameter the 4-bit coding method can signal (15 is an escape)
+const MAX_RICE_PARAMETER: u32 = 14;
+
+// Finest residual partitioning tried per subframe
+const MAX_PARTITION_ORDER: u32 = 6;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("FLAC holds 1 to 8 channels, not {0}")]
+    Channels(usize),
+    #[error("FLAC can't store a sample rate of {0} Hz")]
+    SampleRate(f32),
+    #[error("FLAC holds audio within full scale, but this peaks at {0}")]
+    OverFullScale(f32),
+}
+
+/// Encodes a sample as 24-bit FLAC, using fixed predictors and Rice-coded
+/// residuals. Lossless apart from rounding the floats to 24 bits, so audio
+/// peaking above 0 dBFS is refused rather than clipped; reads back with
+/// `FlacDecoder`. The sample rate is rounded to whole hertz.
+pub fn encode_flac(sample: &Sample) -> Result<Vec<u8>, EncodeError> {
+    let channels = sample.channels;
+    if !(1..=8).contains(&channels) {
+        return Err(EncodeError::Channels(channels));
+    }
+    let rate = sample.sample_rate.round();
+    if !(1.0..=655_350.0).contains(&rate) {
+        return Err(EncodeError::SampleRate(sample.sample_rate));
+    }
+
+    if let Some(&peak) = sample.data.iter().find(|x| !(-1.0..=1.0).contains(*x)) {
+        return Err(EncodeError::OverFullScale(peak));
+    }
+
+    // Full scale positive is one step short of 1.0, so that rounds down
+    let scale = (1 << (BITS_PER_SAMPLE - 1)) as f32;
+    let max = scale - 1.0;
+    let ints: Vec<i32> = sample
+        .data
+        .iter()
+        .map(|&x| (x * scale).round().clamp(-scale, max) as i32)
+        .collect();
+    let frames = ints.len() / channels;
+
+    let mut out = BitWriter::default();
+    out.bytes.extend_from_slice(b"fLaC");
+
+    // STREAMINFO, the only (and so last) metadata block
+    out.write(1, 1);
+    out.write(0, 7);
+    out.write(34, 24);
+    let block_size = BLOCK_SIZE.min(frames.max(16));
+    out.write(block_size as u64, 16);
+    out.write(block_size as u64, 16);
+    out.write(0, 24); // Frame sizes unknown
+    out.write(0, 24);
+    out.write(rate as u64, 20);
+    out.write(channels as u64 - 1, 3);
+    out.write(u64::from(BITS_PER_SAMPLE) - 1, 5);
+    out.write(frames as u64, 36);
+    out.bytes.extend_from_slice(&[0; 16]); // No MD5
+
+    let mut channel = Vec::with_capacity(BLOCK_SIZE);
+    for (number, block) in ints.chunks(BLOCK_SIZE * channels).enumerate() {
+        let start = out.bytes.len();
+        let len = block.len() / channels;
+
+        out.write(0b11_1111_1111_1110, 14);
+        out.write(0, 1);
+        out.write(0, 1); // Fixed block size
+        out.write(0b0111, 4); // Block size follows as 16 bits
+        out.write(0, 4); // Sample rate from STREAMINFO
+        out.write(channels as u64 - 1, 4); // Independent channels
+        out.write(0b110, 3); // 24 bits per sample
+        out.write(0, 1);
+        out.write_utf8(number as u64);
+        out.write(len as u64 - 1, 16);
+        let crc = crc8(&out.bytes[start..]);
+        out.write(u64::from(crc), 8);
+
+        for ch in 0..channels {
+            channel.clear();
+            channel.extend(
+                block
+                    .iter()
+                    .skip(ch)
+                    .step_by(channels)
+                    .map(|&x| i64::from(x)),
+            );
+            write_subframe(&mut out, &channel);
+        }
+
+        out.align();
+        let crc = crc16(&out.bytes[start..]);
+        out.write(u64::from(crc), 16);
+    }
+    Ok(out.bytes)
+}
+
+/// Writes whichever of constant, fixed-predictor or verbatim is smallest.
+fn write_subframe(out: &mut BitWriter, samples: &[i64]) {
+    if samples.iter().all(|&x| x == samples[0]) {
+        out.write(0b0000_0000, 8);
+        out.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * u64::from(BITS_PER_SAMPLE);
+    let best = (0..=4usize)
+        .filter(|&order| order < samples.len())
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let coding = RiceCoding::best(&residual, order, samples.len());
+            let bits = (order as u64 * u64::from(BITS_PER_SAMPLE)).saturating_add(coding.bits);
+            (order, residual, coding, bits)
+        })
+        .min_by_key(|(.., bits)| *bits);
+
+    match best {
+        Some((order, residual, coding, bits)) if bits < verbatim_bits => {
+            out.write((0b00_1000 | order as u64) << 1, 8);
+            for &warmup in &samples[..order] {
+                out.write_signed(warmup, BITS_PER_SAMPLE);
+            }
+            coding.write(out, &residual);
+        }
+        _ => {
+            out.write(0b0000_0010, 8);
+            for &x in samples {
+                out.write_signed(x, BITS_PER_SAMPLE);
+            }
+        }
+    }
+}
+
+/// Prediction error of the fixed polynomial predictor of `order`, for
+/// every sample after the warm-up.
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |back: usize| samples[i - back];
+            let prediction = match order {
+                0 => 0,
+                1 => s(1),
+                2 => 2 * s(1) - s(2),
+                3 => 3 * s(1) - 3 * s(2) + s(3),
+                _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+            };
+            samples[i] - prediction
+        })
+        .collect()
+}
+
+/// Partitioning and per-partition Rice parameters for one residual.
+struct RiceCoding {
+    // Warm-up samples missing from the first partition
+    order: usize,
+    partition_order: u32,
+    parameters: Vec<u32>,
+    bits: u64,
+}
+
+impl RiceCoding {
+    /// Cheapest partition order and parameters for the residual of a
+    /// `block_len` block predicted at `order`.
+    fn best(residual: &[i64], order: usize, block_len: usize) -> Self {
+        let folded: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+        (0..=MAX_PARTITION_ORDER)
+            .take_while(|&p| block_len % (1 << p) == 0 && block_len >> p > order)
+            .map(|partition_order| {
+                let mut coding = Self {
+                    order,
+                    partition_order,
+                    parameters: Vec::new(),
+                    bits: 2 + 4,
+                };
+                for partition in partitions(&folded, partition_order, order) {
+                    let (parameter, bits) = (0..=MAX_RICE_PARAMETER)
+                        .map(|k| {
+                            let unary: u64 = partition.iter().map(|&u| u >> k).sum();
+                            (k, 4 + unary + partition.len() as u64 * (u64::from(k) + 1))
+                        })
+                        .min_by_key(|&(_, bits)| bits)
+                        .unwrap_or((0, 4));
+                    coding.parameters.push(parameter);
+                    coding.bits += bits;
+                }
+                coding
+            })
+            .min_by_key(|coding| coding.bits)
+            .unwrap_or(Self {
+                order,
+                partition_order: 0,
+                parameters: vec![MAX_RICE_PARAMETER],
+                bits: u64::MAX,
+            })
+    }
+
+    fn write(&self, out: &mut BitWriter, residual: &[i64]) {
+        let folded: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+        out.write(0b00, 2); // 4-bit Rice parameters
+        out.write(u64::from(self.partition_order), 4);
+        for (partition, &k) in
+            partitions(&folded, self.partition_order, self.order).zip(&self.parameters)
+        {
+            out.write(u64::from(k), 4);
+            for &u in partition {
+                out.write_unary(u >> k);
+                out.write(u & ((1 << k) - 1), k);
+            }
+        }
+    }
+}
+
+/// Splits a residual into `2^partition_order` equal parts of the block,
+/// the first short by the `order` warm-up samples.
+fn partitions(folded: &[u64], partition_order: u32, order: usize) -> impl Iterator<Item = &[u64]> {
+    let len = (folded.len() + order) >> partition_order;
+    let mut rest = folded;
+    (0..1usize << partition_order).map(move |i| {
+        let take = if i == 0 { len - order } else { len };
+        let (partition, tail) = rest.split_at(take.min(rest.len()));
+        rest = tail;
+        partition
+    })
+}
+
+/// Maps signed residuals to unsigned: 0, -1, 1, -2... become 0, 1, 2, 3...
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Big-endian bit packer.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    /// Appends the low `bits` bits of `value`, at most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xffff_ffff, 32);
+            return;
+        }
+        self.pending = (self.pending << bits) | (value & ((1u64 << bits) - 1));
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1u64 << self.pending_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// `value` zeros then a one.
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// FLAC's UTF-8-style variable-length frame number.
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let continuation = (1..=6).find(|&n| value < 1 << (6 - n + 6 * n)).unwrap_or(6);
+        // One leading 1 per byte in the sequence
+        let marker = (0xff00u64 >> (continuation + 1)) & 0xff;
+        self.write(marker | (value >> (6 * continuation)), 8);
+        for i in (0..continuation).rev() {
+            self.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+        }
+    }
+
+    /// Pads with zeros to the next byte boundary.
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo test tone on the 24-bit grid, so it survives encoding exactly.
+    fn tone(frames: usize) -> Sample {
+        let step = 1.0 / (1 << 23) as f32;
+        let data = (0..frames * 2)
+            .map(|i| {
+                let x = 0.9 * (i as f32 * 0.01).sin() * if i % 2 == 0 { 1.0 } else { -0.5 };
+                (x / step).round() * step
+            })
+            .collect();
+        Sample::new(data, 44100.0, 2)
+    }
+
+    fn decode(bytes: &[u8]) -> (claxon::metadata::StreamInfo, Vec<f32>) {
+        let mut reader = claxon::FlacReader::new(bytes).unwrap();
+        let info = reader.streaminfo();
+        let scale = (1 << (info.bits_per_sample - 1)) as f32;
+        let data = reader
+            .samples()
+            .map(|x| x.unwrap() as f32 / scale)
+            .collect();
+        (info, data)
+    }
+
+    #[test]
+    fn round_trips_through_claxon() {
+        // Several blocks plus a short final one
+        let sample = tone(BLOCK_SIZE * 2 + 100);
+        let (info, data) = decode(&encode_flac(&sample).unwrap());
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.bits_per_sample, BITS_PER_SAMPLE);
+        assert_eq!(info.samples, Some(sample.frames() as u64));
+        assert_eq!(data, &sample.data[..]);
+    }
+
+    #[test]
+    fn round_trips_silence_and_full_scale() {
+        let data = [0.0, 0.0, 0.0, -1.0, 1.0, 0.5, -0.5, 0.0].repeat(8);
+        let sample = Sample::new(data.clone(), 48000.0, 1);
+        let (_, decoded) = decode(&encode_flac(&sample).unwrap());
+        for (x, y) in data.iter().zip(&decoded) {
+            // Only +1.0 is off the grid, by a single step
+            assert!((x - y).abs() <= 1.0 / (1 << 23) as f32);
+        }
+    }
+
+    #[test]
+    fn refuses_audio_over_full_scale() {
+        let sample = Sample::new(vec![0.0, 0.5, 1.25, -0.5], 48000.0, 1);
+        assert!(matches!(
+            encode_flac(&sample),
+            Err(EncodeError::OverFullScale(peak)) if peak == 1.25
+        ));
+        let sample = Sample::new(vec![0.0, f32::NAN], 48000.0, 1);
+        assert!(matches!(
+            encode_flac(&sample),
+            Err(EncodeError::OverFullScale(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_what_flac_cannot_hold() {
+        let sample = Sample::new(vec![0.0; 9 * 16], 48000.0, 9);
+        assert!(matches!(
+            encode_flac(&sample),
+            Err(EncodeError::Channels(9))
+        ));
+        let sample = Sample::new(vec![0.0; 16], 0.0, 1);
+        assert!(matches!(
+            encode_flac(&sample),
+            Err(EncodeError::SampleRate(_))
+        ));
+    }
+}
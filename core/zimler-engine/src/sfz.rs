@@ -0,0 +1,832 @@
+use crate::{
+    Curve, DecoderRegistry, EnvelopeShape, LoopMode, RateConversion, Sample, SampleLoop,
+    SampleMapping, Zone, MAX_SAMPLE_SLOTS,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Something in an SFZ file that was skipped or only partly honoured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfzWarning {
+    /// 1-based line in the SFZ source
+    pub line: usize,
+    pub kind: SfzWarningKind,
+}
+
+impl fmt::Display for SfzWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SfzWarningKind {
+    #[error("unsupported header <{0}>")]
+    UnsupportedHeader(String),
+    #[error("unsupported opcode {opcode}={value}")]
+    UnsupportedOpcode { opcode: String, value: String },
+    #[error("invalid value for {opcode}: {value}")]
+    InvalidValue { opcode: String, value: String },
+    #[error("{opcode}={value} is played as {fallback}")]
+    Approximated {
+        opcode: String,
+        value: String,
+        fallback: &'static str,
+    },
+    #[error("unsupported directive {0}")]
+    UnsupportedDirective(String),
+    #[error("region has no sample")]
+    MissingSample,
+    #[error("loop requested but no loop points given")]
+    MissingLoopPoints,
+    #[error("cannot load {path:?}: {message}")]
+    SampleLoad { path: PathBuf, message: String },
+    #[error("region skipped, all {MAX_SAMPLE_SLOTS} sample slots are in use")]
+    TooManyRegions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SfzLoopMode {
+    NoLoop,
+    OneShot,
+    LoopContinuous,
+    LoopSustain,
+}
+
+/// A `<region>` with everything inherited from its headers resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfzRegion {
+    /// As written in the file, relative to `default_path`
+    pub sample: Option<String>,
+    pub lokey: u8,
+    pub hikey: u8,
+    pub pitch_keycenter: u8,
+    pub lovel: u8,
+    pub hivel: u8,
+    pub tune_cents: f32,
+    pub transpose: i32,
+    pub volume_db: f32,
+    /// -100 (left) to 100 (right)
+    pub pan: f32,
+    pub loop_mode: Option<SfzLoopMode>,
+    pub loop_start: Option<usize>,
+    /// Inclusive, as in SFZ
+    pub loop_end: Option<usize>,
+    /// Seconds, or percent for sustain
+    pub ampeg_attack: Option<f32>,
+    pub ampeg_decay: Option<f32>,
+    pub ampeg_sustain: Option<f32>,
+    pub ampeg_release: Option<f32>,
+    pub seq_length: u32,
+    pub seq_position: u32,
+    /// Line of the `<region>` header
+    pub line: usize,
+}
+
+impl Default for SfzRegion {
+    fn default() -> Self {
+        Self {
+            sample: None,
+            lokey: 0,
+            hikey: 127,
+            pitch_keycenter: 60,
+            lovel: 0,
+            hivel: 127,
+            tune_cents: 0.0,
+            transpose: 0,
+            volume_db: 0.0,
+            pan: 0.0,
+            loop_mode: None,
+            loop_start: None,
+            loop_end: None,
+            ampeg_attack: None,
+            ampeg_decay: None,
+            ampeg_sustain: None,
+            ampeg_release: None,
+            seq_length: 1,
+            seq_position: 1,
+            line: 0,
+        }
+    }
+}
+
+impl SfzRegion {
+    /// The region's amp envelope, or `None` to use the engine's.
+    pub fn envelope(&self) -> Option<EnvelopeShape> {
+        if self.ampeg_attack.is_none()
+            && self.ampeg_decay.is_none()
+            && self.ampeg_sustain.is_none()
+            && self.ampeg_release.is_none()
+        {
+            return None;
+        }
+        // SFZ defaults for whatever isn't given
+        Some(EnvelopeShape::ADSR {
+            attack_ms: self.ampeg_attack.unwrap_or(0.0) * 1000.0,
+            decay_ms: self.ampeg_decay.unwrap_or(0.0) * 1000.0,
+            sustain: self.ampeg_sustain.unwrap_or(100.0) / 100.0,
+            release_ms: self.ampeg_release.unwrap_or(0.001) * 1000.0,
+            attack_curve: Curve::LINEAR,
+            decay_curve: Curve::LINEAR,
+            release_curve: Curve::LINEAR,
+        })
+    }
+
+    fn apply(&mut self, opcode: &Opcode) {
+        match opcode {
+            Opcode::Sample(path) => self.sample = Some(path.clone()),
+            Opcode::LoKey(key) => self.lokey = *key,
+            Opcode::HiKey(key) => self.hikey = *key,
+            Opcode::Key(key) => {
+                self.lokey = *key;
+                self.hikey = *key;
+                self.pitch_keycenter = *key;
+            }
+            Opcode::PitchKeycenter(key) => self.pitch_keycenter = *key,
+            Opcode::LoVel(vel) => self.lovel = *vel,
+            Opcode::HiVel(vel) => self.hivel = *vel,
+            Opcode::Tune(cents) => self.tune_cents = *cents,
+            Opcode::Transpose(semitones) => self.transpose = *semitones,
+            Opcode::Volume(db) => self.volume_db = *db,
+            Opcode::Pan(pan) => self.pan = *pan,
+            Opcode::LoopMode(mode) => self.loop_mode = Some(*mode),
+            Opcode::LoopStart(frame) => self.loop_start = Some(*frame),
+            Opcode::LoopEnd(frame) => self.loop_end = Some(*frame),
+            Opcode::AmpegAttack(secs) => self.ampeg_attack = Some(*secs),
+            Opcode::AmpegDecay(secs) => self.ampeg_decay = Some(*secs),
+            Opcode::AmpegSustain(percent) => self.ampeg_sustain = Some(*percent),
+            Opcode::AmpegRelease(secs) => self.ampeg_release = Some(*secs),
+            Opcode::SeqLength(length) => self.seq_length = *length,
+            Opcode::SeqPosition(position) => self.seq_position = *position,
+            Opcode::DefaultPath(_) => {}
+        }
+    }
+}
+
+/// An opcode we understand, with its value already parsed.
+#[derive(Debug, Clone, PartialEq)]
+enum Opcode {
+    Sample(String),
+    LoKey(u8),
+    HiKey(u8),
+    Key(u8),
+    PitchKeycenter(u8),
+    LoVel(u8),
+    HiVel(u8),
+    Tune(f32),
+    Transpose(i32),
+    Volume(f32),
+    Pan(f32),
+    LoopMode(SfzLoopMode),
+    LoopStart(usize),
+    LoopEnd(usize),
+    AmpegAttack(f32),
+    AmpegDecay(f32),
+    AmpegSustain(f32),
+    AmpegRelease(f32),
+    SeqLength(u32),
+    SeqPosition(u32),
+    DefaultPath(String),
+}
+
+impl Opcode {
+    fn parse(name: &str, value: &str) -> Result<Self, SfzWarningKind> {
+        let invalid = || SfzWarningKind::InvalidValue {
+            opcode: name.to_string(),
+            value: value.to_string(),
+        };
+        let key = || parse_key(value).ok_or_else(invalid);
+        let velocity = || {
+            value
+                .parse::<u8>()
+                .ok()
+                .filter(|v| *v <= 127)
+                .ok_or_else(invalid)
+        };
+        let number = || {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or_else(invalid)
+        };
+        let seconds = || number().map(|secs| secs.max(0.0));
+
+        Ok(match name {
+            "sample" => Self::Sample(value.to_string()),
+            "lokey" => Self::LoKey(key()?),
+            "hikey" => Self::HiKey(key()?),
+            "key" => Self::Key(key()?),
+            "pitch_keycenter" => Self::PitchKeycenter(key()?),
+            "lovel" => Self::LoVel(velocity()?),
+            "hivel" => Self::HiVel(velocity()?),
+            "tune" => Self::Tune(number()?),
+            "transpose" => Self::Transpose(value.parse().map_err(|_| invalid())?),
+            "volume" => Self::Volume(number()?),
+            "pan" => Self::Pan(number()?.clamp(-100.0, 100.0)),
+            "loop_mode" | "loopmode" => Self::LoopMode(match value {
+                "no_loop" => SfzLoopMode::NoLoop,
+                "one_shot" => SfzLoopMode::OneShot,
+                "loop_continuous" => SfzLoopMode::LoopContinuous,
+                "loop_sustain" => SfzLoopMode::LoopSustain,
+                _ => return Err(invalid()),
+            }),
+            "loop_start" | "loopstart" => Self::LoopStart(value.parse().map_err(|_| invalid())?),
+            "loop_end" | "loopend" => Self::LoopEnd(value.parse().map_err(|_| invalid())?),
+            "ampeg_attack" => Self::AmpegAttack(seconds()?),
+            "ampeg_decay" => Self::AmpegDecay(seconds()?),
+            "ampeg_sustain" => Self::AmpegSustain(number()?.clamp(0.0, 100.0)),
+            "ampeg_release" => Self::AmpegRelease(seconds()?),
+            "seq_length" => {
+                Self::SeqLength(value.parse().ok().filter(|l| *l > 0).ok_or_else(invalid)?)
+            }
+            "seq_position" => {
+                Self::SeqPosition(value.parse().ok().filter(|p| *p > 0).ok_or_else(invalid)?)
+            }
+            "default_path" => Self::DefaultPath(value.to_string()),
+            _ => {
+                return Err(SfzWarningKind::UnsupportedOpcode {
+                    opcode: name.to_string(),
+                    value: value.to_string(),
+                })
+            }
+        })
+    }
+}
+
+/// Header whose opcodes are currently being collected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+    // An unsupported header; its opcodes are skipped
+    Ignored,
+}
+
+/// A parsed SFZ instrument. Parsing never fails; anything that can't be
+/// used ends up in `warnings`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SfzInstrument {
+    pub regions: Vec<SfzRegion>,
+    pub warnings: Vec<SfzWarning>,
+    /// From `<control> default_path`, prefixed to every sample path
+    pub default_path: String,
+    /// Directory sample paths are relative to, normally the SFZ file's
+    pub base_dir: PathBuf,
+}
+
+/// Sample slots and mapping built from an `SfzInstrument`, ready to install
+/// into a bank. Slot numbers count from 0 in `samples` order.
+pub struct SfzBuild {
+    pub samples: Vec<Sample>,
+    pub mapping: SampleMapping,
+    pub warnings: Vec<SfzWarning>,
+}
+
+impl SfzInstrument {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        let mut instrument = Self::parse(&source);
+        instrument.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(instrument)
+    }
+
+    pub fn parse(source: &str) -> Self {
+        let mut instrument = Self::default();
+        let mut scope = Scope::Control;
+        // Opcodes in force from each enclosing header
+        let mut control: Vec<Opcode> = Vec::new();
+        let mut global: Vec<Opcode> = Vec::new();
+        let mut master: Vec<Opcode> = Vec::new();
+        let mut group: Vec<Opcode> = Vec::new();
+        let mut region: Option<(usize, Vec<Opcode>)> = None;
+
+        let finish_region = |instrument: &mut Self,
+                             region: &mut Option<(usize, Vec<Opcode>)>,
+                             inherited: [&[Opcode]; 4]| {
+            let Some((line, opcodes)) = region.take() else {
+                return;
+            };
+            let mut resolved = SfzRegion {
+                line,
+                ..SfzRegion::default()
+            };
+            for opcode in inherited.into_iter().flatten().chain(&opcodes) {
+                resolved.apply(opcode);
+            }
+            instrument.regions.push(resolved);
+        };
+
+        let mut in_block_comment = false;
+        for (index, raw_line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let line = strip_comments(raw_line, &mut in_block_comment);
+            let line = line.trim();
+
+            if line.starts_with('#') {
+                instrument.warn(
+                    line_number,
+                    SfzWarningKind::UnsupportedDirective(line.to_string()),
+                );
+                continue;
+            }
+
+            let mut rest = line;
+            while !rest.is_empty() {
+                let (text, header) = match rest.find('<') {
+                    Some(start) => {
+                        let end = rest[start..]
+                            .find('>')
+                            .map_or(rest.len(), |end| start + end);
+                        let header = rest[start + 1..end].trim();
+                        let text = &rest[..start];
+                        rest = rest.get(end + 1..).unwrap_or("");
+                        (text, Some(header))
+                    }
+                    None => (std::mem::take(&mut rest), None),
+                };
+
+                for (name, value) in split_opcodes(text) {
+                    if scope == Scope::Ignored {
+                        continue;
+                    }
+                    let opcode = match Opcode::parse(name, &value) {
+                        Ok(opcode) => opcode,
+                        Err(kind) => {
+                            instrument.warn(line_number, kind);
+                            continue;
+                        }
+                    };
+                    match scope {
+                        Scope::Control => {
+                            if let Opcode::DefaultPath(path) = &opcode {
+                                instrument.default_path.clone_from(path);
+                            } else {
+                                control.push(opcode);
+                            }
+                        }
+                        Scope::Global => global.push(opcode),
+                        Scope::Master => master.push(opcode),
+                        Scope::Group => group.push(opcode),
+                        Scope::Region => {
+                            if let Some((_, opcodes)) = &mut region {
+                                opcodes.push(opcode);
+                            }
+                        }
+                        Scope::Ignored => {}
+                    }
+                }
+
+                let Some(header) = header else {
+                    continue;
+                };
+                finish_region(
+                    &mut instrument,
+                    &mut region,
+                    [&control, &global, &master, &group],
+                );
+                scope = match header {
+                    "control" => {
+                        control.clear();
+                        Scope::Control
+                    }
+                    "global" => {
+                        global.clear();
+                        master.clear();
+                        group.clear();
+                        Scope::Global
+                    }
+                    "master" => {
+                        master.clear();
+                        group.clear();
+                        Scope::Master
+                    }
+                    "group" => {
+                        group.clear();
+                        Scope::Group
+                    }
+                    "region" => {
+                        region = Some((line_number, Vec::new()));
+                        Scope::Region
+                    }
+                    other => {
+                        instrument.warn(
+                            line_number,
+                            SfzWarningKind::UnsupportedHeader(other.to_string()),
+                        );
+                        Scope::Ignored
+                    }
+                };
+            }
+        }
+        finish_region(
+            &mut instrument,
+            &mut region,
+            [&control, &global, &master, &group],
+        );
+
+        instrument
+    }
+
+    /// Where a region's sample file lives on disk.
+    pub fn sample_path(&self, sample: &str) -> PathBuf {
+        // SFZ files written on Windows use backslashes
+        let relative = format!("{}{}", self.default_path, sample).replace('\\', "/");
+        self.base_dir.join(relative)
+    }
+
+    /// Decodes every sample and builds a `Zone` per SFZ region. Regions
+    /// playing the same file with the same loop share a slot.
+    pub fn build(&self, rate_conversion: RateConversion, engine_rate: f32) -> SfzBuild {
+        let registry = DecoderRegistry::default();
+        // Prepared sample plus the rate its loop points were written for
+        let mut decoded: HashMap<PathBuf, Option<(Sample, f32)>> = HashMap::new();
+        let mut warnings = self.warnings.clone();
+        let mut samples: Vec<Sample> = Vec::new();
+        let mut slots: HashMap<(PathBuf, Option<SampleLoop>), usize> = HashMap::new();
+        let mut zones = Vec::new();
+
+        for sfz_region in &self.regions {
+            let line = sfz_region.line;
+            let Some(sample_name) = &sfz_region.sample else {
+                warnings.push(SfzWarning {
+                    line,
+                    kind: SfzWarningKind::MissingSample,
+                });
+                continue;
+            };
+            let path = self.sample_path(sample_name);
+            let entry = decoded.entry(path.clone()).or_insert_with(|| {
+                let load = || -> Result<(Sample, f32), String> {
+                    let mut sample = registry.decode_file(&path).map_err(|e| e.to_string())?;
+                    // Tuning comes from the opcodes, not the file's metadata
+                    sample.tune_cents = 0.0;
+                    let source_rate = sample.sample_rate;
+                    let sample = rate_conversion
+                        .prepare(sample, engine_rate)
+                        .map_err(|e| e.to_string())?;
+                    Ok((sample, source_rate))
+                };
+                load()
+                    .map_err(|message| {
+                        warnings.push(SfzWarning {
+                            line,
+                            kind: SfzWarningKind::SampleLoad {
+                                path: path.clone(),
+                                message,
+                            },
+                        });
+                    })
+                    .ok()
+            });
+            let Some((sample, source_rate)) = entry else {
+                continue;
+            };
+
+            let (sample_loop, warning) = Self::region_loop(sfz_region, sample, *source_rate);
+            warnings.extend(warning.map(|kind| SfzWarning { line, kind }));
+
+            let slot = match slots.get(&(path.clone(), sample_loop)) {
+                Some(slot) => *slot,
+                None if samples.len() >= MAX_SAMPLE_SLOTS => {
+                    warnings.push(SfzWarning {
+                        line,
+                        kind: SfzWarningKind::TooManyRegions,
+                    });
+                    continue;
+                }
+                None => {
+                    let mut sample = sample.clone();
+                    sample.set_loop(sample_loop);
+                    samples.push(sample);
+                    slots.insert((path, sample_loop), samples.len() - 1);
+                    samples.len() - 1
+                }
+            };
+
+            zones.push(Zone {
+                slot,
+                key_low: sfz_region.lokey,
+                key_high: sfz_region.hikey,
+                velocity_low: sfz_region.lovel,
+                velocity_high: sfz_region.hivel,
+                root_note: Some(sfz_region.pitch_keycenter),
+                fine_tune_cents: sfz_region.tune_cents + sfz_region.transpose as f32 * 100.0,
+                gain: 10f32.powf(sfz_region.volume_db / 20.0),
+                pan: sfz_region.pan / 100.0,
+                seq_length: sfz_region.seq_length,
+                seq_position: sfz_region.seq_position,
+                envelope: sfz_region.envelope(),
+            });
+        }
+
+        SfzBuild {
+            samples,
+            mapping: SampleMapping {
+                zones,
+                // SFZ leaves unmapped keys silent
+                nearest_root_fallback: false,
+            },
+            warnings,
+        }
+    }
+
+    /// Works out a region's loop, and anything about it worth a warning.
+    /// Without `loop_mode` the file's own loop plays, as SFZ players do.
+    fn region_loop(
+        region: &SfzRegion,
+        sample: &Sample,
+        source_rate: f32,
+    ) -> (Option<SampleLoop>, Option<SfzWarningKind>) {
+        // Opcode loop points count source frames; the audio may have been resampled
+        let scale = f64::from(sample.sample_rate) / f64::from(source_rate);
+        let to_frames = |frame: usize| (frame as f64 * scale).round() as usize;
+        let opcode_loop = match (region.loop_start, region.loop_end) {
+            (Some(start), Some(end)) => Some(SampleLoop::new(
+                to_frames(start),
+                to_frames(end + 1),
+                LoopMode::Forward,
+            )),
+            _ => None,
+        };
+        let points = opcode_loop.or(sample.sample_loop);
+
+        let sustain = match region.loop_mode {
+            None => return (points, None),
+            Some(SfzLoopMode::NoLoop) => return (None, None),
+            Some(SfzLoopMode::OneShot) => {
+                let warning = SfzWarningKind::Approximated {
+                    opcode: "loop_mode".to_string(),
+                    value: "one_shot".to_string(),
+                    fallback: "no_loop",
+                };
+                return (None, Some(warning));
+            }
+            Some(SfzLoopMode::LoopContinuous) => false,
+            Some(SfzLoopMode::LoopSustain) => true,
+        };
+        match points {
+            Some(sample_loop) => (
+                Some(SampleLoop {
+                    sustain,
+                    ..sample_loop
+                }),
+                None,
+            ),
+            None => (None, Some(SfzWarningKind::MissingLoopPoints)),
+        }
+    }
+
+    fn warn(&mut self, line: usize, kind: SfzWarningKind) {
+        self.warnings.push(SfzWarning { line, kind });
+    }
+}
+
+/// Drops `//` and `/* */` comments, tracking block comments across lines.
+fn strip_comments(line: &str, in_block_comment: &mut bool) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    loop {
+        if *in_block_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    rest = &rest[end + 2..];
+                    *in_block_comment = false;
+                }
+                None => return out,
+            }
+        }
+        let line_comment = rest.find("//");
+        let block_comment = rest.find("/*");
+        match (line_comment, block_comment) {
+            (Some(line), Some(block)) if line < block => {
+                out.push_str(&rest[..line]);
+                return out;
+            }
+            (_, Some(block)) => {
+                out.push_str(&rest[..block]);
+                out.push(' ');
+                rest = &rest[block + 2..];
+                *in_block_comment = true;
+            }
+            (Some(line), None) => {
+                out.push_str(&rest[..line]);
+                return out;
+            }
+            (None, None) => {
+                out.push_str(rest);
+                return out;
+            }
+        }
+    }
+}
+
+/// Splits `name=value` pairs. Values may contain spaces (sample paths often
+/// do), so a value runs until the next word that looks like `name=`.
+fn split_opcodes(text: &str) -> Vec<(&str, String)> {
+    let mut opcodes: Vec<(&str, String)> = Vec::new();
+    for word in text.split_whitespace() {
+        match word.split_once('=') {
+            Some((name, value))
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                opcodes.push((name, value.to_string()));
+            }
+            _ => {
+                if let Some((_, value)) = opcodes.last_mut() {
+                    value.push(' ');
+                    value.push_str(word);
+                }
+            }
+        }
+    }
+    opcodes
+}
+
+/// Parses a MIDI note number or a note name like `c4`, `f#3` or `eb-1`
+/// (middle C is `c4`).
+fn parse_key(value: &str) -> Option<u8> {
+    if let Ok(number) = value.parse::<u8>() {
+        return (number <= 127).then_some(number);
+    }
+
+    let value = value.to_ascii_lowercase();
+    let mut chars = value.chars();
+    let semitone: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next()? {
+        '#' => (1, &rest[1..]),
+        'b' if rest.len() > 1 => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let note = (octave + 1) * 12 + semitone + accidental;
+    u8::try_from(note).ok().filter(|note| *note <= 127)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_inherit_from_every_header() {
+        let instrument = SfzInstrument::parse(
+            "<control> default_path=samples/ volume=-6
+             <global> pan=-50 tune=10
+             <master> transpose=1
+             <group> lokey=40 hikey=50 tune=20
+             <region> sample=a.wav
+             <region> sample=b.wav tune=-5 pan=25",
+        );
+        assert!(instrument.warnings.is_empty(), "{:?}", instrument.warnings);
+        assert_eq!(instrument.default_path, "samples/");
+
+        let [a, b] = &instrument.regions[..] else {
+            panic!("expected two regions, got {:?}", instrument.regions);
+        };
+        assert_eq!(a.sample.as_deref(), Some("a.wav"));
+        assert_eq!((a.volume_db, a.pan, a.transpose), (-6.0, -50.0, 1));
+        assert_eq!((a.lokey, a.hikey, a.tune_cents), (40, 50, 20.0));
+        assert_eq!((b.tune_cents, b.pan), (-5.0, 25.0));
+        assert_eq!((a.line, b.line), (5, 6));
+    }
+
+    #[test]
+    fn headers_reset_only_their_own_level() {
+        let instrument = SfzInstrument::parse(
+            "<control> volume=-6
+             <global> pan=-50
+             <group> lokey=40
+             <region> sample=a.wav
+             <group> hikey=50
+             <region> sample=b.wav
+             <global>
+             <region> sample=c.wav",
+        );
+        let [a, b, c] = &instrument.regions[..] else {
+            panic!("expected three regions");
+        };
+        assert_eq!((a.lokey, a.hikey), (40, 127));
+        // A new group drops the last group's opcodes, but not the global's
+        assert_eq!((b.lokey, b.hikey, b.pan), (0, 50, -50.0));
+        // A new global drops everything under it, but control opcodes stay
+        assert_eq!((c.lokey, c.hikey, c.pan, c.volume_db), (0, 127, 0.0, -6.0));
+    }
+
+    #[test]
+    fn default_path_prefixes_sample_paths() {
+        let mut instrument = SfzInstrument::parse(
+            "<control> default_path=..\\Samples\\Piano\\
+             <region> sample=Grand C4.wav",
+        );
+        instrument.base_dir = PathBuf::from("/instruments/piano");
+        let sample = instrument.regions[0].sample.as_deref().unwrap();
+        // Spaces stay part of the value; backslashes become separators
+        assert_eq!(sample, "Grand C4.wav");
+        assert_eq!(
+            instrument.sample_path(sample),
+            PathBuf::from("/instruments/piano/../Samples/Piano/Grand C4.wav")
+        );
+    }
+
+    #[test]
+    fn keys_parse_as_numbers_or_note_names() {
+        for (value, note) in [
+            ("60", Some(60)),
+            ("127", Some(127)),
+            ("128", None),
+            ("c4", Some(60)),
+            ("C4", Some(60)),
+            ("c#4", Some(61)),
+            ("db4", Some(61)),
+            ("f#3", Some(54)),
+            ("b3", Some(59)),
+            ("eb-1", Some(3)),
+            ("c-1", Some(0)),
+            ("cb-1", None),
+            ("g9", Some(127)),
+            ("g#9", None),
+            ("h4", None),
+            ("c", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_key(value), note, "{value:?}");
+        }
+
+        let instrument = SfzInstrument::parse("<region> sample=a.wav key=a#3");
+        let region = &instrument.regions[0];
+        assert_eq!(
+            (region.lokey, region.hikey, region.pitch_keycenter),
+            (58, 58, 58)
+        );
+    }
+
+    #[test]
+    fn skips_what_it_cannot_use_with_warnings() {
+        let instrument = SfzInstrument::parse(
+            "// A comment
+             #define $KEY 60
+             <curve> v000=0 v127=1
+             <region> sample=a.wav lokey=q9 fil_type=lpf_2p /* block
+             comment */ hivel=200 volume=-3",
+        );
+        let warnings: Vec<_> = instrument
+            .warnings
+            .iter()
+            .map(|warning| (warning.line, warning.kind.clone()))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (
+                    2,
+                    SfzWarningKind::UnsupportedDirective("#define $KEY 60".to_string())
+                ),
+                (3, SfzWarningKind::UnsupportedHeader("curve".to_string())),
+                (
+                    4,
+                    SfzWarningKind::InvalidValue {
+                        opcode: "lokey".to_string(),
+                        value: "q9".to_string(),
+                    }
+                ),
+                (
+                    4,
+                    SfzWarningKind::UnsupportedOpcode {
+                        opcode: "fil_type".to_string(),
+                        value: "lpf_2p".to_string(),
+                    }
+                ),
+                (
+                    5,
+                    SfzWarningKind::InvalidValue {
+                        opcode: "hivel".to_string(),
+                        value: "200".to_string(),
+                    }
+                ),
+            ]
+        );
+        // Opcodes under the unsupported <curve> aren't warned about, and
+        // the region keeps everything that did parse
+        let region = &instrument.regions[0];
+        assert_eq!(
+            (region.lokey, region.hivel, region.volume_db),
+            (0, 127, -3.0)
+        );
+    }
+}
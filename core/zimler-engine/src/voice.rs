@@ -1,4 +1,22 @@
-use crate::{Envelope, Sample};
+use crate::{
+    Envelope, EnvelopeShape, LoopMode, Modulation, Modulator, RetriggerMode, Sample, SampleLoop,
+    VoiceInfo,
+};
+use std::f32::consts::{FRAC_PI_4, SQRT_2, TAU};
+use std::sync::Arc;
+use zimler_dsp::{FilterMode, Interpolator, SergeFilter, SmoothedParam, DEFAULT_RAMP_MS};
+
+// Fade applied to a stolen voice before its new note starts, to avoid a click
+const STEAL_FADE_MS: f32 = 2.0;
+
+// Vibrato speed under the mod wheel
+const VIBRATO_HZ: f32 = 5.5;
+
+// Sample channels a voice reads and filters; any beyond are dropped
+const MAX_CHANNELS: usize = 8;
+
+// Frames of envelope computed at once
+const ENVELOPE_BLOCK: usize = 64;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VoiceState {
@@ -11,47 +29,370 @@ pub struct Voice {
     state: VoiceState,
     sample: Option<Sample>,
     position: f64,
+    // 1 playing forwards, -1 backwards through a loop
+    direction: f64,
     pitch_ratio: f64,
+    interpolator: Interpolator,
+    // Pitch ratio from the note alone, before pitch bend
+    base_ratio: f64,
     envelope: Envelope,
-    #[allow(dead_code)]
     sample_rate: f32,
     note: Option<u8>,
     velocity: f32,
+    // Fraction of the sample skipped at the start / trimmed from the end
+    start_offset: f32,
+    end_offset: SmoothedParam,
+    pitch_bend: f32,
+    pitch_bend_range: f32,
+    bend_semitones: SmoothedParam,
+    // Semitones from the note's pitch, closing to 0 as a glide ends
+    glide: SmoothedParam,
+    glide_ms: f32,
+    vibrato: Vibrato,
+    modulator: Modulator,
+    // Pitch offset the modulator last applied
+    mod_semitones: f32,
+    // Engine-wide trigger count when this voice last started a note
+    trigger_order: u64,
+    // Bank slot the note came from, for display
+    slot: Option<usize>,
+    // Note waiting for the steal fade to finish
+    pending: Option<PendingNote>,
+    pending_release: bool,
+    fade_samples: u32,
+    fade_remaining: u32,
+    filter: VoiceFilter,
+    panner: Panner,
+    // Interleaved channels of the buffer the voice renders into
+    output_channels: usize,
+}
+
+/// Settings from the zone a note plays. They take effect as the note
+/// starts, so a voice fading out for a steal keeps its own until then.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteSetup {
+    /// Bank slot the note plays, for display
+    pub slot: Option<usize>,
+    /// -1 (left) to 1 (right)
+    pub pan: f32,
+    pub envelope: EnvelopeShape,
+}
+
+struct PendingNote {
+    note: u8,
+    velocity: f32,
+    sample: Sample,
+    setup: NoteSetup,
+}
+
+/// One filter per sample channel sharing smoothed cutoff and resonance,
+/// bypassed when there is no mode.
+struct VoiceFilter {
+    // Holds the cutoff and resonance; the channels' filters are tuned by
+    // its coefficients and only keep their own state
+    tuning: SergeFilter,
+    filters: [SergeFilter; MAX_CHANNELS],
+    mode: Option<FilterMode>,
+    // Smoothed in octaves (log2 Hz) so sweeps sound even
+    cutoff: SmoothedParam,
+    // Octaves of modulation on top of the cutoff
+    cutoff_offset: f32,
+    resonance: SmoothedParam,
+}
+
+/// Places a voice's channels in the output: pan, stereo width and up- or
+/// down-mixing to the output's channel count.
+struct Panner {
+    pan: f32,
+    // Constant-power gains for the pan, fixed for the length of a note
+    gains: [f32; 2],
+    width: SmoothedParam,
+}
+
+impl Panner {
+    fn new(sample_rate: f32) -> Self {
+        let mut panner = Self {
+            pan: 0.0,
+            gains: [0.0; 2],
+            width: SmoothedParam::new(1.0, sample_rate, DEFAULT_RAMP_MS),
+        };
+        panner.start();
+        panner
+    }
+
+    /// Takes up the pan for a note starting.
+    fn start(&mut self) {
+        let angle = (self.pan + 1.0) * FRAC_PI_4;
+        self.gains = [angle.cos(), angle.sin()];
+    }
+
+    /// Adds one frame of sample channels to one output frame.
+    fn mix(&mut self, frame: &[f32], out: &mut [f32], gain: f32) {
+        let width = self.width.next_value();
+        match (frame.len(), out.len()) {
+            (0, _) | (_, 0) => {}
+            (_, 1) => out[0] += frame.iter().sum::<f32>() / frame.len() as f32 * gain,
+            // Mono sources are panned, centre sitting 3 dB down per side
+            (1, _) => {
+                out[0] += frame[0] * self.gains[0] * gain;
+                out[1] += frame[0] * self.gains[1] * gain;
+            }
+            // Stereo sources are balanced, unchanged at centre
+            _ => {
+                let mid = (frame[0] + frame[1]) * 0.5;
+                let side = (frame[0] - frame[1]) * 0.5 * width;
+                out[0] += (mid + side) * self.gains[0] * SQRT_2 * gain;
+                out[1] += (mid - side) * self.gains[1] * SQRT_2 * gain;
+                for (out, x) in out[2..].iter_mut().zip(&frame[2..]) {
+                    *out += x * gain;
+                }
+            }
+        }
+    }
+}
+
+/// Sine vibrato brought in by the mod wheel.
+struct Vibrato {
+    // Mod wheel from 0 to 1, scaling the depth
+    wheel: SmoothedParam,
+    // Semitones either way at full wheel
+    depth: f32,
+    phase: f32,
+    increment: f32,
+}
+
+impl Vibrato {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            wheel: SmoothedParam::new(0.0, sample_rate, DEFAULT_RAMP_MS),
+            depth: 0.5,
+            phase: 0.0,
+            increment: TAU * VIBRATO_HZ / sample_rate,
+        }
+    }
+
+    /// Whether the pitch offset may change this frame.
+    fn is_moving(&self) -> bool {
+        self.wheel.is_smoothing() || (self.wheel.value() > 0.0 && self.depth > 0.0)
+    }
+
+    /// Pitch offset in semitones for the next frame.
+    fn next_value(&mut self) -> f32 {
+        let wheel = self.wheel.next_value();
+        if wheel <= 0.0 {
+            return 0.0;
+        }
+        self.phase = (self.phase + self.increment) % TAU;
+        wheel * self.depth * self.phase.sin()
+    }
+}
+
+impl VoiceFilter {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            tuning: SergeFilter::with_sample_rate(sample_rate),
+            filters: [SergeFilter::with_sample_rate(sample_rate); MAX_CHANNELS],
+            mode: None,
+            cutoff: SmoothedParam::new(1000.0f32.log2(), sample_rate, DEFAULT_RAMP_MS),
+            cutoff_offset: 0.0,
+            resonance: SmoothedParam::new(0.5, sample_rate, DEFAULT_RAMP_MS),
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+    }
+
+    /// Advances automation by one sample, `octaves` above the set cutoff,
+    /// retuning only while either moves.
+    fn advance(&mut self, octaves: f32) {
+        if self.mode.is_none() {
+            return;
+        }
+        if self.cutoff.is_smoothing() || octaves != self.cutoff_offset {
+            self.cutoff_offset = octaves;
+            let cutoff = (self.cutoff.next_value() + octaves).exp2();
+            self.tuning.set_cutoff(cutoff);
+        }
+        if self.resonance.is_smoothing() {
+            let resonance = self.resonance.next_value();
+            self.tuning.set_resonance(resonance);
+        }
+    }
+
+    fn process(&mut self, ch: usize, input: f32) -> f32 {
+        match (self.mode, self.filters.get_mut(ch)) {
+            (Some(mode), Some(filter)) => filter
+                .process_with(self.tuning.coefficients(), input)
+                .get(mode),
+            _ => input,
+        }
+    }
 }
 
 impl Voice {
+    /// A voice rendering into stereo buffers.
     pub fn new(sample_rate: f32) -> Self {
+        Self::with_format(sample_rate, 2)
+    }
+
+    /// A voice rendering into buffers of `channels` interleaved channels.
+    pub fn with_format(sample_rate: f32, channels: usize) -> Self {
         Self {
             state: VoiceState::Idle,
             sample: None,
             position: 0.0,
+            direction: 1.0,
             pitch_ratio: 1.0,
+            interpolator: Interpolator::default(),
+            base_ratio: 1.0,
             envelope: Envelope::new(sample_rate),
             sample_rate,
             note: None,
             velocity: 1.0,
+            start_offset: 0.0,
+            end_offset: SmoothedParam::new(0.0, sample_rate, DEFAULT_RAMP_MS),
+            pitch_bend: 0.0,
+            pitch_bend_range: 2.0,
+            bend_semitones: SmoothedParam::new(0.0, sample_rate, DEFAULT_RAMP_MS),
+            glide: SmoothedParam::new(0.0, sample_rate, 0.0),
+            glide_ms: 0.0,
+            vibrato: Vibrato::new(sample_rate),
+            modulator: Modulator::new(sample_rate),
+            mod_semitones: 0.0,
+            trigger_order: 0,
+            slot: None,
+            pending: None,
+            pending_release: false,
+            fade_samples: ((STEAL_FADE_MS / 1000.0) * sample_rate).max(1.0) as u32,
+            fade_remaining: 0,
+            filter: VoiceFilter::new(sample_rate),
+            panner: Panner::new(sample_rate),
+            output_channels: channels.max(1),
         }
     }
 
+    /// Starts a note, attacking from the envelope's current level. A voice
+    /// that was sounding glides from its old pitch.
     pub fn trigger(&mut self, note: u8, velocity: f32, sample: Sample) {
+        let start_frame = sample.frames() as f64 * f64::from(self.start_offset);
+        let previous = self.note.filter(|_| self.is_active());
+
         self.note = Some(note);
         self.velocity = velocity;
         self.sample = Some(sample);
-        self.position = 0.0;
+        self.position = start_frame;
+        self.direction = 1.0;
         self.state = VoiceState::Active;
-
-        // Calculate pitch ratio for 1V/oct (each semitone up = ratio * 2^(1/12))
-        let root_note = self.sample.as_ref().and_then(|s| s.root_note).unwrap_or(60);
-        let semitones = note as f64 - root_note as f64;
-        self.pitch_ratio = 2.0_f64.powf(semitones / 12.0);
+        self.filter.reset();
+        self.panner.start();
+        self.retune(note, previous);
 
         self.envelope.trigger();
+        self.modulator.trigger();
+    }
+
+    /// Gives the voice a new note, handling one it is still sounding as
+    /// `mode` says.
+    pub fn retrigger(
+        &mut self,
+        note: u8,
+        velocity: f32,
+        sample: Sample,
+        setup: NoteSetup,
+        mode: RetriggerMode,
+    ) {
+        // Idle voices start at once, and a steal fade under way runs its course
+        if !self.is_active() || self.pending.is_some() {
+            self.steal(note, velocity, sample, setup);
+            return;
+        }
+        match mode {
+            RetriggerMode::Reset => self.steal(note, velocity, sample, setup),
+            RetriggerMode::Legato
+                if self.state == VoiceState::Active
+                    && self
+                        .sample
+                        .as_ref()
+                        .is_some_and(|playing| Arc::ptr_eq(&playing.data, &sample.data)) =>
+            {
+                self.apply_setup(setup);
+                let previous = self.note.replace(note);
+                self.retune(note, previous);
+            }
+            RetriggerMode::FromCurrent | RetriggerMode::Legato => {
+                self.apply_setup(setup);
+                self.trigger(note, velocity, sample);
+            }
+        }
+    }
+
+    fn apply_setup(&mut self, setup: NoteSetup) {
+        self.slot = setup.slot;
+        self.set_pan(setup.pan);
+        self.envelope.set_shape(setup.envelope);
+    }
+
+    /// Sets the pitch for `note` on the current sample, gliding there from
+    /// wherever a `previous` note had got to if a glide time is set.
+    fn retune(&mut self, note: u8, previous: Option<u8>) {
+        // Calculate pitch ratio for 1V/oct (each semitone up = ratio * 2^(1/12)),
+        // scaled so a sample recorded at another rate still plays in tune
+        let sample = self.sample.as_ref();
+        let root_note = sample.and_then(|s| s.root_note).unwrap_or(60);
+        let source_rate = sample.map_or(self.sample_rate, |s| s.sample_rate);
+        let tune = sample.map_or(0.0, |s| f64::from(s.tune_cents) / 100.0);
+        let semitones = note as f64 - root_note as f64 + tune;
+        let base_ratio =
+            2.0_f64.powf(semitones / 12.0) * (f64::from(source_rate) / f64::from(self.sample_rate));
+
+        match previous {
+            Some(previous) if self.glide_ms > 0.0 => {
+                let from = f32::from(previous) - f32::from(note) + self.glide.value();
+                self.glide.set_immediate(from);
+                self.glide.set_target(0.0);
+            }
+            _ => self.glide.set_immediate(0.0),
+        }
+        self.base_ratio = base_ratio;
+        self.pitch_ratio = bent_ratio(
+            self.base_ratio,
+            self.bend_semitones.value() + self.glide.value() + self.mod_semitones,
+        );
+    }
+
+    /// Takes over a busy voice: the current note fades out over a few
+    /// milliseconds, then the new one starts with `setup`. Idle voices
+    /// trigger at once.
+    pub fn steal(&mut self, note: u8, velocity: f32, sample: Sample, setup: NoteSetup) {
+        if self.is_active() {
+            if self.pending.is_none() {
+                self.fade_remaining = self.fade_samples;
+            }
+            self.pending = Some(PendingNote {
+                note,
+                velocity,
+                sample,
+                setup,
+            });
+            self.pending_release = false;
+        } else {
+            self.apply_setup(setup);
+            self.trigger(note, velocity, sample);
+        }
     }
 
     pub fn release(&mut self) {
+        if self.pending.is_some() {
+            self.pending_release = true;
+            return;
+        }
         if self.state == VoiceState::Active {
             self.state = VoiceState::Releasing;
             self.envelope.release();
+            self.modulator.release();
         }
     }
 
@@ -59,48 +400,483 @@ impl Voice {
         self.state != VoiceState::Idle
     }
 
+    /// The note this voice is playing, or about to play once a steal fade ends.
     pub fn get_note(&self) -> Option<u8> {
-        self.note
+        self.pending
+            .as_ref()
+            .map(|pending| pending.note)
+            .or(self.note)
+    }
+
+    /// Current output level from envelope and velocity, for quietest-voice stealing.
+    pub fn level(&self) -> f32 {
+        if self.pending.is_some() {
+            // Already on its way out
+            return 0.0;
+        }
+        self.envelope.get_current_value() * self.velocity
+    }
+
+    pub fn trigger_order(&self) -> u64 {
+        self.trigger_order
+    }
+
+    pub fn set_trigger_order(&mut self, order: u64) {
+        self.trigger_order = order;
+    }
+
+    /// What the voice is playing and where, for displays.
+    pub fn info(&self) -> VoiceInfo {
+        let Some(sample) = self.sample.as_ref().filter(|_| self.is_active()) else {
+            return VoiceInfo::default();
+        };
+        let frames = sample.frames() as f64;
+        VoiceInfo {
+            note: self.note,
+            slot: self.slot,
+            position: self.position,
+            start_frame: (frames * f64::from(self.start_offset)) as usize,
+            end_frame: (frames * f64::from(1.0 - self.end_offset.value())) as usize,
+        }
+    }
+
+    /// Kernel used to read between sample frames. Build it off the audio
+    /// thread; clones share their tables.
+    pub fn set_interpolator(&mut self, interpolator: Interpolator) {
+        self.interpolator = interpolator;
+    }
+
+    pub fn set_envelope(&mut self, shape: EnvelopeShape) {
+        self.envelope.set_shape(shape);
+    }
+
+    /// Start applies from the next trigger; end applies immediately.
+    pub fn set_sample_offsets(&mut self, start: f32, end: f32) {
+        self.start_offset = start.clamp(0.0, 1.0);
+        self.end_offset.set_target(end.clamp(0.0, 1.0));
+    }
+
+    /// Selects the filter response, or bypasses the filter with `None`.
+    pub fn set_filter_mode(&mut self, mode: Option<FilterMode>) {
+        self.filter.mode = mode;
+    }
+
+    /// Pan from -1 (left) to 1 (right) for the next note.
+    pub fn set_pan(&mut self, pan: f32) {
+        self.panner.pan = pan.clamp(-1.0, 1.0);
+    }
+
+    /// Stereo width of stereo samples: 0 is mono, 1 as recorded, up to 2
+    /// for wider.
+    pub fn set_width(&mut self, width: f32) {
+        self.panner.width.set_target(width.clamp(0.0, 2.0));
+    }
+
+    /// Filter cutoff in Hz, glided to over the parameter ramp.
+    pub fn set_filter_cutoff(&mut self, hz: f32) {
+        self.filter.cutoff.set_target(hz.max(1.0).log2());
+    }
+
+    /// Filter resonance from 0 to 1 (self-oscillation).
+    pub fn set_filter_resonance(&mut self, resonance: f32) {
+        self.filter.resonance.set_target(resonance.clamp(0.0, 1.0));
+    }
+
+    /// Bend position from -1 to 1, scaled by the bend range in semitones.
+    pub fn set_pitch_bend(&mut self, bend: f32) {
+        self.pitch_bend = bend.clamp(-1.0, 1.0);
+        self.bend_semitones
+            .set_target(self.pitch_bend * self.pitch_bend_range);
+    }
+
+    pub fn set_pitch_bend_range(&mut self, semitones: f32) {
+        self.pitch_bend_range = semitones.max(0.0);
+        self.bend_semitones
+            .set_target(self.pitch_bend * self.pitch_bend_range);
+    }
+
+    /// Time to glide between back-to-back notes; 0 jumps straight there.
+    pub fn set_glide_ms(&mut self, ms: f32) {
+        self.glide_ms = ms.max(0.0);
+        self.glide = SmoothedParam::new(self.glide.value(), self.sample_rate, self.glide_ms);
+    }
+
+    /// Mod wheel position from 0 to 1, bringing in vibrato.
+    pub fn set_mod_wheel(&mut self, amount: f32) {
+        self.vibrato.wheel.set_target(amount.clamp(0.0, 1.0));
+    }
+
+    /// Vibrato depth in semitones with the mod wheel all the way up.
+    pub fn set_vibrato_depth(&mut self, semitones: f32) {
+        self.vibrato.depth = semitones.max(0.0);
+    }
+
+    /// Envelope routed to pitch, filter or amplitude, from the next note
+    /// for gated shapes.
+    pub fn set_modulation(&mut self, modulation: Option<Modulation>) {
+        self.modulator.set(modulation);
+    }
+
+    /// Brings a free-running modulator into phase with the engine's.
+    pub fn sync_modulator(&mut self, clock: &Envelope) {
+        self.modulator.sync(clock);
+    }
+
+    /// Tempo in beats per minute, for cycling envelopes synced to it.
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.envelope.set_tempo(bpm);
+        self.modulator.set_tempo(bpm);
     }
 
     pub fn process_block(&mut self, output: &mut [f32]) {
-        if let Some(sample) = &self.sample {
-            let channels = sample.channels;
-            let sample_data = &sample.data;
-            let sample_len = sample_data.len() / channels;
-
-            for out in output.chunks_mut(channels) {
-                if self.position >= sample_len as f64 {
-                    self.state = VoiceState::Idle;
-                    break;
-                }
+        let mut offset = 0;
+        while offset < output.len() {
+            offset += self.render(&mut output[offset..]);
+
+            // A finished steal fade hands the rest of the block to the new note
+            if self.fade_remaining > 0 || !self.start_pending() {
+                break;
+            }
+        }
+    }
+
+    /// Renders until the output is full, the voice goes idle or a steal fade
+    /// completes, returning how many output samples were written.
+    fn render(&mut self, output: &mut [f32]) -> usize {
+        let Some(sample) = &self.sample else {
+            return output.len();
+        };
+
+        // The field is public, so a sample may claim no channels at all
+        let channels = sample.channels.max(1);
+        let sample_data = &sample.data;
+        let sample_len = sample.frames();
+        let mut frame = [0.0; MAX_CHANNELS];
+        let frame = &mut frame[..channels.min(MAX_CHANNELS)];
+        let mut written = 0;
+        let frames = output.len().div_ceil(self.output_channels);
+        let mut levels = [0.0; ENVELOPE_BLOCK];
+
+        for (i, out) in output.chunks_mut(self.output_channels).enumerate() {
+            let level = i % ENVELOPE_BLOCK;
+            if level == 0 {
+                let len = (frames - i).min(ENVELOPE_BLOCK);
+                self.envelope.process_block(&mut levels[..len]);
+            }
+
+            // Sustain loops only hold while the note does; after that the
+            // tail plays forwards to the end
+            let sample_loop = sample
+                .sample_loop
+                .filter(|l| !l.sustain || self.state == VoiceState::Active);
+            if sample_loop.is_none() {
+                self.direction = 1.0;
+            }
 
-                // Linear interpolation for sub-sample accuracy
-                let pos_floor = self.position.floor() as usize;
-                let pos_fract = self.position.fract() as f32;
+            let end_frame = sample_len as f64 * f64::from(1.0 - self.end_offset.next_value());
+            if self.position >= end_frame || self.position < 0.0 {
+                self.state = VoiceState::Idle;
+                break;
+            }
 
-                for ch in 0..channels.min(out.len()) {
-                    let idx = pos_floor * channels + ch;
-                    let next_idx = ((pos_floor + 1) * channels + ch).min(sample_data.len() - 1);
+            let offsets = self.modulator.next();
+            let mut gain = self.velocity * levels[level] * offsets.gain;
+            if self.pending.is_some() {
+                gain *= self.fade_remaining as f32 / self.fade_samples as f32;
+            }
 
-                    let sample_value = if idx < sample_data.len() {
-                        let curr = sample_data[idx];
-                        let next = sample_data[next_idx];
-                        curr * (1.0 - pos_fract) + next * pos_fract
-                    } else {
-                        0.0
-                    };
+            self.filter.advance(offsets.octaves);
 
-                    out[ch] += sample_value * self.velocity * self.envelope.get_current_value();
+            let seam = sample_loop.and_then(|l| loop_seam(&l, self.position, self.direction));
+            for (ch, value) in frame.iter_mut().enumerate() {
+                let read = |position| {
+                    self.interpolator
+                        .read(sample_data, channels, ch, position, self.pitch_ratio)
+                };
+                let mut sample_value = read(self.position);
+                if let Some((offset, blend)) = seam {
+                    sample_value += (read(self.position + offset) - sample_value) * blend;
                 }
+                *value = self.filter.process(ch, sample_value);
+            }
+            self.panner.mix(frame, out, gain);
+            written += out.len();
+
+            if self.bend_semitones.is_smoothing()
+                || self.glide.is_smoothing()
+                || self.vibrato.is_moving()
+                || offsets.semitones != self.mod_semitones
+            {
+                self.mod_semitones = offsets.semitones;
+                let bend = self.bend_semitones.next_value()
+                    + self.glide.next_value()
+                    + self.vibrato.next_value()
+                    + self.mod_semitones;
+                self.pitch_ratio = bent_ratio(self.base_ratio, bend);
+            }
 
-                self.position += self.pitch_ratio;
-                self.envelope.process_sample();
+            let previous = self.position;
+            self.position += self.pitch_ratio * self.direction;
+            if let Some(sample_loop) = &sample_loop {
+                wrap_loop(
+                    sample_loop,
+                    previous,
+                    &mut self.position,
+                    &mut self.direction,
+                );
+            }
+
+            // The envelope runs a block ahead, so a finished one ends the
+            // voice once the levels it gave have played
+            let block_end = level + 1 == ENVELOPE_BLOCK || i + 1 == frames;
+            if block_end && self.envelope.is_finished() {
+                self.state = VoiceState::Idle;
+            }
 
-                if self.envelope.is_finished() {
-                    self.state = VoiceState::Idle;
+            if self.pending.is_some() {
+                self.fade_remaining = self.fade_remaining.saturating_sub(1);
+                if self.fade_remaining == 0 {
+                    return written;
                 }
             }
+
+            if self.state == VoiceState::Idle {
+                break;
+            }
+        }
+
+        // An idle voice contributes silence for the rest of the block, unless
+        // a stolen note is waiting to take over straight away
+        if self.state == VoiceState::Idle && self.pending.is_some() {
+            self.fade_remaining = 0;
+            return written;
+        }
+        output.len()
+    }
+
+    /// Starts the note a steal was waiting on. Returns false if there was none.
+    fn start_pending(&mut self) -> bool {
+        let Some(pending) = self.pending.take() else {
+            return false;
+        };
+
+        self.apply_setup(pending.setup);
+        self.envelope.reset();
+        self.trigger(pending.note, pending.velocity, pending.sample);
+        if std::mem::take(&mut self.pending_release) {
+            self.release();
+        }
+        true
+    }
+}
+
+/// Keeps a voice inside its loop after it moved from `previous` to `position`.
+fn wrap_loop(sample_loop: &SampleLoop, previous: f64, position: &mut f64, direction: &mut f64) {
+    let start = sample_loop.start as f64;
+    let end = sample_loop.end as f64;
+    let len = end - start;
+    // `end` is exclusive, so bounces turn on the last frame in the loop
+    let last = end - 1.0;
+    let crossed_end = *direction > 0.0 && previous < end && *position >= end;
+    let crossed_last = *direction > 0.0 && previous <= last && *position > last;
+    let crossed_start = *direction < 0.0 && previous >= start && *position < start;
+
+    match sample_loop.mode {
+        LoopMode::Forward if crossed_end => {
+            *position = start + (*position - start).rem_euclid(len);
+        }
+        LoopMode::Reverse if crossed_start => {
+            *position = start + (*position - start).rem_euclid(len);
+        }
+        LoopMode::Reverse | LoopMode::PingPong if crossed_last => {
+            *position = (2.0 * last - *position).max(start);
+            *direction = -1.0;
+        }
+        LoopMode::PingPong if crossed_start => {
+            *position = (2.0 * start - *position).min(last);
+            *direction = 1.0;
+        }
+        _ => {}
+    }
+}
+
+/// While approaching the seam of a crossfaded loop, returns the offset to
+/// the audio on the far side of the seam and how far to blend towards it.
+fn loop_seam(sample_loop: &SampleLoop, position: f64, direction: f64) -> Option<(f64, f32)> {
+    let fade = sample_loop.crossfade as f64;
+    if fade <= 0.0 {
+        return None;
+    }
+    let start = sample_loop.start as f64;
+    let end = sample_loop.end as f64;
+    let len = end - start;
+
+    match sample_loop.mode {
+        LoopMode::Forward if position >= end - fade && position < end => {
+            Some((-len, ((position - (end - fade)) / fade) as f32))
+        }
+        LoopMode::Reverse if direction < 0.0 && position >= start && position < start + fade => {
+            Some((len, ((start + fade - position) / fade) as f32))
+        }
+        _ => None,
+    }
+}
+
+fn bent_ratio(base_ratio: f64, bend_semitones: f32) -> f64 {
+    base_ratio * (f64::from(bend_semitones) / 12.0).exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Curve;
+
+    const RATE: f32 = 1000.0;
+
+    /// A mono voice on a ramp sample whose value at each frame is its
+    /// index, so output shows where the voice read. Attacks at once and
+    /// releases slowly, so positions alone decide when it stops.
+    fn ramp_voice(frames: usize, sample_loop: SampleLoop) -> Voice {
+        let mut sample = Sample::new((0..frames).map(|i| i as f32).collect(), RATE, 1);
+        sample.set_loop(Some(sample_loop));
+        let mut voice = Voice::with_format(RATE, 1);
+        voice.set_envelope(EnvelopeShape::AR {
+            attack_ms: 0.0,
+            release_ms: 1000.0,
+            attack_curve: Curve::LINEAR,
+            release_curve: Curve::LINEAR,
+        });
+        voice.trigger(60, 1.0, sample);
+        voice
+    }
+
+    /// Where the voice reads for each of the next `frames` frames.
+    fn positions(voice: &mut Voice, frames: usize) -> Vec<f64> {
+        (0..frames)
+            .map(|_| {
+                let position = voice.position;
+                voice.process_block(&mut [0.0]);
+                position
+            })
+            .collect()
+    }
+
+    #[test]
+    fn forward_loop_wraps_to_start() {
+        let mut voice = ramp_voice(10, SampleLoop::new(2, 5, LoopMode::Forward));
+        assert_eq!(
+            positions(&mut voice, 12),
+            [0., 1., 2., 3., 4., 2., 3., 4., 2., 3., 4., 2.]
+        );
+        assert!(voice.is_active());
+    }
+
+    #[test]
+    fn ping_pong_loop_turns_on_its_first_and_last_frames() {
+        let mut voice = ramp_voice(10, SampleLoop::new(2, 5, LoopMode::PingPong));
+        assert_eq!(
+            positions(&mut voice, 12),
+            [0., 1., 2., 3., 4., 3., 2., 3., 4., 3., 2., 3.]
+        );
+    }
+
+    #[test]
+    fn reverse_loop_plays_in_then_runs_backwards() {
+        let mut voice = ramp_voice(10, SampleLoop::new(2, 5, LoopMode::Reverse));
+        assert_eq!(
+            positions(&mut voice, 12),
+            [0., 1., 2., 3., 4., 3., 2., 4., 3., 2., 4., 3.]
+        );
+    }
+
+    #[test]
+    fn loops_wrap_at_fractional_rates() {
+        // A fifth up, 1.5 frames a step
+        let mut voice = ramp_voice(20, SampleLoop::new(4, 10, LoopMode::Forward));
+        voice.trigger(67, 1.0, voice.sample.clone().unwrap());
+        let read = positions(&mut voice, 12);
+        assert!(read.iter().all(|&p| p < 10.0), "{read:?}");
+        let steps: Vec<f64> = read.windows(2).map(|w| w[1] - w[0]).collect();
+        for step in steps {
+            // Whole loop lengths drop out of every step across the seam
+            let step = (step + 6.0).rem_euclid(6.0);
+            assert!((step - 1.5).abs() < 0.01, "{read:?}");
+        }
+    }
+
+    #[test]
+    fn sustain_loop_plays_on_to_the_end_once_released() {
+        let mut sample_loop = SampleLoop::new(2, 5, LoopMode::PingPong);
+        sample_loop.sustain = true;
+        let mut voice = ramp_voice(8, sample_loop);
+        assert_eq!(positions(&mut voice, 6), [0., 1., 2., 3., 4., 3.]);
+
+        // Released heading backwards, it turns and runs out the tail
+        voice.release();
+        assert_eq!(positions(&mut voice, 5), [2., 3., 4., 5., 6.]);
+        assert!(voice.is_active());
+        assert_eq!(positions(&mut voice, 2), [7., 8.]);
+        assert!(!voice.is_active());
+    }
+
+    #[test]
+    fn loops_keep_going_after_release_unless_sustain() {
+        let mut voice = ramp_voice(8, SampleLoop::new(2, 5, LoopMode::Forward));
+        positions(&mut voice, 3);
+        voice.release();
+        assert_eq!(positions(&mut voice, 6), [3., 4., 2., 3., 4., 2.]);
+    }
+
+    #[test]
+    fn crossfaded_seam_blends_towards_the_loop_start() {
+        let mut sample_loop = SampleLoop::new(10, 20, LoopMode::Forward);
+        sample_loop.crossfade = 4;
+        let mut voice = ramp_voice(30, sample_loop);
+        let mut output = [0.0; 24];
+        voice.process_block(&mut output);
+
+        // The attack's first frame is silent; after it, the ramp plays
+        // until the seam, then fades towards the frames before the start
+        let expected = [
+            0., 1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12., 13., 14., 15., 16., 14.5, 13.,
+            11.5, 10., 11., 12., 13.,
+        ];
+        for (i, (out, expected)) in output.iter().zip(expected).enumerate().skip(1) {
+            assert!(
+                (out - expected).abs() < 1e-4,
+                "frame {i}: {out} != {expected}"
+            );
         }
     }
+
+    #[test]
+    fn loop_seam_blends_only_near_the_seam() {
+        let mut sample_loop = SampleLoop::new(10, 20, LoopMode::Forward);
+        sample_loop.crossfade = 4;
+        assert_eq!(loop_seam(&sample_loop, 15.0, 1.0), None);
+        assert_eq!(loop_seam(&sample_loop, 16.0, 1.0), Some((-10.0, 0.0)));
+        assert_eq!(loop_seam(&sample_loop, 18.0, 1.0), Some((-10.0, 0.5)));
+        assert_eq!(loop_seam(&sample_loop, 20.0, 1.0), None);
+
+        sample_loop.mode = LoopMode::Reverse;
+        assert_eq!(loop_seam(&sample_loop, 11.0, 1.0), None);
+        assert_eq!(loop_seam(&sample_loop, 11.0, -1.0), Some((10.0, 0.75)));
+        assert_eq!(loop_seam(&sample_loop, 14.0, -1.0), None);
+
+        sample_loop.mode = LoopMode::PingPong;
+        assert_eq!(loop_seam(&sample_loop, 18.0, 1.0), None);
+    }
+
+    #[test]
+    fn samples_without_channels_play_as_mono() {
+        assert_eq!(Sample::new(vec![0.5; 8], RATE, 0).channels, 1);
+
+        let mut sample = Sample::new(vec![0.5; 64], RATE, 1);
+        sample.channels = 0;
+        let mut voice = Voice::with_format(RATE, 1);
+        voice.trigger(60, 1.0, sample);
+        let mut output = [0.0; 32];
+        voice.process_block(&mut output);
+        assert!(output[1..].iter().all(|x| *x > 0.0));
+    }
 }
@@ -3,7 +3,26 @@
 #![allow(clippy::missing_const_for_fn)]
 
 use crossbeam::channel::{bounded, Receiver, Sender};
-use midir::MidiInputConnection;
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Name other MIDI software sees this client under
+const CLIENT_NAME: &str = "Zimler";
+
+#[derive(Debug, thiserror::Error)]
+pub enum MidiError {
+    #[error("MIDI input unavailable: {0}")]
+    Init(#[from] midir::InitError),
+    #[error("No MIDI input matching {0:?}")]
+    NoSuchPort(String),
+    #[error("No MIDI input at index {0}")]
+    NoSuchIndex(usize),
+    #[error("Already connected to {0}")]
+    AlreadyConnected(String),
+    #[error("Could not connect to {name}: {reason}")]
+    Connect { name: String, reason: String },
+}
 
 #[derive(Debug, Clone)]
 pub enum MidiMessage {
@@ -27,12 +46,53 @@ pub enum MidiMessage {
     },
 }
 
+/// A parsed message and when it arrived.
+#[derive(Debug, Clone)]
+pub struct MidiEvent {
+    pub message: MidiMessage,
+    /// Input it came in on
+    pub port: Arc<str>,
+    /// Arrival time, comparable across inputs
+    pub received: Instant,
+    /// Driver timestamp in microseconds, only comparable within one input
+    pub timestamp_us: u64,
+}
+
+/// An input port as last enumerated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidiPortInfo {
+    pub index: usize,
+    pub name: String,
+    pub connected: bool,
+}
+
+/// What changed between two scans of the input ports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Inputs asked for earlier that came back and were connected again
+    pub reconnected: Vec<String>,
+}
+
+struct Connection {
+    name: Arc<str>,
+    // midir's stable port id; `None` for a virtual port
+    id: Option<String>,
+    // Closes the port when dropped
+    _connection: MidiInputConnection<()>,
+}
+
+/// Receives MIDI from any number of inputs at once, parsing each message
+/// onto one bounded channel.
 pub struct MidiHandler {
-    #[allow(dead_code)]
-    tx: Sender<MidiMessage>,
-    rx: Receiver<MidiMessage>,
-    #[allow(dead_code)]
-    connection: Option<MidiInputConnection<()>>,
+    tx: Sender<MidiEvent>,
+    rx: Receiver<MidiEvent>,
+    connections: Vec<Connection>,
+    // Inputs connected by name, reconnected by `rescan` when they return
+    wanted: Vec<String>,
+    // Port names at the last scan
+    known: Vec<String>,
 }
 
 impl Default for MidiHandler {
@@ -47,14 +107,182 @@ impl MidiHandler {
         Self {
             tx,
             rx,
-            connection: None,
+            connections: Vec::new(),
+            wanted: Vec::new(),
+            known: Vec::new(),
         }
     }
 
-    pub fn get_receiver(&self) -> Receiver<MidiMessage> {
+    pub fn get_receiver(&self) -> Receiver<MidiEvent> {
         self.rx.clone()
     }
 
+    /// Input ports currently on the system.
+    pub fn ports(&self) -> Result<Vec<MidiPortInfo>, MidiError> {
+        let input = open_input()?;
+        Ok(port_list(&input)
+            .into_iter()
+            .enumerate()
+            .map(|(index, (port, name))| MidiPortInfo {
+                index,
+                connected: self.is_connected(&port.id()),
+                name,
+            })
+            .collect())
+    }
+
+    /// Names of the inputs being listened to, virtual ports included.
+    pub fn connected(&self) -> impl Iterator<Item = &str> {
+        self.connections.iter().map(|c| &*c.name)
+    }
+
+    /// Connects the input with this exact name, or else the first whose
+    /// name contains it, ignoring case. Returns the full port name. The
+    /// input is reconnected by `rescan` if it is unplugged and comes back.
+    pub fn connect(&mut self, name: &str) -> Result<String, MidiError> {
+        let input = open_input()?;
+        let ports = port_list(&input);
+        let (port, full_name) =
+            find_port(&ports, name).ok_or_else(|| MidiError::NoSuchPort(name.to_string()))?;
+        let full_name = full_name.clone();
+        self.open(input, &port.clone(), &full_name)?;
+        self.want(&full_name);
+        Ok(full_name)
+    }
+
+    /// Connects the input at `index` in `ports`. Returns its name.
+    pub fn connect_index(&mut self, index: usize) -> Result<String, MidiError> {
+        let input = open_input()?;
+        let (port, name) = port_list(&input)
+            .into_iter()
+            .nth(index)
+            .ok_or(MidiError::NoSuchIndex(index))?;
+        self.open(input, &port, &name)?;
+        self.want(&name);
+        Ok(name)
+    }
+
+    /// Stops listening to an input, virtual or not. Returns false if it
+    /// wasn't connected.
+    pub fn disconnect(&mut self, name: &str) -> bool {
+        self.wanted.retain(|wanted| wanted != name);
+        let before = self.connections.len();
+        self.connections.retain(|c| &*c.name != name);
+        self.connections.len() != before
+    }
+
+    pub fn disconnect_all(&mut self) {
+        self.wanted.clear();
+        self.connections.clear();
+    }
+
+    /// Re-enumerates the inputs, dropping connections to ports that have
+    /// gone and reconnecting inputs asked for by name that are back. midir
+    /// has no hot-plug notifications, so call this every second or so. The
+    /// first scan reports every port as added.
+    pub fn rescan(&mut self) -> Result<PortChanges, MidiError> {
+        let input = open_input()?;
+        let ports = port_list(&input);
+        let names: Vec<String> = ports.iter().map(|(_, name)| name.clone()).collect();
+        let mut changes = PortChanges {
+            added: names
+                .iter()
+                .filter(|name| !self.known.contains(name))
+                .cloned()
+                .collect(),
+            removed: self
+                .known
+                .iter()
+                .filter(|name| !names.contains(name))
+                .cloned()
+                .collect(),
+            reconnected: Vec::new(),
+        };
+        self.known = names;
+
+        let ids: Vec<String> = ports.iter().map(|(port, _)| port.id()).collect();
+        self.connections
+            .retain(|c| c.id.as_ref().map_or(true, |id| ids.contains(id)));
+
+        let mut input = Some(input);
+        for wanted in self.wanted.clone() {
+            if self.connections.iter().any(|c| *c.name == *wanted) {
+                continue;
+            }
+            let Some((port, name)) = ports.iter().find(|(_, name)| *name == wanted) else {
+                continue;
+            };
+            // Each connection consumes a client
+            let client = match input.take() {
+                Some(input) => input,
+                None => open_input()?,
+            };
+            if self.open(client, port, name).is_ok() {
+                changes.reconnected.push(name.clone());
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Creates an input other software can connect to, such as a virtual
+    /// keyboard or `aconnect` on Linux. Not available on Windows.
+    #[cfg(unix)]
+    pub fn create_virtual_port(&mut self, name: &str) -> Result<(), MidiError> {
+        use midir::os::unix::VirtualInput;
+
+        if self.connections.iter().any(|c| &*c.name == name) {
+            return Err(MidiError::AlreadyConnected(name.to_string()));
+        }
+        let name: Arc<str> = name.into();
+        let connection = open_input()?
+            .create_virtual(&name, forward(self.tx.clone(), Arc::clone(&name)), ())
+            .map_err(|e| MidiError::Connect {
+                name: name.to_string(),
+                reason: e.to_string(),
+            })?;
+        self.connections.push(Connection {
+            name,
+            id: None,
+            _connection: connection,
+        });
+        Ok(())
+    }
+
+    fn is_connected(&self, id: &str) -> bool {
+        self.connections.iter().any(|c| c.id.as_deref() == Some(id))
+    }
+
+    fn want(&mut self, name: &str) {
+        if !self.wanted.iter().any(|wanted| wanted == name) {
+            self.wanted.push(name.to_string());
+        }
+    }
+
+    fn open(
+        &mut self,
+        input: MidiInput,
+        port: &MidiInputPort,
+        name: &str,
+    ) -> Result<(), MidiError> {
+        let id = port.id();
+        if self.is_connected(&id) {
+            return Err(MidiError::AlreadyConnected(name.to_string()));
+        }
+        let name: Arc<str> = name.into();
+        let connection = input
+            .connect(port, &name, forward(self.tx.clone(), Arc::clone(&name)), ())
+            .map_err(|e| MidiError::Connect {
+                name: name.to_string(),
+                reason: e.to_string(),
+            })?;
+        self.connections.push(Connection {
+            name,
+            id: Some(id),
+            _connection: connection,
+        });
+        Ok(())
+    }
+
     pub fn parse_midi(data: &[u8]) -> Option<MidiMessage> {
         if data.len() < 2 {
             return None;
@@ -96,3 +324,49 @@ impl MidiHandler {
         }
     }
 }
+
+fn open_input() -> Result<MidiInput, MidiError> {
+    let mut input = MidiInput::new(CLIENT_NAME)?;
+    // Sysex, clock and active sensing carry nothing the engine uses
+    input.ignore(Ignore::All);
+    Ok(input)
+}
+
+/// Ports with their names, skipping any that vanished mid-listing.
+fn port_list(input: &MidiInput) -> Vec<(MidiInputPort, String)> {
+    input
+        .ports()
+        .into_iter()
+        .filter_map(|port| {
+            let name = input.port_name(&port).ok()?;
+            Some((port, name))
+        })
+        .collect()
+}
+
+fn find_port<'a>(
+    ports: &'a [(MidiInputPort, String)],
+    name: &str,
+) -> Option<&'a (MidiInputPort, String)> {
+    let lower = name.to_lowercase();
+    ports.iter().find(|(_, port)| port == name).or_else(|| {
+        ports
+            .iter()
+            .find(|(_, port)| port.to_lowercase().contains(&lower))
+    })
+}
+
+/// The driver callback for one input: parses and queues each message.
+fn forward(tx: Sender<MidiEvent>, port: Arc<str>) -> impl FnMut(u64, &[u8], &mut ()) + Send {
+    move |timestamp_us, bytes, ()| {
+        if let Some(message) = MidiHandler::parse_midi(bytes) {
+            // A full channel drops the message rather than stall the driver
+            let _ = tx.try_send(MidiEvent {
+                message,
+                port: Arc::clone(&port),
+                received: Instant::now(),
+                timestamp_us,
+            });
+        }
+    }
+}
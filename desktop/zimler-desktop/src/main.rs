@@ -6,8 +6,10 @@ use std::sync::{Arc, Mutex};
 use zimler_engine::{EngineConfig, EngineHandle, ZimlerEngine};
 
 mod audio_backend;
+mod midi;
 mod ui;
 
+use midi::ZimlerMidiPlugin;
 use ui::ZimlerUiPlugin;
 
 #[derive(Resource, Clone)]
@@ -44,6 +46,7 @@ fn main() {
         }))
         .insert_resource(EngineResource { handle })
         .add_plugins(ZimlerUiPlugin)
+        .add_plugins(ZimlerMidiPlugin)
         .add_systems(Startup, setup_scene)
         .run();
 }
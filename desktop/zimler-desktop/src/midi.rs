@@ -0,0 +1,77 @@
+use crate::EngineResource;
+use bevy::prelude::*;
+use zimler_engine::MidiDispatcher;
+use zimler_midi::{MidiEvent, MidiHandler};
+
+// Seconds between scans for plugged and unplugged devices
+const RESCAN_SECS: f32 = 1.0;
+
+/// Plays the engine from every MIDI input, including ones plugged in later,
+/// plus a virtual input for software keyboards.
+pub struct ZimlerMidiPlugin;
+
+impl Plugin for ZimlerMidiPlugin {
+    fn build(&self, app: &mut App) {
+        let mut handler = MidiHandler::new();
+        #[cfg(unix)]
+        if let Err(e) = handler.create_virtual_port("Zimler In") {
+            eprintln!("No virtual MIDI input: {e}");
+        }
+
+        app.insert_resource(MidiInput {
+            events: handler.get_receiver(),
+            dispatcher: MidiDispatcher::new(),
+            rescan: Timer::from_seconds(RESCAN_SECS, TimerMode::Repeating),
+        })
+        .insert_non_send_resource(handler)
+        .add_systems(Startup, connect_all)
+        .add_systems(Update, (dispatch_midi, rescan_midi));
+    }
+}
+
+#[derive(Resource)]
+struct MidiInput {
+    events: crossbeam::channel::Receiver<MidiEvent>,
+    dispatcher: MidiDispatcher,
+    rescan: Timer,
+}
+
+fn connect_all(mut handler: NonSendMut<MidiHandler>) {
+    let ports = match handler.ports() {
+        Ok(ports) => ports,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    for port in ports {
+        match handler.connect_index(port.index) {
+            Ok(name) => println!("MIDI input: {name}"),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+}
+
+fn dispatch_midi(midi: Res<MidiInput>, engine: Res<EngineResource>) {
+    for event in midi.events.try_iter() {
+        let _ = midi.dispatcher.dispatch(&event.message, &engine.handle);
+    }
+}
+
+fn rescan_midi(time: Res<Time>, mut midi: ResMut<MidiInput>, mut handler: NonSendMut<MidiHandler>) {
+    if !midi.rescan.tick(time.delta()).just_finished() {
+        return;
+    }
+    // No MIDI system now means none later either; stay quiet
+    let Ok(changes) = handler.rescan() else {
+        return;
+    };
+    for name in changes.reconnected {
+        println!("MIDI input: {name}");
+    }
+    for name in changes.added {
+        if let Ok(name) = handler.connect(&name) {
+            println!("MIDI input: {name}");
+        }
+    }
+}